@@ -0,0 +1,8 @@
+fn main() {
+    println!("cargo:rerun-if-changed=proto");
+    prost_build::compile_protos(
+        &["proto/ion.proto", "proto/biz.proto", "proto/rtc.proto"],
+        &["proto"],
+    )
+    .unwrap();
+}
@@ -0,0 +1,492 @@
+//! Opt-in congestion control for the publisher. `AbrController` turns the rtcp feedback
+//! in the publisher stats (losses the sfu reports, and a bandwidth estimate when one is
+//! available) into a target bitrate, and applies it to the registered encoders, stepping
+//! their resolution down through a capsfilter when the target gets too low for it.
+//!
+//! The loss based rule follows the loss controller of GCC: back off when more than
+//! `loss_high` of the packets are lost, probe upwards when less than `loss_low` are.
+
+use super::stats::StatsReport;
+use glib::ToValue;
+use gst::prelude::*;
+use log::*;
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct AbrConfig {
+    /// Bounds of the target bitrate in bits per second.
+    pub min_bitrate: u32,
+    pub max_bitrate: u32,
+    /// Where the target starts, `None` reads it from the first encoder.
+    pub start_bitrate: Option<u32>,
+    /// How often stats are sampled and the target updated.
+    pub interval: Duration,
+    pub loss_high: f64,
+    pub loss_low: f64,
+    /// Growth of the target per interval while losses stay below `loss_low`.
+    pub increase: f64,
+}
+
+impl AbrConfig {
+    pub fn new(min_bitrate: u32, max_bitrate: u32) -> AbrConfig {
+        AbrConfig {
+            min_bitrate,
+            max_bitrate,
+            start_bitrate: None,
+            interval: Duration::from_secs(1),
+            loss_high: 0.1,
+            loss_low: 0.02,
+            increase: 1.08,
+        }
+    }
+
+    pub fn start_bitrate(mut self, bitrate: u32) -> AbrConfig {
+        self.start_bitrate = Some(bitrate);
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> AbrConfig {
+        self.interval = interval;
+        self
+    }
+}
+
+/// A resolution step of an encoder, used while the target is at least `min_bitrate`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resolution {
+    pub width: i32,
+    pub height: i32,
+    pub min_bitrate: u32,
+}
+
+/// An encoder whose bitrate property the controller drives.
+#[derive(Clone, Debug)]
+pub struct EncoderControl {
+    encoder: gst::Element,
+    property: String,
+    /// Bits per second in one unit of the property.
+    unit: u32,
+    capsfilter: Option<gst::Element>,
+    resolutions: Vec<Resolution>,
+    resolution: Option<usize>,
+}
+
+impl EncoderControl {
+    /// Controls a known encoder: `target-bitrate` in bit/s for the vpx encoders and in
+    /// kbit/s for av1enc, `bitrate` in bit/s for openh264enc and `bitrate` in kbit/s for
+    /// anything else (x264enc, x265enc, nvh264enc, the vaapi encoders). Use `property` for
+    /// other encoders.
+    pub fn new(encoder: &gst::Element) -> EncoderControl {
+        let factory = encoder
+            .get_factory()
+            .map(|f| f.get_name().to_string())
+            .unwrap_or_default();
+        let (property, unit) = match factory.as_str() {
+            "vp8enc" | "vp9enc" => ("target-bitrate", 1),
+            "av1enc" => ("target-bitrate", 1000),
+            "openh264enc" => ("bitrate", 1),
+            _ => ("bitrate", 1000),
+        };
+
+        EncoderControl {
+            encoder: encoder.clone(),
+            property: property.to_string(),
+            unit,
+            capsfilter: None,
+            resolutions: vec![],
+            resolution: None,
+        }
+    }
+
+    /// Overrides the bitrate property and how many bits per second one unit of it is.
+    pub fn property(mut self, name: &str, unit: u32) -> EncoderControl {
+        self.property = name.to_string();
+        self.unit = unit.max(1);
+        self
+    }
+
+    /// Switches the width and height of `capsfilter` between `resolutions`. The filter
+    /// must sit behind a `videoscale` upstream of the encoder.
+    pub fn resolutions(
+        mut self,
+        capsfilter: &gst::Element,
+        mut resolutions: Vec<Resolution>,
+    ) -> EncoderControl {
+        resolutions.sort_by(|a, b| b.min_bitrate.cmp(&a.min_bitrate));
+        self.capsfilter = Some(capsfilter.clone());
+        self.resolutions = resolutions;
+        self.resolution = None;
+        self
+    }
+
+    fn bitrate(&self) -> Option<u32> {
+        let value = self.encoder.get_property(&self.property).ok()?;
+        let units = value
+            .get_some::<u32>()
+            .ok()
+            .map(u64::from)
+            .or_else(|| value.get_some::<i32>().ok().map(|v| v.max(0) as u64))
+            .or_else(|| value.get_some::<u64>().ok())
+            .or_else(|| value.get_some::<i64>().ok().map(|v| v.max(0) as u64))?;
+        Some((units * self.unit as u64).min(u32::MAX as u64) as u32)
+    }
+
+    fn set_bitrate(&self, bitrate: u32) {
+        let units = bitrate / self.unit;
+        let value = match self
+            .encoder
+            .find_property(&self.property)
+            .map(|pspec| pspec.get_value_type())
+        {
+            Some(glib::Type::I32) => (units.min(i32::MAX as u32) as i32).to_value(),
+            Some(glib::Type::U64) => (units as u64).to_value(),
+            Some(glib::Type::I64) => (units as i64).to_value(),
+            Some(_) => units.to_value(),
+            None => {
+                warn!(
+                    "{} has no {} property",
+                    self.encoder.get_name(),
+                    self.property
+                );
+                return;
+            }
+        };
+
+        if let Err(err) = self.encoder.set_property(&self.property, &value) {
+            warn!("could not set {} bitrate: {}", self.encoder.get_name(), err);
+        }
+    }
+
+    fn set_resolution(&mut self, bitrate: u32) {
+        let capsfilter = match self.capsfilter {
+            Some(ref capsfilter) if !self.resolutions.is_empty() => capsfilter,
+            _ => return,
+        };
+
+        // step up only with some headroom, so a target hovering at a threshold doesn't
+        // flip the resolution every interval
+        let fits = |r: &Resolution, current: bool| {
+            let needed = if current {
+                r.min_bitrate
+            } else {
+                r.min_bitrate + r.min_bitrate / 10
+            };
+            bitrate >= needed
+        };
+        let index = self
+            .resolutions
+            .iter()
+            .enumerate()
+            .position(|(i, r)| fits(r, Some(i) == self.resolution))
+            .unwrap_or(self.resolutions.len() - 1);
+        if Some(index) == self.resolution {
+            return;
+        }
+
+        let step = self.resolutions[index];
+        let caps = capsfilter
+            .get_property("caps")
+            .ok()
+            .and_then(|v| v.get::<gst::Caps>().ok().flatten())
+            // a fresh capsfilter has ANY caps, which have no structure to set the size on
+            .filter(|caps| !caps.is_any())
+            .unwrap_or_else(|| gst::Caps::new_simple("video/x-raw", &[]));
+        let mut caps = caps.copy();
+        if let Some(caps) = caps.get_mut() {
+            for s in caps.iter_mut() {
+                s.set("width", &step.width);
+                s.set("height", &step.height);
+            }
+        }
+
+        info!(
+            "switching {} to {}x{}",
+            self.encoder.get_name(),
+            step.width,
+            step.height
+        );
+        if let Err(err) = capsfilter.set_property("caps", &caps) {
+            warn!("could not set resolution: {}", err);
+            return;
+        }
+        self.resolution = Some(index);
+    }
+}
+
+pub struct AbrController {
+    config: AbrConfig,
+    encoders: Vec<EncoderControl>,
+    estimator: Option<gst::Element>,
+    target: Option<u32>,
+}
+
+impl AbrController {
+    pub fn new(config: AbrConfig) -> AbrController {
+        AbrController {
+            config,
+            encoders: vec![],
+            estimator: None,
+            target: None,
+        }
+    }
+
+    /// Registers an encoder. With several encoders the target is split evenly.
+    pub fn encoder(mut self, encoder: EncoderControl) -> AbrController {
+        self.encoders.push(encoder);
+        self
+    }
+
+    /// Caps the target at the `estimated-bitrate` of a bandwidth estimator element,
+    /// like gst-plugins-rs' `rtpgccbwe` working on transport-wide cc feedback.
+    pub fn estimator(mut self, element: &gst::Element) -> AbrController {
+        self.estimator = Some(element.clone());
+        self
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// Current target in bits per second, `None` before the first update.
+    pub fn target(&self) -> Option<u32> {
+        self.target
+    }
+
+    /// Bandwidth estimate from the estimator element or the selected ice pair.
+    fn estimate(&self, report: &StatsReport) -> Option<u32> {
+        let from_element = self.estimator.as_ref().and_then(|e| {
+            e.get_property("estimated-bitrate")
+                .ok()
+                .and_then(|v| v.get_some::<u32>().ok())
+        });
+        let from_pair = report
+            .stats
+            .publisher
+            .ice_pairs
+            .iter()
+            .filter_map(|p| p.available_outgoing_bitrate)
+            .fold(None, |max: Option<f64>, b| {
+                Some(max.map_or(b, |m| m.max(b)))
+            })
+            .map(|b| b as u32);
+
+        match (from_element, from_pair) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Updates the target from a stats report and applies it to the encoders.
+    /// Returns the new target in bits per second.
+    pub fn update(&mut self, report: &StatsReport) -> u32 {
+        let config = &self.config;
+        let current = self
+            .target
+            .or(config.start_bitrate)
+            .or_else(|| {
+                self.encoders
+                    .iter()
+                    .filter_map(EncoderControl::bitrate)
+                    .next()
+                    .map(|b| b.saturating_mul(self.encoders.len() as u32))
+            })
+            .unwrap_or(config.max_bitrate);
+
+        let (lost, expected) = report.outbound.iter().fold((0, 0), |(lost, expected), r| {
+            let sent = (r.packet_rate * report.elapsed.as_secs_f64()).round() as i64;
+            let lost_now = r.packets_lost.max(0);
+            (lost + lost_now, expected + sent + lost_now)
+        });
+        let loss = if expected > 0 {
+            lost as f64 / expected as f64
+        } else {
+            0.0
+        };
+
+        let mut target = current as f64;
+        if expected == 0 {
+            // nothing sent in the interval, hold
+        } else if loss > config.loss_high {
+            target *= 1.0 - 0.5 * loss;
+        } else if loss < config.loss_low {
+            target *= config.increase;
+        }
+
+        let mut target = target
+            .max(config.min_bitrate as f64)
+            .min(config.max_bitrate as f64) as u32;
+        if let Some(estimate) = self.estimate(report) {
+            target = target.min(estimate).max(config.min_bitrate);
+        }
+
+        if self.target != Some(target) {
+            debug!(
+                "abr target {} bit/s (loss {:.1}%, was {})",
+                target,
+                loss * 100.0,
+                current
+            );
+            let share = target / self.encoders.len().max(1) as u32;
+            for encoder in self.encoders.iter_mut() {
+                encoder.set_bitrate(share);
+                encoder.set_resolution(share);
+            }
+        }
+
+        self.target = Some(target);
+        target
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::{RtpRate, Stats, TransportStats};
+    use std::time::Instant;
+
+    fn report(packet_rate: f64, packets_lost: i64) -> StatsReport {
+        StatsReport {
+            stats: Stats {
+                publisher: TransportStats::default(),
+                subscriber: TransportStats::default(),
+                collected_at: Instant::now(),
+            },
+            elapsed: Duration::from_secs(1),
+            outbound: vec![RtpRate {
+                ssrc: 1,
+                bitrate: 0.0,
+                packet_rate,
+                packets_lost,
+                loss_fraction: 0.0,
+            }],
+            inbound: vec![],
+        }
+    }
+
+    fn controller() -> AbrController {
+        AbrController::new(AbrConfig::new(100_000, 2_000_000).start_bitrate(1_000_000))
+    }
+
+    // identity's datarate stands in for an encoder's bitrate property
+    fn encoder() -> (gst::Element, EncoderControl) {
+        gst::init().unwrap();
+        let identity = gst::ElementFactory::make("identity", None).unwrap();
+        let control = EncoderControl::new(&identity).property("datarate", 1);
+        (identity, control)
+    }
+
+    fn datarate(identity: &gst::Element) -> i32 {
+        identity
+            .get_property("datarate")
+            .unwrap()
+            .get_some::<i32>()
+            .unwrap()
+    }
+
+    #[test]
+    fn backs_off_on_loss() {
+        let mut abr = controller();
+        // a third of the expected packets lost
+        assert_eq!(abr.update(&report(100.0, 50)), 833_333);
+    }
+
+    #[test]
+    fn increases_without_loss_and_holds_in_between() {
+        let mut abr = controller();
+        assert_eq!(abr.update(&report(100.0, 0)), 1_080_000);
+        // 5% is between loss_low and loss_high
+        assert_eq!(abr.update(&report(95.0, 5)), 1_080_000);
+        // nothing sent, nothing learned
+        assert_eq!(abr.update(&report(0.0, 0)), 1_080_000);
+    }
+
+    #[test]
+    fn target_stays_within_bounds() {
+        let mut abr = controller();
+        for _ in 0..20 {
+            abr.update(&report(100.0, 0));
+        }
+        assert_eq!(abr.target(), Some(2_000_000));
+
+        for _ in 0..20 {
+            abr.update(&report(10.0, 90));
+        }
+        assert_eq!(abr.target(), Some(100_000));
+    }
+
+    #[test]
+    fn target_is_split_between_encoders() {
+        let (first, first_control) = encoder();
+        let (second, second_control) = encoder();
+        let mut abr = controller().encoder(first_control).encoder(second_control);
+
+        abr.update(&report(100.0, 0));
+        assert_eq!(datarate(&first), 540_000);
+        assert_eq!(datarate(&second), 540_000);
+    }
+
+    #[test]
+    fn start_is_read_from_the_encoders() {
+        let (identity, control) = encoder();
+        identity.set_property("datarate", &300_000i32).unwrap();
+        let mut abr = AbrController::new(AbrConfig::new(100_000, 2_000_000))
+            .encoder(control.clone())
+            .encoder(control);
+
+        // both encoders at 300k, the combined start holds while the loss is moderate
+        assert_eq!(abr.update(&report(95.0, 5)), 600_000);
+    }
+
+    #[test]
+    fn resolution_steps_up_only_with_headroom() {
+        let (_, control) = encoder();
+        let capsfilter = gst::ElementFactory::make("capsfilter", None).unwrap();
+        let mut control = control.resolutions(
+            &capsfilter,
+            vec![
+                Resolution {
+                    width: 160,
+                    height: 120,
+                    min_bitrate: 0,
+                },
+                Resolution {
+                    width: 640,
+                    height: 480,
+                    min_bitrate: 500_000,
+                },
+                Resolution {
+                    width: 320,
+                    height: 240,
+                    min_bitrate: 200_000,
+                },
+            ],
+        );
+        let width = |capsfilter: &gst::Element| {
+            capsfilter
+                .get_property("caps")
+                .unwrap()
+                .get::<gst::Caps>()
+                .unwrap()
+                .unwrap()
+                .get_structure(0)
+                .unwrap()
+                .get_some::<i32>("width")
+                .unwrap()
+        };
+
+        control.set_resolution(600_000);
+        assert_eq!(width(&capsfilter), 640);
+        // the current step is kept down to its threshold
+        control.set_resolution(500_000);
+        assert_eq!(width(&capsfilter), 640);
+        control.set_resolution(480_000);
+        assert_eq!(width(&capsfilter), 320);
+        // stepping up needs 10% above the threshold
+        control.set_resolution(520_000);
+        assert_eq!(width(&capsfilter), 320);
+        control.set_resolution(560_000);
+        assert_eq!(width(&capsfilter), 640);
+        control.set_resolution(50_000);
+        assert_eq!(width(&capsfilter), 160);
+    }
+}
@@ -1,7 +1,11 @@
+use futures::StreamExt;
 use glib;
 use gst::prelude::*;
-use ion_gst_rs::jsonrpc::JsonRPCSignaler;
-use ion_gst_rs::{Client, SessionDescription, Signal, TrickleCandidate};
+use ion_gst_rs::jsonrpc::{JsonRPCSignaler, ReconnectConfig};
+use ion_gst_rs::{
+    AbrConfig, AbrController, Client, ClientConfig, EncoderControl, SessionDescription, Signal,
+    TrackEvent, TrackKind, TrickleCandidate, VideoCodec,
+};
 use log::*;
 
 use enclose::enc;
@@ -11,31 +15,31 @@ async fn run() -> Result<(), anyhow::Error> {
     log::set_max_level(LevelFilter::Trace);
     gst::init()?;
 
-    let rpc = JsonRPCSignaler::new("ws://127.0.0.1:7000/session/test");
-    let pipeline = gst::parse_launch(
-        "
-        webrtcbin name=subscriber 
-        webrtcbin name=publisher
-        videotestsrc is-live=true ! 
-        video/x-raw,width=640,height=480 ! tee name=vtee
-        vtee. ! fakesink
-        vtee. !
-        queue ! 
-        x264enc speed-preset=ultrafast !
-        queue !
-        video/x-h264,profile=baseline ! 
-        h264parse config-interval=-1 ! 
-        rtph264pay ! application/x-rtp,clock-rate=90000,media=video,encoding=H264 ! progressreport !  queue ! publisher.  ",
-    )?
-    .downcast::<gst::Pipeline>()
-    .unwrap();
+    let rpc = JsonRPCSignaler::new("ws://127.0.0.1:7000/session/test")
+        .with_reconnect(ReconnectConfig::default());
+    let pipeline = gst::Pipeline::new(None);
 
-    let mut client = Client::new(rpc, pipeline.clone(), "publisher", "subscriber");
+    let mut client = Client::new(rpc, &pipeline, ClientConfig::default())?;
+    let video = client
+        .publish()
+        .video_codec(VideoCodec::H264)
+        .add_test_source(TrackKind::Video)?;
+
+    let mut tracks = client.tracks();
+    glib::MainContext::default().spawn(enc!( (pipeline) async move {
+        while let Some(event) = tracks.next().await {
+            let track = match event {
+                TrackEvent::Added(track) => track,
+                TrackEvent::Removed(track) => {
+                    info!("track {} of stream {} removed", track.track_id, track.stream_id);
+                    continue;
+                }
+            };
+            info!(
+                "track {} of stream {} added ({:?} {})",
+                track.track_id, track.stream_id, track.kind, track.codec
+            );
 
-    client
-        .subscriber
-        .connect_pad_added(enc!( (pipeline) move |_webrtc, subscriber_pad| {
-            debug!("pad added!");
             let decodebin =
                 gst::ElementFactory::make("decodebin", None).expect("could not make decodebin");
 
@@ -68,14 +72,23 @@ async fn run() -> Result<(), anyhow::Error> {
             pipeline.add(&decodebin).unwrap();
             decodebin.sync_state_with_parent().unwrap();
             let sink = decodebin.get_static_pad("sink").unwrap();
-            subscriber_pad.link(&sink).unwrap();
-        }));
+            track.pad.link(&sink).unwrap();
+        }
+    }));
 
     pipeline.set_state(gst::State::Playing)?;
-    //@todo maybe use the gst_bus to trigger the client.join instead of this sleep hack
-    // we need to wait for the ssrc caps event to arrive at webrtcbin before calling create-offer
-    std::thread::sleep_ms(2000);
-    client.join("test".to_string()).await?;
+    // join waits for the publish pads to negotiate caps before creating the offer
+    let mut events = client.join("test".to_string()).await?;
+    glib::MainContext::default().spawn(async move {
+        while let Some(event) = events.next().await {
+            info!("client event: {:?}", event);
+        }
+    });
+
+    client.adapt_bitrate(
+        AbrController::new(AbrConfig::new(150_000, 2_000_000))
+            .encoder(EncoderControl::new(&video.encoder)),
+    );
 
     loop {
         client.ping().await?;
@@ -0,0 +1,425 @@
+//! Codecs the publish helpers can encode to, with the gstreamer chains that produce rtp
+//! webrtcbin and ion-sfu accept, and the preferences that pick between them.
+
+use super::sdp::{Media, Sdp};
+use log::*;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VideoCodec {
+    H264,
+    Vp8,
+    Vp9,
+    /// Needs `rtpav1pay` from gst-plugins-rs.
+    Av1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AudioCodec {
+    Opus,
+}
+
+impl VideoCodec {
+    /// The rtp encoding name, as in `a=rtpmap`.
+    pub fn encoding_name(&self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "H264",
+            VideoCodec::Vp8 => "VP8",
+            VideoCodec::Vp9 => "VP9",
+            VideoCodec::Av1 => "AV1",
+        }
+    }
+
+    fn elements(&self) -> [&'static str; 2] {
+        match self {
+            VideoCodec::H264 => ["x264enc", "rtph264pay"],
+            VideoCodec::Vp8 => ["vp8enc", "rtpvp8pay"],
+            VideoCodec::Vp9 => ["vp9enc", "rtpvp9pay"],
+            VideoCodec::Av1 => ["av1enc", "rtpav1pay"],
+        }
+    }
+
+    /// Whether the encoder and payloader used for this codec are installed.
+    pub fn is_available(&self) -> bool {
+        self.elements()
+            .iter()
+            .all(|name| gst::ElementFactory::find(name).is_some())
+    }
+
+    pub fn payload_type(&self) -> u32 {
+        match self {
+            VideoCodec::H264 => 102,
+            VideoCodec::Vp8 => 96,
+            VideoCodec::Vp9 => 98,
+            VideoCodec::Av1 => 45,
+        }
+    }
+
+    /// The codec with rtp encoding name `name`, ignoring case.
+    pub fn from_encoding_name(name: &str) -> Option<VideoCodec> {
+        [
+            VideoCodec::H264,
+            VideoCodec::Vp8,
+            VideoCodec::Vp9,
+            VideoCodec::Av1,
+        ]
+        .iter()
+        .copied()
+        .find(|codec| codec.encoding_name().eq_ignore_ascii_case(name))
+    }
+
+    /// Encoder and payloader taking raw video, the encoder is named `encoder`. Tuned for
+    /// real time: no b-frames, a keyframe every two seconds at 30fps.
+    pub(crate) fn encode_description(&self) -> String {
+        let encode = match self {
+            VideoCodec::H264 => {
+                "x264enc name=encoder speed-preset=ultrafast tune=zerolatency key-int-max=60 !
+                video/x-h264,profile=constrained-baseline ! h264parse config-interval=-1"
+            }
+            VideoCodec::Vp8 => {
+                "vp8enc name=encoder deadline=1 keyframe-max-dist=60 error-resilient=partitions"
+            }
+            VideoCodec::Vp9 => "vp9enc name=encoder deadline=1 keyframe-max-dist=60",
+            VideoCodec::Av1 => "av1enc name=encoder cpu-used=8 end-usage=cbr keyframe-max-dist=60",
+        };
+        format!("{} ! {}", encode, self.pay_description())
+    }
+
+    /// Payloader taking the encoded video, e.g. from `depay_description`.
+    pub(crate) fn pay_description(&self) -> String {
+        let pay = match self {
+            VideoCodec::H264 => "rtph264pay config-interval=-1 aggregate-mode=zero-latency",
+            VideoCodec::Vp8 => "rtpvp8pay picture-id-mode=15-bit",
+            VideoCodec::Vp9 => "rtpvp9pay picture-id-mode=15-bit",
+            VideoCodec::Av1 => "rtpav1pay",
+        };
+        format!("{} pt={} mtu=1200", pay, self.payload_type())
+    }
+}
+
+impl Default for VideoCodec {
+    /// vp8, its encoder ships with gst-plugins-good.
+    fn default() -> VideoCodec {
+        VideoCodec::Vp8
+    }
+}
+
+impl fmt::Display for VideoCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.encoding_name())
+    }
+}
+
+impl AudioCodec {
+    pub fn encoding_name(&self) -> &'static str {
+        match self {
+            AudioCodec::Opus => "OPUS",
+        }
+    }
+
+    /// Whether the encoder and payloader used for this codec are installed.
+    pub fn is_available(&self) -> bool {
+        let elements = match self {
+            AudioCodec::Opus => ["opusenc", "rtpopuspay"],
+        };
+        elements
+            .iter()
+            .all(|name| gst::ElementFactory::find(name).is_some())
+    }
+
+    pub fn payload_type(&self) -> u32 {
+        match self {
+            AudioCodec::Opus => 111,
+        }
+    }
+
+    pub fn from_encoding_name(name: &str) -> Option<AudioCodec> {
+        Some(AudioCodec::Opus).filter(|codec| codec.encoding_name().eq_ignore_ascii_case(name))
+    }
+
+    /// Encoder and payloader taking raw audio, the encoder is named `encoder`.
+    pub(crate) fn encode_description(&self) -> String {
+        let encode = match self {
+            AudioCodec::Opus => "opusenc name=encoder inband-fec=true",
+        };
+        format!("{} ! {}", encode, self.pay_description())
+    }
+
+    pub(crate) fn pay_description(&self) -> String {
+        let pay = match self {
+            AudioCodec::Opus => "rtpopuspay",
+        };
+        format!("{} pt={} mtu=1200", pay, self.payload_type())
+    }
+}
+
+impl Default for AudioCodec {
+    fn default() -> AudioCodec {
+        AudioCodec::Opus
+    }
+}
+
+impl fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.encoding_name())
+    }
+}
+
+/// Depayloader, and parser where muxers or payloaders need one, turning rtp of encoding
+/// `name` back into the encoded stream.
+pub(crate) fn depay_description(name: &str) -> Option<&'static str> {
+    Some(match name.to_ascii_uppercase().as_str() {
+        "VP8" => "rtpvp8depay",
+        "VP9" => "rtpvp9depay",
+        "H264" => "rtph264depay ! h264parse",
+        "AV1" => "rtpav1depay",
+        "OPUS" => "rtpopusdepay ! opusparse",
+        _ => return None,
+    })
+}
+
+/// Media type of the encoded stream `depay_description` produces for encoding `name`.
+pub(crate) fn encoded_media_type(name: &str) -> Option<&'static str> {
+    Some(match name.to_ascii_uppercase().as_str() {
+        "VP8" => "video/x-vp8",
+        "VP9" => "video/x-vp9",
+        "H264" => "video/x-h264",
+        "AV1" => "video/x-av1",
+        "OPUS" => "audio/x-opus",
+        _ => return None,
+    })
+}
+
+/// Codecs to negotiate, most preferred first. The publish helpers encode with the first
+/// installed codec, the subscriber answers the sfu with only the listed codecs in this
+/// order. An empty list leaves that kind to webrtcbin.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CodecPreferences {
+    pub video: Vec<VideoCodec>,
+    pub audio: Vec<AudioCodec>,
+}
+
+impl CodecPreferences {
+    pub fn new() -> CodecPreferences {
+        CodecPreferences::default()
+    }
+
+    pub fn video(mut self, codec: VideoCodec) -> CodecPreferences {
+        self.video.push(codec);
+        self
+    }
+
+    pub fn audio(mut self, codec: AudioCodec) -> CodecPreferences {
+        self.audio.push(codec);
+        self
+    }
+
+    /// The video codec to publish with: the first preferred one that is installed.
+    pub fn publish_video(&self) -> VideoCodec {
+        self.video
+            .iter()
+            .copied()
+            .find(VideoCodec::is_available)
+            .unwrap_or_default()
+    }
+
+    pub fn publish_audio(&self) -> AudioCodec {
+        self.audio
+            .iter()
+            .copied()
+            .find(AudioCodec::is_available)
+            .unwrap_or_default()
+    }
+
+    /// Reorders and filters the payload types of every audio and video m-line to the
+    /// preferences, keeping the rtx formats of kept codecs. M-lines without any preferred
+    /// codec are left as they are, rejecting them would drop the track altogether.
+    pub(crate) fn munge(&self, sdp: &str) -> String {
+        if self.video.is_empty() && self.audio.is_empty() {
+            return sdp.to_string();
+        }
+
+        let mut sdp = Sdp::parse(sdp);
+        for (mline, media) in sdp.media.iter_mut().enumerate() {
+            if media.is_disabled() {
+                continue;
+            }
+            let preferred: Vec<&str> = match media.kind() {
+                "video" => self.video.iter().map(VideoCodec::encoding_name).collect(),
+                "audio" => self.audio.iter().map(AudioCodec::encoding_name).collect(),
+                _ => continue,
+            };
+            if !preferred.is_empty() && !prefer(media, &preferred) {
+                warn!(
+                    "m-line {} offers none of the preferred codecs {:?}",
+                    mline, preferred
+                );
+            }
+        }
+        sdp.to_string()
+    }
+}
+
+/// Payload type an rtx format retransmits, from its `apt` parameter.
+fn rtx_of<'a>(media: &'a Media, pt: &str) -> Option<&'a str> {
+    media.attributes("fmtp").find_map(|fmtp| {
+        let mut parts = fmtp.splitn(2, ' ');
+        if parts.next()? != pt {
+            return None;
+        }
+        parts
+            .next()?
+            .split(';')
+            .find_map(|param| param.trim().strip_prefix("apt="))
+    })
+}
+
+fn prefer(media: &mut Media, preferred: &[&str]) -> bool {
+    let formats = media.formats();
+    let mut kept: Vec<String> = vec![];
+    for name in preferred {
+        for pt in formats.iter() {
+            let matches = media
+                .codec(pt)
+                .map_or(false, |codec| codec.eq_ignore_ascii_case(name));
+            if matches && !kept.contains(pt) {
+                kept.push(pt.clone());
+            }
+        }
+    }
+    if kept.is_empty() {
+        return false;
+    }
+
+    let rtx: Vec<String> = formats
+        .iter()
+        .filter(|pt| {
+            media
+                .codec(pt)
+                .map_or(false, |codec| codec.eq_ignore_ascii_case("rtx"))
+        })
+        .filter(|pt| rtx_of(media, pt).map_or(false, |apt| kept.iter().any(|k| k == apt)))
+        .cloned()
+        .collect();
+    kept.extend(rtx);
+
+    media.retain_formats(&kept);
+    true
+}
+
+/// The codec negotiated for each audio and video m-line of an answer.
+pub(crate) fn negotiated(answer: &str) -> Vec<(u32, String)> {
+    Sdp::parse(answer)
+        .media
+        .iter()
+        .enumerate()
+        .filter(|(_, media)| matches!(media.kind(), "audio" | "video") && !media.is_disabled())
+        .filter_map(|(mline, media)| Some((mline as u32, media.first_codec()?.to_string())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VIDEO: &str = "m=video 9 UDP/TLS/RTP/SAVPF 96 97 98 99 100\r\n\
+                         a=mid:0\r\n\
+                         a=rtpmap:96 VP8/90000\r\n\
+                         a=rtcp-fb:96 nack pli\r\n\
+                         a=rtpmap:97 rtx/90000\r\n\
+                         a=fmtp:97 apt=96\r\n\
+                         a=rtpmap:98 H264/90000\r\n\
+                         a=rtcp-fb:98 nack pli\r\n\
+                         a=fmtp:98 profile-level-id=42e01f;packetization-mode=1\r\n\
+                         a=rtpmap:99 rtx/90000\r\n\
+                         a=fmtp:99 apt=98\r\n\
+                         a=rtpmap:100 VP9/90000\r\n";
+
+    const AUDIO: &str = "m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+                         a=mid:1\r\n\
+                         a=rtpmap:111 opus/48000/2\r\n";
+
+    fn media(text: &str) -> Media {
+        Sdp::parse(text).media.remove(0)
+    }
+
+    #[test]
+    fn rtx_of_reads_apt() {
+        let video = media(VIDEO);
+        assert_eq!(rtx_of(&video, "97"), Some("96"));
+        assert_eq!(rtx_of(&video, "99"), Some("98"));
+        assert_eq!(rtx_of(&video, "98"), None);
+        assert_eq!(rtx_of(&video, "96"), None);
+    }
+
+    #[test]
+    fn prefer_reorders_and_keeps_rtx_of_kept_codecs() {
+        let mut video = media(VIDEO);
+        assert!(prefer(&mut video, &["H264", "VP8"]));
+        assert_eq!(video.formats(), vec!["98", "96", "97", "99"]);
+        assert_eq!(video.first_codec(), Some("H264"));
+        assert_eq!(rtx_of(&video, "99"), Some("98"));
+        assert_eq!(rtx_of(&video, "97"), Some("96"));
+    }
+
+    #[test]
+    fn prefer_drops_attributes_of_removed_codecs() {
+        let mut video = media(VIDEO);
+        assert!(prefer(&mut video, &["vp8"]));
+        assert_eq!(video.formats(), vec!["96", "97"]);
+        assert!(video.codec("98").is_none());
+        assert!(video.codec("100").is_none());
+        assert!(video.attributes("rtcp-fb").all(|fb| fb.starts_with("96 ")));
+        assert!(video.attributes("fmtp").all(|fmtp| fmtp.starts_with("97 ")));
+    }
+
+    #[test]
+    fn prefer_leaves_media_without_preferred_codec() {
+        let mut video = media(VIDEO);
+        assert!(!prefer(&mut video, &["AV1"]));
+        assert_eq!(video.lines, media(VIDEO).lines);
+    }
+
+    #[test]
+    fn munge_applies_preferences_per_kind() {
+        let sdp = format!("v=0\r\n{}{}", VIDEO, AUDIO);
+        let prefs = CodecPreferences::new()
+            .video(VideoCodec::Vp9)
+            .video(VideoCodec::Vp8)
+            .audio(AudioCodec::Opus);
+
+        let munged = Sdp::parse(&prefs.munge(&sdp));
+        assert_eq!(munged.media[0].formats(), vec!["100", "96", "97"]);
+        assert_eq!(munged.media[1].formats(), vec!["111"]);
+    }
+
+    #[test]
+    fn munge_keeps_mlines_without_preferred_codec() {
+        let sdp = format!("v=0\r\n{}{}", VIDEO, AUDIO);
+        let prefs = CodecPreferences::new().video(VideoCodec::Av1);
+
+        let munged = Sdp::parse(&prefs.munge(&sdp));
+        assert_eq!(munged.media[0].lines, media(VIDEO).lines);
+        assert_eq!(munged.media[1].lines, media(AUDIO).lines);
+    }
+
+    #[test]
+    fn munge_without_preferences_is_verbatim() {
+        let sdp = format!("v=0\r\n{}", VIDEO);
+        assert_eq!(CodecPreferences::new().munge(&sdp), sdp);
+    }
+
+    #[test]
+    fn negotiated_lists_first_codec_of_active_mlines() {
+        let answer = format!(
+            "v=0\r\n{}{}m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+             m=video 0 UDP/TLS/RTP/SAVPF 96\r\n\
+             a=rtpmap:96 VP8/90000\r\n",
+            VIDEO, AUDIO
+        );
+        assert_eq!(
+            negotiated(&answer),
+            vec![(0, "VP8".to_string()), (1, "opus".to_string())]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use super::negotiation;
+use super::Error;
+use futures::channel::mpsc;
+use gst::prelude::*;
+use log::*;
+use std::sync::{Arc, Mutex};
+
+/// Options for `Client::create_data_channel`, mapped onto the webrtcbin
+/// `create-data-channel` options structure.
+#[derive(Clone, Debug)]
+pub struct DataChannelOptions {
+    pub ordered: bool,
+    /// Partial reliability by retransmit count, unset means fully reliable.
+    pub max_retransmits: Option<i32>,
+    /// Partial reliability by lifetime in milliseconds.
+    pub max_packet_lifetime: Option<i32>,
+    pub protocol: Option<String>,
+}
+
+impl Default for DataChannelOptions {
+    fn default() -> DataChannelOptions {
+        DataChannelOptions {
+            ordered: true,
+            max_retransmits: None,
+            max_packet_lifetime: None,
+            protocol: None,
+        }
+    }
+}
+
+impl DataChannelOptions {
+    pub fn unordered() -> DataChannelOptions {
+        DataChannelOptions {
+            ordered: false,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn to_structure(&self) -> gst::Structure {
+        let mut options = gst::Structure::new_empty("options");
+        options.set("ordered", &self.ordered);
+        if let Some(retransmits) = self.max_retransmits {
+            options.set("max-retransmits", &retransmits);
+        }
+        if let Some(lifetime) = self.max_packet_lifetime {
+            options.set("max-packet-lifetime", &lifetime);
+        }
+        if let Some(protocol) = &self.protocol {
+            options.set("protocol", protocol);
+        }
+        options
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DataChannelMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+type MessageSender = Arc<Mutex<Option<mpsc::UnboundedSender<DataChannelMessage>>>>;
+
+fn deliver(tx: &MessageSender, msg: DataChannelMessage) {
+    if let Some(tx) = &*tx.lock().unwrap() {
+        let _ = tx.unbounded_send(msg);
+    }
+}
+
+/// A data channel on either transport. Incoming messages are buffered from the moment
+/// the channel is created and delivered through `messages`.
+pub struct DataChannel {
+    channel: gst_webrtc::WebRTCDataChannel,
+    label: String,
+    messages: Option<mpsc::UnboundedReceiver<DataChannelMessage>>,
+}
+
+impl DataChannel {
+    /// Creates a channel on `publisher`, see `Client::create_data_channel`.
+    pub(crate) fn create(
+        publisher: &gst::Element,
+        label: &str,
+        options: &DataChannelOptions,
+    ) -> Result<DataChannel, Error> {
+        let channel = negotiation::emit(
+            publisher,
+            "create-data-channel",
+            &[&label, &options.to_structure()],
+        )?
+        .and_then(|v| v.get::<gst_webrtc::WebRTCDataChannel>().ok().flatten())
+        .ok_or(Error::EmptyReply {
+            action: "create-data-channel",
+        })?;
+
+        DataChannel::new(channel)
+    }
+
+    pub(crate) fn new(channel: gst_webrtc::WebRTCDataChannel) -> Result<DataChannel, Error> {
+        let label = channel
+            .get_property("label")
+            .ok()
+            .and_then(|v| v.get::<String>().ok().flatten())
+            .unwrap_or_default();
+
+        let (tx, rx) = mpsc::unbounded();
+        // shared so on-close can end the message stream
+        let tx: MessageSender = Arc::new(Mutex::new(Some(tx)));
+
+        let string_tx = tx.clone();
+        channel
+            .connect("on-message-string", false, move |values| {
+                if let Ok(Some(msg)) = values[1].get::<String>() {
+                    deliver(&string_tx, DataChannelMessage::Text(msg));
+                }
+                None
+            })
+            .map_err(|e| Error::Action {
+                action: "connect on-message-string",
+                source: e,
+            })?;
+
+        let data_tx = tx.clone();
+        channel
+            .connect("on-message-data", false, move |values| {
+                if let Ok(Some(data)) = values[1].get::<glib::Bytes>() {
+                    deliver(&data_tx, DataChannelMessage::Binary(data.to_vec()));
+                }
+                None
+            })
+            .map_err(|e| Error::Action {
+                action: "connect on-message-data",
+                source: e,
+            })?;
+
+        let close_label = label.clone();
+        channel
+            .connect("on-close", false, move |_| {
+                debug!("data channel {} closed", close_label);
+                tx.lock().unwrap().take();
+                None
+            })
+            .map_err(|e| Error::Action {
+                action: "connect on-close",
+                source: e,
+            })?;
+
+        let error_label = label.clone();
+        channel
+            .connect("on-error", false, move |_| {
+                warn!("data channel {} error", error_label);
+                None
+            })
+            .map_err(|e| Error::Action {
+                action: "connect on-error",
+                source: e,
+            })?;
+
+        Ok(DataChannel {
+            channel,
+            label,
+            messages: Some(rx),
+        })
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Takes the stream of incoming messages, it ends when the channel closes.
+    /// Returns `None` if it was already taken.
+    pub fn messages(&mut self) -> Option<mpsc::UnboundedReceiver<DataChannelMessage>> {
+        self.messages.take()
+    }
+
+    pub fn send_string(&self, msg: &str) -> Result<(), Error> {
+        self.channel
+            .emit("send-string", &[&msg])
+            .map(|_| ())
+            .map_err(|e| Error::Action {
+                action: "send-string",
+                source: e,
+            })
+    }
+
+    pub fn send_data(&self, data: &[u8]) -> Result<(), Error> {
+        let data = glib::Bytes::from(data);
+        self.channel
+            .emit("send-data", &[&data])
+            .map(|_| ())
+            .map_err(|e| Error::Action {
+                action: "send-data",
+                source: e,
+            })
+    }
+
+    pub fn send(&self, msg: &DataChannelMessage) -> Result<(), Error> {
+        match msg {
+            DataChannelMessage::Text(text) => self.send_string(text),
+            DataChannelMessage::Binary(data) => self.send_data(data),
+        }
+    }
+
+    pub fn close(&self) -> Result<(), Error> {
+        self.channel
+            .emit("close", &[])
+            .map(|_| ())
+            .map_err(|e| Error::Action {
+                action: "close",
+                source: e,
+            })
+    }
+}
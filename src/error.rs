@@ -0,0 +1,131 @@
+use std::fmt;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The signal transport could not be established.
+    Connect {
+        url: String,
+        source: BoxError,
+    },
+    /// A signaling request or notification failed.
+    Signal {
+        method: &'static str,
+        source: BoxError,
+    },
+    NotConnected,
+    Json(serde_json::Error),
+    /// A session description could not be parsed, `sdp` holds the raw text.
+    SdpParse {
+        sdp: String,
+        source: glib::BoolError,
+    },
+    SdpSerialize(glib::BoolError),
+    /// Emitting a webrtcbin action signal failed.
+    Action {
+        action: &'static str,
+        source: glib::BoolError,
+    },
+    /// The promise of a webrtcbin action was interrupted or expired.
+    Promise {
+        action: &'static str,
+        reason: String,
+    },
+    /// The promise of a webrtcbin action resolved without the expected reply.
+    EmptyReply {
+        action: &'static str,
+    },
+    ElementNotFound(String),
+    Pipeline {
+        context: String,
+        source: Option<BoxError>,
+    },
+    Timeout(&'static str),
+    UnknownTrickleTarget(u32),
+    /// Simulcast could not be set up or the sfu rejected it.
+    Simulcast(String),
+}
+
+impl Error {
+    pub(crate) fn signal<E>(method: &'static str, source: E) -> Error
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        Error::Signal {
+            method,
+            source: Box::new(source),
+        }
+    }
+
+    pub(crate) fn pipeline<E>(context: &str, source: E) -> Error
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        Error::Pipeline {
+            context: context.to_string(),
+            source: Some(Box::new(source)),
+        }
+    }
+
+    /// Whether the error came from the signaling side and the operation may succeed
+    /// when retried, possibly after reconnecting. Media and negotiation errors are
+    /// considered fatal for the session.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Connect { .. }
+            | Error::Signal { .. }
+            | Error::NotConnected
+            | Error::Timeout(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Connect { url, .. } => write!(f, "could not connect to {}", url),
+            Error::Signal { method, .. } => write!(f, "signal {} failed", method),
+            Error::NotConnected => write!(f, "signal is not connected"),
+            Error::Json(_) => write!(f, "json (de)serialization failed"),
+            Error::SdpParse { .. } => write!(f, "could not parse session description"),
+            Error::SdpSerialize(_) => write!(f, "could not serialize session description"),
+            Error::Action { action, .. } => write!(f, "webrtcbin {} failed", action),
+            Error::Promise { action, reason } => {
+                write!(f, "webrtcbin {} promise failed: {}", action, reason)
+            }
+            Error::EmptyReply { action } => write!(f, "webrtcbin {} returned no reply", action),
+            Error::ElementNotFound(name) => write!(f, "element not found: {}", name),
+            Error::Pipeline { context, .. } => write!(f, "pipeline error: {}", context),
+            Error::Timeout(what) => write!(f, "timed out waiting for {}", what),
+            Error::UnknownTrickleTarget(target) => {
+                write!(f, "trickle for unknown target {}", target)
+            }
+            Error::Simulcast(reason) => write!(f, "simulcast: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Connect { source, .. } | Error::Signal { source, .. } => Some(source.as_ref()),
+            Error::Pipeline {
+                source: Some(source),
+                ..
+            } => Some(source.as_ref()),
+            Error::Json(source) => Some(source),
+            Error::SdpParse { source, .. } => Some(source),
+            Error::SdpSerialize(source) => Some(source),
+            Error::Action { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
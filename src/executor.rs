@@ -0,0 +1,130 @@
+//! Where the crate runs its background tasks (signal notifications, renegotiation,
+//! websocket io).
+//!
+//! The default spawns on `glib::MainContext::default()`, which only makes progress while
+//! something iterates that context. Applications built on another runtime pass an
+//! `Executor` wrapping their own spawner, e.g. with a tokio `Handle`
+//! `Executor::new(move |task| { handle.spawn(task); })`, or run a `MainLoopThread`.
+
+use futures::future::BoxFuture;
+use futures::task::{FutureObj, Spawn};
+use log::*;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::thread;
+
+#[derive(Clone)]
+pub struct Executor {
+    spawn: Arc<dyn Fn(BoxFuture<'static, ()>) + Send + Sync>,
+    name: &'static str,
+}
+
+impl Executor {
+    /// Creates an executor from a function that spawns a task on some runtime.
+    pub fn new<F>(spawn: F) -> Executor
+    where
+        F: Fn(BoxFuture<'static, ()>) + Send + Sync + 'static,
+    {
+        Executor {
+            spawn: Arc::new(spawn),
+            name: "custom",
+        }
+    }
+
+    /// Spawns on `context`, tasks run on whichever thread iterates it.
+    pub fn main_context(context: glib::MainContext) -> Executor {
+        Executor {
+            spawn: Arc::new(move |task| context.spawn(task)),
+            name: "glib main context",
+        }
+    }
+
+    /// Spawns through a `futures` spawner, like a `ThreadPool`.
+    pub fn from_spawn<S>(spawner: S) -> Executor
+    where
+        S: Spawn + Send + Sync + 'static,
+    {
+        Executor {
+            spawn: Arc::new(move |task| {
+                if let Err(err) = spawner.spawn_obj(FutureObj::from(task)) {
+                    error!("could not spawn task: {}", err);
+                }
+            }),
+            name: "futures spawner",
+        }
+    }
+
+    pub(crate) fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        (self.spawn)(Box::pin(task))
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Executor {
+        Executor::main_context(glib::MainContext::default())
+    }
+}
+
+impl fmt::Debug for Executor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Executor({})", self.name)
+    }
+}
+
+/// A glib main loop iterating its own `MainContext` on a dedicated thread, for
+/// applications that don't run a glib loop themselves. The loop stops when this is dropped.
+pub struct MainLoopThread {
+    context: glib::MainContext,
+    main_loop: glib::MainLoop,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MainLoopThread {
+    pub fn spawn() -> MainLoopThread {
+        let context = glib::MainContext::new();
+        let main_loop = glib::MainLoop::new(Some(&context), false);
+
+        let thread_context = context.clone();
+        let thread_loop = main_loop.clone();
+        let thread = thread::Builder::new()
+            .name("ion-gst-glib".to_string())
+            .spawn(move || {
+                thread_context.push_thread_default();
+                thread_loop.run();
+                thread_context.pop_thread_default();
+            })
+            .expect("could not start glib main loop thread");
+
+        MainLoopThread {
+            context,
+            main_loop,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn context(&self) -> &glib::MainContext {
+        &self.context
+    }
+
+    /// An executor spawning on this thread's context.
+    pub fn executor(&self) -> Executor {
+        Executor::main_context(self.context.clone())
+    }
+}
+
+impl Drop for MainLoopThread {
+    fn drop(&mut self) {
+        // quit from the loop itself, a quit before `run` started would be lost
+        let main_loop = self.main_loop.clone();
+        self.context.invoke(move || main_loop.quit());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("glib main loop thread panicked");
+            }
+        }
+    }
+}
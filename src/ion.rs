@@ -0,0 +1,581 @@
+//! `Signal` implementation for ion's room signaling, for deployments running the full ion
+//! stack (biz + islb + sfu) rather than a bare ion-sfu.
+//!
+//! Joining first enters the room through the `biz.Biz/Signal` stream and then negotiates
+//! media through the `rtc.RTC/Signal` stream. Room activity (peers, streams, chat) is
+//! delivered on `IonSignaler::room_events`. Both streams are grpc-web calls over websocket,
+//! see `grpcws`, with the messages generated from ion's `.proto` files.
+
+use super::{Error, Executor, SessionDescription, Signal, SignalNotification, TrickleCandidate};
+use async_trait::async_trait;
+use async_tungstenite::tungstenite::client::IntoClientRequest;
+use async_tungstenite::tungstenite::http::HeaderValue;
+use async_tungstenite::tungstenite::Message as WsMessage;
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, AbortHandle, FutureExt};
+use futures::stream::{self, SplitStream, Stream};
+use futures::{SinkExt, StreamExt};
+use log::*;
+use prost::Message;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+pub(crate) mod grpcws;
+pub mod proto;
+
+use grpcws::ServerFrame;
+use proto::{biz, ion, rtc};
+
+pub(crate) const BIZ_PATH: &str = "biz.Biz/Signal";
+pub(crate) const RTC_PATH: &str = "rtc.RTC/Signal";
+
+/// Room activity reported by the biz service.
+#[derive(Clone, Debug)]
+pub enum RoomEvent {
+    PeerJoined(ion::Peer),
+    PeerUpdated(ion::Peer),
+    PeerLeft(ion::Peer),
+    StreamsAdded {
+        uid: String,
+        streams: Vec<ion::Stream>,
+    },
+    StreamsRemoved {
+        uid: String,
+        streams: Vec<ion::Stream>,
+    },
+    Message(ion::Message),
+}
+
+/// A request the server refused, with the reason of its reply.
+#[derive(Debug)]
+struct Rejected(String);
+
+impl Rejected {
+    fn from_error(error: Option<rtc::Error>) -> Rejected {
+        let error = error.unwrap_or_default();
+        Rejected(format!("{} ({})", error.reason, error.code))
+    }
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rejected: {}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+type RtcResult<T> = Result<T, rtc::Error>;
+
+/// Requests waiting for their reply. ion answers in order and without request ids,
+/// so there is at most one of each kind in flight.
+#[derive(Default)]
+struct Pending {
+    join: Option<oneshot::Sender<biz::JoinReply>>,
+    rtc_join: Option<oneshot::Sender<RtcResult<rtc::JoinReply>>>,
+    answer: Option<oneshot::Sender<RtcResult<rtc::SessionDescription>>>,
+}
+
+#[derive(Clone, Default)]
+struct RoomSubscribers(Arc<Mutex<Vec<mpsc::UnboundedSender<RoomEvent>>>>);
+
+impl RoomSubscribers {
+    fn publish(&self, event: RoomEvent) {
+        trace!("room event: {:?}", event);
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+type WsStream =
+    SplitStream<async_tungstenite::WebSocketStream<async_tungstenite::async_std::ConnectStream>>;
+
+pub struct IonSignaler {
+    url: String,
+    uid: String,
+    token: String,
+    info: Vec<u8>,
+    biz: Option<mpsc::UnboundedSender<WsMessage>>,
+    rtc: Option<mpsc::UnboundedSender<WsMessage>>,
+    pending: Arc<Mutex<Pending>>,
+    rooms: RoomSubscribers,
+    tasks: Vec<AbortHandle>,
+    executor: Executor,
+}
+
+impl IonSignaler {
+    /// Creates a signaler that joins rooms as peer `uid`. `url` is the websocket endpoint
+    /// of ion's grpc-web gateway, e.g. `ws://localhost:5551`.
+    pub fn new(url: &str, uid: &str) -> IonSignaler {
+        IonSignaler {
+            url: url.trim_end_matches('/').to_string(),
+            uid: uid.to_string(),
+            token: String::new(),
+            info: vec![],
+            biz: None,
+            rtc: None,
+            pending: Arc::new(Mutex::new(Pending::default())),
+            rooms: RoomSubscribers::default(),
+            tasks: vec![],
+            executor: Executor::default(),
+        }
+    }
+
+    /// Sets the auth token sent with the room join.
+    pub fn with_token(mut self, token: &str) -> IonSignaler {
+        self.token = token.to_string();
+        self
+    }
+
+    /// Sets the peer info other room members receive, usually json.
+    pub fn with_info(mut self, info: Vec<u8>) -> IonSignaler {
+        self.info = info;
+        self
+    }
+
+    /// Runs the websocket io on `executor` instead of the default glib main context.
+    pub fn with_executor(mut self, executor: Executor) -> IonSignaler {
+        self.executor = executor;
+        self
+    }
+
+    pub fn uid(&self) -> &str {
+        &self.uid
+    }
+
+    /// Returns a stream of room activity. Only events after subscribing are delivered.
+    pub fn room_events(&self) -> mpsc::UnboundedReceiver<RoomEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.rooms.0.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Sends a chat message to peer `to`, or to the whole room if `to` is empty.
+    pub fn send_message(&self, to: &str, data: &[u8]) -> Result<(), Error> {
+        self.send_biz(biz::signal_request::Payload::Msg(ion::Message {
+            from: self.uid.clone(),
+            to: to.to_string(),
+            data: data.to_vec(),
+        }))
+    }
+
+    /// Opens the call at `path`, its messages are written by a task until the returned
+    /// sender is dropped.
+    async fn open_call(
+        &self,
+        path: &str,
+    ) -> Result<(mpsc::UnboundedSender<WsMessage>, WsStream), Error> {
+        let url = format!("{}/{}", self.url, path);
+        let connect_error = |e: async_tungstenite::tungstenite::Error| Error::Connect {
+            url: url.clone(),
+            source: Box::new(e),
+        };
+
+        let mut request = url.as_str().into_client_request().map_err(connect_error)?;
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(grpcws::PROTOCOL),
+        );
+        let (ws, _) = async_tungstenite::async_std::connect_async(request)
+            .await
+            .map_err(connect_error)?;
+        let (sink, stream) = ws.split();
+
+        let (outgoing, outgoing_rx) = mpsc::unbounded();
+        outgoing
+            .unbounded_send(grpcws::request_headers())
+            .map_err(|_| Error::NotConnected)?;
+        self.executor.spawn(write_frames(sink, outgoing_rx));
+
+        Ok((outgoing, stream))
+    }
+
+    fn send_biz(&self, payload: biz::signal_request::Payload) -> Result<(), Error> {
+        let request = biz::SignalRequest {
+            payload: Some(payload),
+        };
+        self.biz
+            .as_ref()
+            .ok_or(Error::NotConnected)?
+            .unbounded_send(grpcws::request(&request))
+            .map_err(|_| Error::NotConnected)
+    }
+
+    fn send_rtc(&self, payload: rtc::request::Payload) -> Result<(), Error> {
+        let request = rtc::Request {
+            payload: Some(payload),
+        };
+        self.rtc
+            .as_ref()
+            .ok_or(Error::NotConnected)?
+            .unbounded_send(grpcws::request(&request))
+            .map_err(|_| Error::NotConnected)
+    }
+}
+
+fn to_proto(target: rtc::Target, desc: SessionDescription) -> rtc::SessionDescription {
+    rtc::SessionDescription {
+        target: target as i32,
+        r#type: desc.t,
+        sdp: desc.sdp,
+        track_infos: vec![],
+    }
+}
+
+fn from_proto(desc: rtc::SessionDescription) -> SessionDescription {
+    SessionDescription {
+        t: desc.r#type,
+        sdp: desc.sdp,
+    }
+}
+
+/// Writes queued messages to the socket until the queue is dropped, then closes it.
+async fn write_frames<S>(mut sink: S, mut outgoing: mpsc::UnboundedReceiver<WsMessage>)
+where
+    S: futures::Sink<WsMessage> + Unpin,
+    S::Error: fmt::Display,
+{
+    while let Some(msg) = outgoing.next().await {
+        if let Err(err) = sink.send(msg).await {
+            error!("ion signal write failed: {}", err);
+            return;
+        }
+    }
+    let _ = sink.close().await;
+}
+
+/// Decodes the replies of a call until the server ends it or the socket closes.
+fn replies<S, M>(stream: S, call: &'static str) -> impl Stream<Item = M>
+where
+    S: Stream<Item = Result<WsMessage, async_tungstenite::tungstenite::Error>> + Unpin,
+    M: Message + Default,
+{
+    let reader = grpcws::FrameReader::default();
+    stream::unfold(
+        (stream, reader),
+        move |(mut stream, mut reader)| async move {
+            loop {
+                while let Some(frame) = reader.next_frame() {
+                    match frame {
+                        ServerFrame::Message(data) => match M::decode(&data[..]) {
+                            Ok(msg) => return Some((msg, (stream, reader))),
+                            Err(err) => warn!("could not decode {} reply: {}", call, err),
+                        },
+                        headers => match headers.status() {
+                            Some((0, _)) => {
+                                debug!("{} ended", call);
+                                return None;
+                            }
+                            Some((status, message)) => {
+                                error!("{} failed with status {}: {}", call, status, message);
+                                return None;
+                            }
+                            None => trace!("{} headers: {:?}", call, headers),
+                        },
+                    }
+                }
+
+                match stream.next().await {
+                    Some(Ok(WsMessage::Binary(data))) => reader.push(&data),
+                    Some(Ok(WsMessage::Close(_))) | None => return None,
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => {
+                        error!("{} read failed: {}", call, err);
+                        return None;
+                    }
+                }
+            }
+        },
+    )
+}
+
+fn dispatch_biz(reply: biz::SignalReply, pending: &Mutex<Pending>, rooms: &RoomSubscribers) {
+    use biz::signal_reply::Payload;
+    use ion::{peer_event, stream_event};
+
+    match reply.payload {
+        Some(Payload::JoinReply(reply)) => match pending.lock().unwrap().join.take() {
+            Some(tx) => {
+                let _ = tx.send(reply);
+            }
+            None => warn!("unexpected biz join reply"),
+        },
+        Some(Payload::LeaveReply(reply)) => {
+            if !reply.reason.is_empty() {
+                warn!("leaving room failed: {}", reply.reason);
+            }
+        }
+        Some(Payload::PeerEvent(event)) => {
+            let peer = event.peer.unwrap_or_default();
+            let event = match peer_event::State::from_i32(event.state) {
+                Some(peer_event::State::Join) => RoomEvent::PeerJoined(peer),
+                Some(peer_event::State::Update) => RoomEvent::PeerUpdated(peer),
+                Some(peer_event::State::Leave) => RoomEvent::PeerLeft(peer),
+                None => {
+                    warn!("unknown peer state {}", event.state);
+                    return;
+                }
+            };
+            rooms.publish(event);
+        }
+        Some(Payload::StreamEvent(event)) => {
+            let (uid, streams) = (event.uid, event.streams);
+            let event = match stream_event::State::from_i32(event.state) {
+                Some(stream_event::State::Add) => RoomEvent::StreamsAdded { uid, streams },
+                Some(stream_event::State::Remove) => RoomEvent::StreamsRemoved { uid, streams },
+                None => {
+                    warn!("unknown stream state {}", event.state);
+                    return;
+                }
+            };
+            rooms.publish(event);
+        }
+        Some(Payload::Msg(msg)) => rooms.publish(RoomEvent::Message(msg)),
+        None => warn!("empty biz reply"),
+    }
+}
+
+/// Handles one rtc reply. Returns false once the notification receiver is gone.
+async fn dispatch_rtc(
+    reply: rtc::Reply,
+    pending: &Mutex<Pending>,
+    tx: &mut mpsc::Sender<SignalNotification>,
+) -> bool {
+    use rtc::reply::Payload;
+
+    let notification = match reply.payload {
+        Some(Payload::Join(reply)) => {
+            match pending.lock().unwrap().rtc_join.take() {
+                Some(waiter) => {
+                    let _ = waiter.send(Ok(reply));
+                }
+                None => warn!("unexpected rtc join reply"),
+            }
+            return true;
+        }
+        Some(Payload::Description(desc)) if desc.r#type == "answer" => {
+            match pending.lock().unwrap().answer.take() {
+                Some(waiter) => {
+                    let _ = waiter.send(Ok(desc));
+                }
+                None => warn!("unexpected answer"),
+            }
+            return true;
+        }
+        Some(Payload::Description(desc)) => {
+            trace!("got offer: {:?}", desc);
+            SignalNotification::Negotiate {
+                offer: from_proto(desc),
+            }
+        }
+        Some(Payload::Trickle(trickle)) => {
+            let candidate: TrickleCandidate = match serde_json::from_str(&trickle.init) {
+                Ok(candidate) => candidate,
+                Err(err) => {
+                    warn!("invalid trickle candidate: {}", err);
+                    return true;
+                }
+            };
+            SignalNotification::Trickle {
+                target: trickle.target as u32,
+                candidate,
+            }
+        }
+        Some(Payload::Error(error)) => {
+            // errors answer whichever rtc request is in flight
+            let mut pending = pending.lock().unwrap();
+            if let Some(waiter) = pending.answer.take() {
+                let _ = waiter.send(Err(error));
+            } else if let Some(waiter) = pending.rtc_join.take() {
+                let _ = waiter.send(Err(error));
+            } else {
+                warn!("rtc error: {}", error.reason);
+            }
+            return true;
+        }
+        Some(Payload::TrackEvent(event)) => {
+            debug!("rtc track event: {:?}", event);
+            return true;
+        }
+        Some(Payload::Subscription(reply)) => {
+            debug!("rtc subscription reply: {:?}", reply);
+            return true;
+        }
+        None => {
+            warn!("empty rtc reply");
+            return true;
+        }
+    };
+
+    // waits for room rather than dropping, a lost offer desyncs the subscriber
+    tx.send(notification).await.is_ok()
+}
+
+async fn read_biz(stream: WsStream, pending: Arc<Mutex<Pending>>, rooms: RoomSubscribers) {
+    let replies = replies::<_, biz::SignalReply>(stream, BIZ_PATH);
+    futures::pin_mut!(replies);
+    while let Some(reply) = replies.next().await {
+        dispatch_biz(reply, &pending, &rooms);
+    }
+
+    debug!("ion biz signal closed");
+    pending.lock().unwrap().join.take();
+}
+
+/// Dispatches rtc replies until the call ends. Requests still waiting for a reply are
+/// cancelled when it does.
+async fn read_rtc(
+    stream: WsStream,
+    pending: Arc<Mutex<Pending>>,
+    mut tx: mpsc::Sender<SignalNotification>,
+) {
+    let replies = replies::<_, rtc::Reply>(stream, RTC_PATH);
+    futures::pin_mut!(replies);
+    while let Some(reply) = replies.next().await {
+        if !dispatch_rtc(reply, &pending, &mut tx).await {
+            break;
+        }
+    }
+
+    debug!("ion rtc signal closed");
+    *pending.lock().unwrap() = Pending::default();
+}
+
+#[async_trait]
+impl Signal for IonSignaler {
+    async fn open(&mut self) -> Result<mpsc::Receiver<SignalNotification>, Error> {
+        let (biz, biz_stream) = self.open_call(BIZ_PATH).await?;
+        let (rtc, rtc_stream) = self.open_call(RTC_PATH).await?;
+        self.biz = Some(biz);
+        self.rtc = Some(rtc);
+
+        let (task, handle) = future::abortable(read_biz(
+            biz_stream,
+            self.pending.clone(),
+            self.rooms.clone(),
+        ));
+        self.executor.spawn(task.map(|_| ()));
+        self.tasks.push(handle);
+
+        let (tx, rx) = mpsc::channel(16);
+        let (task, handle) = future::abortable(read_rtc(rtc_stream, self.pending.clone(), tx));
+        self.executor.spawn(task.map(|_| ()));
+        self.tasks.push(handle);
+
+        Ok(rx)
+    }
+
+    async fn close(&mut self) -> Result<(), Error> {
+        if self.biz.is_none() {
+            return Err(Error::NotConnected);
+        }
+
+        if let Err(err) = self.send_biz(biz::signal_request::Payload::Leave(biz::Leave {
+            uid: self.uid.clone(),
+        })) {
+            warn!("could not leave room: {}", err);
+        }
+        // the writers flush what is queued and close the sockets once their queue is gone
+        for call in [self.biz.take(), self.rtc.take()].iter().flatten() {
+            let _ = call.unbounded_send(grpcws::finish_send());
+        }
+
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        *self.pending.lock().unwrap() = Pending::default();
+
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        // ion has no ping request, websocket pings keep proxies from dropping the sockets
+        for call in [&self.biz, &self.rtc].iter() {
+            call.as_ref()
+                .ok_or(Error::NotConnected)?
+                .unbounded_send(WsMessage::Ping(vec![]))
+                .map_err(|_| Error::NotConnected)?;
+        }
+        Ok(())
+    }
+
+    async fn join(
+        &self,
+        sid: String,
+        offer: SessionDescription,
+    ) -> Result<SessionDescription, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().join = Some(tx);
+        self.send_biz(biz::signal_request::Payload::Join(biz::Join {
+            peer: Some(ion::Peer {
+                sid: sid.clone(),
+                uid: self.uid.clone(),
+                info: self.info.clone(),
+            }),
+            token: self.token.clone(),
+        }))?;
+
+        let reply = rx.await.map_err(|_| Error::NotConnected)?;
+        if !reply.success {
+            return Err(Error::signal("join", Rejected(reply.reason)));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().rtc_join = Some(tx);
+        self.send_rtc(rtc::request::Payload::Join(rtc::JoinRequest {
+            sid,
+            uid: self.uid.clone(),
+            config: Default::default(),
+            description: Some(to_proto(rtc::Target::Publisher, offer)),
+        }))?;
+
+        let reply = rx
+            .await
+            .map_err(|_| Error::NotConnected)?
+            .map_err(|e| Error::signal("join", Rejected::from_error(Some(e))))?;
+        if !reply.success {
+            return Err(Error::signal("join", Rejected::from_error(reply.error)));
+        }
+
+        reply.description.map(from_proto).ok_or(Error::Signal {
+            method: "join",
+            source: "join reply without answer".into(),
+        })
+    }
+
+    async fn offer(&self, offer: SessionDescription) -> Result<SessionDescription, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().answer = Some(tx);
+        self.send_rtc(rtc::request::Payload::Description(to_proto(
+            rtc::Target::Publisher,
+            offer,
+        )))?;
+
+        rx.await
+            .map_err(|_| Error::NotConnected)?
+            .map(from_proto)
+            .map_err(|e| Error::signal("offer", Rejected::from_error(Some(e))))
+    }
+
+    async fn answer(&self, answer: SessionDescription) -> Result<(), Error> {
+        self.send_rtc(rtc::request::Payload::Description(to_proto(
+            rtc::Target::Subscriber,
+            answer,
+        )))
+    }
+
+    async fn trickle(&self, target: u32, candidate: TrickleCandidate) -> Result<(), Error> {
+        let target = match target {
+            0 => rtc::Target::Publisher,
+            1 => rtc::Target::Subscriber,
+            _ => return Err(Error::UnknownTrickleTarget(target)),
+        };
+
+        self.send_rtc(rtc::request::Payload::Trickle(rtc::Trickle {
+            target: target as i32,
+            init: serde_json::to_string(&candidate)?,
+        }))
+    }
+}
@@ -0,0 +1,164 @@
+//! gRPC-Web over websocket, as spoken by improbable-eng's grpc-web proxy and ion's
+//! gateway: each streaming rpc runs on its own websocket, on the `/{service}/{method}` path
+//! with the `grpc-websockets` subprotocol.
+//!
+//! The client sends its request headers first, then each message as `0x00` followed by a
+//! length prefixed grpc frame, and `0x01` to half-close. The server replies with a stream
+//! of grpc frames: headers and trailers are flagged with `0x80` and carry http header
+//! lines, the trailers end the call with its `grpc-status`.
+
+use async_tungstenite::tungstenite::Message as WsMessage;
+use prost::Message;
+use std::convert::TryInto;
+
+pub(crate) const PROTOCOL: &str = "grpc-websockets";
+
+const TRAILER_FLAG: u8 = 0x80;
+
+fn frame(flag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(flag);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn encode<M: Message>(msg: &M) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(msg.encoded_len());
+    // only fails when the buffer lacks capacity, which a Vec grows
+    msg.encode(&mut buf).unwrap();
+    buf
+}
+
+fn header_lines(headers: &[(&str, &str)]) -> Vec<u8> {
+    headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// The first message of a call.
+pub(crate) fn request_headers() -> WsMessage {
+    WsMessage::Binary(header_lines(&[
+        ("content-type", "application/grpc-web+proto"),
+        ("x-grpc-web", "1"),
+    ]))
+}
+
+pub(crate) fn request<M: Message>(msg: &M) -> WsMessage {
+    let mut data = vec![0];
+    data.extend(frame(0, &encode(msg)));
+    WsMessage::Binary(data)
+}
+
+/// Half-closes the call, the server may still reply.
+pub(crate) fn finish_send() -> WsMessage {
+    WsMessage::Binary(vec![1])
+}
+
+/// What the server reads from a client websocket message, after the request headers.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ClientMessage {
+    Request(Vec<u8>),
+    FinishSend,
+    Invalid,
+}
+
+pub(crate) fn parse_client(data: &[u8]) -> ClientMessage {
+    match data.split_first() {
+        Some((0, frame)) if frame.len() >= 5 => {
+            let len = u32::from_be_bytes(frame[1..5].try_into().unwrap()) as usize;
+            match frame.get(5..5 + len) {
+                Some(payload) => ClientMessage::Request(payload.to_vec()),
+                None => ClientMessage::Invalid,
+            }
+        }
+        Some((1, _)) => ClientMessage::FinishSend,
+        _ => ClientMessage::Invalid,
+    }
+}
+
+pub(crate) fn response_headers() -> WsMessage {
+    WsMessage::Binary(frame(
+        TRAILER_FLAG,
+        &header_lines(&[("content-type", "application/grpc-web+proto")]),
+    ))
+}
+
+pub(crate) fn response<M: Message>(msg: &M) -> WsMessage {
+    WsMessage::Binary(frame(0, &encode(msg)))
+}
+
+pub(crate) fn trailers(status: u32, message: &str) -> WsMessage {
+    let status = status.to_string();
+    WsMessage::Binary(frame(
+        TRAILER_FLAG,
+        &header_lines(&[("grpc-status", &status), ("grpc-message", message)]),
+    ))
+}
+
+/// A frame the server sent.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ServerFrame {
+    Message(Vec<u8>),
+    /// Headers, or trailers if they hold a `grpc-status`.
+    Headers(Vec<(String, String)>),
+}
+
+impl ServerFrame {
+    /// The `grpc-status` and `grpc-message` of trailers.
+    pub fn status(&self) -> Option<(u32, String)> {
+        let headers = match self {
+            ServerFrame::Headers(headers) => headers,
+            ServerFrame::Message(_) => return None,
+        };
+        let get = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.clone())
+        };
+        let status = get("grpc-status")?.parse().ok()?;
+        Some((status, get("grpc-message").unwrap_or_default()))
+    }
+}
+
+/// Reassembles the frames of a call, websocket messages may split or merge them.
+#[derive(Default)]
+pub(crate) struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn next_frame(&mut self) -> Option<ServerFrame> {
+        if self.buf.len() < 5 {
+            return None;
+        }
+        let len = u32::from_be_bytes(self.buf[1..5].try_into().unwrap()) as usize;
+        if self.buf.len() < 5 + len {
+            return None;
+        }
+
+        let flag = self.buf[0];
+        let payload: Vec<u8> = self.buf.drain(..5 + len).skip(5).collect();
+        if flag & TRAILER_FLAG == 0 {
+            return Some(ServerFrame::Message(payload));
+        }
+
+        let headers = String::from_utf8_lossy(&payload)
+            .split("\r\n")
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                let name = parts.next()?.trim();
+                let value = parts.next()?.trim();
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect();
+        Some(ServerFrame::Headers(headers))
+    }
+}
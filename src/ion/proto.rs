@@ -0,0 +1,14 @@
+//! Protobuf messages of ion's `biz` (room) and `rtc` (media) signaling services,
+//! generated from the `.proto` files of pion/ion vendored under `proto/`.
+
+pub mod ion {
+    include!(concat!(env!("OUT_DIR"), "/ion.rs"));
+}
+
+pub mod biz {
+    include!(concat!(env!("OUT_DIR"), "/biz.rs"));
+}
+
+pub mod rtc {
+    include!(concat!(env!("OUT_DIR"), "/rtc.rs"));
+}
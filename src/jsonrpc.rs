@@ -1,6 +1,8 @@
-use super::{Error, SessionDescription, Signal, SignalNotification, TrickleCandidate};
+use super::{Error, Executor, SessionDescription, Signal, SignalNotification, TrickleCandidate};
+use async_mutex::Mutex;
 use async_trait::async_trait;
-use futures::{channel::mpsc, future, future::Either, pin_mut};
+use futures::future::{AbortHandle, FutureExt};
+use futures::{channel::mpsc, future, future::Either, pin_mut, SinkExt};
 use jsonrpsee::ws_client::{
     traits::Client, traits::SubscriptionClient, v2::params::JsonRpcParams, NotificationHandler,
     WsClient, WsClientBuilder,
@@ -11,6 +13,8 @@ use serde::{Deserialize, Serialize};
 use serde_json::value::Value;
 use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
 use url::Url;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -35,74 +39,289 @@ pub struct TrickleNotification {
     pub candidate: TrickleCandidate,
 }
 
-pub struct JsonRPCSignaler<'a> {
-    url: &'a str,
-    ws: Option<WsClient>,
+/// Reconnection policy for `JsonRPCSignaler`: the delay before each attempt starts at
+/// `initial_delay` and doubles up to `max_delay`.
+#[derive(Clone, Debug)]
+pub struct ReconnectConfig {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Give up after this many consecutive failed attempts, `None` retries forever.
+    pub max_attempts: Option<u32>,
 }
 
-impl<'a> JsonRPCSignaler<'a> {
-    pub fn new(url: &'a str) -> JsonRPCSignaler {
+impl Default for ReconnectConfig {
+    fn default() -> ReconnectConfig {
+        ReconnectConfig {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+    Reconnected,
+    /// Reconnection gave up after `ReconnectConfig::max_attempts`.
+    Failed,
+    Closed,
+}
+
+#[derive(Clone, Default)]
+struct StateSubscribers(Arc<std::sync::Mutex<Vec<mpsc::UnboundedSender<ConnectionState>>>>);
+
+impl StateSubscribers {
+    fn publish(&self, state: ConnectionState) {
+        debug!("signal connection state: {:?}", state);
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.unbounded_send(state.clone()).is_ok());
+    }
+}
+
+type Notifications = (
+    NotificationHandler<SessionDescription>,
+    NotificationHandler<TrickleNotification>,
+);
+
+pub struct JsonRPCSignaler {
+    url: String,
+    ws: Arc<Mutex<Option<WsClient>>>,
+    tasks: Vec<AbortHandle>,
+    reconnect: Option<ReconnectConfig>,
+    states: StateSubscribers,
+    executor: Executor,
+}
+
+impl JsonRPCSignaler {
+    pub fn new(url: &str) -> JsonRPCSignaler {
         JsonRPCSignaler {
-            url: url,
-            ws: Option::<WsClient>::None,
+            url: url.to_string(),
+            ws: Arc::new(Mutex::new(None)),
+            tasks: vec![],
+            reconnect: None,
+            states: StateSubscribers::default(),
+            executor: Executor::default(),
         }
     }
+
+    /// Enables automatic reconnection. After reconnecting the notification handlers are
+    /// registered again and `SignalNotification::Reconnected` is delivered so the client
+    /// joins the session again.
+    pub fn with_reconnect(mut self, config: ReconnectConfig) -> JsonRPCSignaler {
+        self.reconnect = Some(config);
+        self
+    }
+
+    /// Runs the notification and reconnect task on `executor` instead of the default
+    /// glib main context.
+    pub fn with_executor(mut self, executor: Executor) -> JsonRPCSignaler {
+        self.executor = executor;
+        self
+    }
+
+    /// Returns a stream of connection state changes for this signaler.
+    pub fn connection_state(&self) -> mpsc::UnboundedReceiver<ConnectionState> {
+        let (tx, rx) = mpsc::unbounded();
+        self.states.0.lock().unwrap().push(tx);
+        rx
+    }
 }
 
-#[async_trait]
-impl<'a> Signal for JsonRPCSignaler<'a> {
-    async fn open(&mut self) -> Result<mpsc::Receiver<SignalNotification>, Error> {
-        let u = Url::parse(&self.url).unwrap();
-
-        let client = WsClientBuilder::default()
-            .handshake_url(Cow::Borrowed(&u.path()))
-            .build(&self.url)
-            .await?;
-
-        let mut offer: NotificationHandler<SessionDescription> =
-            client.register_notification("offer").await?;
-        let mut trickle: NotificationHandler<TrickleNotification> =
-            client.register_notification("trickle").await?;
-
-        let (mut tx, rx) = mpsc::channel(16);
-        let mut tx_clone = tx.clone();
-
-        glib::MainContext::default().spawn(async move {
-            loop {
-                if let Some(msg) = offer.next().await {
-                    trace!("got offer: {:?}", msg);
-                    tx_clone
-                        .try_send(SignalNotification::Negotiate { offer: msg })
-                        .expect("could not send offer notification to channel");
-                }
+async fn connect(url: &str) -> Result<(WsClient, Notifications), Error> {
+    let u = Url::parse(url).map_err(|e| Error::Connect {
+        url: url.to_string(),
+        source: Box::new(e),
+    })?;
+
+    let client = WsClientBuilder::default()
+        .handshake_url(Cow::Borrowed(&u.path()))
+        .build(url)
+        .await
+        .map_err(|e| Error::Connect {
+            url: url.to_string(),
+            source: Box::new(e),
+        })?;
+
+    let offer: NotificationHandler<SessionDescription> = client
+        .register_notification("offer")
+        .await
+        .map_err(|e| Error::signal("offer", e))?;
+    let trickle: NotificationHandler<TrickleNotification> = client
+        .register_notification("trickle")
+        .await
+        .map_err(|e| Error::signal("trickle", e))?;
+
+    Ok((client, (offer, trickle)))
+}
+
+/// Forwards notifications to `tx` until the websocket goes away.
+async fn forward_notifications(notifications: Notifications, tx: mpsc::Sender<SignalNotification>) {
+    let (mut offer, mut trickle) = notifications;
+    let mut offer_tx = tx.clone();
+    let mut trickle_tx = tx;
+
+    let offers = async move {
+        while let Some(msg) = offer.next().await {
+            trace!("got offer: {:?}", msg);
+            // waits for room rather than dropping, a lost offer desyncs the subscriber
+            let notification = SignalNotification::Negotiate { offer: msg };
+            if offer_tx.send(notification).await.is_err() {
+                debug!("notification receiver dropped");
+                return;
+            }
+        }
+    };
+    let trickles = async move {
+        while let Some(msg) = trickle.next().await {
+            trace!("got trickle: {:?}", msg);
+            let notification = SignalNotification::Trickle {
+                target: msg.target,
+                candidate: msg.candidate,
+            };
+            if trickle_tx.send(notification).await.is_err() {
+                debug!("notification receiver dropped");
+                return;
+            }
+        }
+    };
+    pin_mut!(offers, trickles);
+
+    match future::select(offers, trickles).await {
+        Either::Left(_) => debug!("offer notifications ended"),
+        Either::Right(_) => debug!("trickle notifications ended"),
+    }
+}
+
+async fn reconnect_with_backoff(
+    url: &str,
+    ws: &Mutex<Option<WsClient>>,
+    config: &ReconnectConfig,
+    states: &StateSubscribers,
+) -> Option<Notifications> {
+    let mut delay = config.initial_delay;
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+        if let Some(max) = config.max_attempts {
+            if attempt > max {
+                return None;
             }
-        });
-        glib::MainContext::default().spawn(async move {
-            loop {
-                if let Some(msg) = trickle.next().await {
-                    println!("got trickle: {:?}", msg);
-                    tx.try_send(SignalNotification::Trickle {
-                        target: msg.target,
-                        candidate: msg.candidate,
-                    })
-                    .expect("could not send trickle notification to channel");
-                }
+        }
+
+        states.publish(ConnectionState::Reconnecting { attempt, delay });
+        async_std::task::sleep(delay).await;
+
+        match connect(url).await {
+            Ok((client, notifications)) => {
+                *ws.lock().await = Some(client);
+                return Some(notifications);
             }
-        });
-        self.ws = Some(client);
+            Err(err) => warn!("signal reconnect attempt {} failed: {}", attempt, err),
+        }
+
+        delay = std::cmp::min(delay * 2, config.max_delay);
+    }
+}
+
+/// Runs for the lifetime of an opened signaler, forwarding notifications and
+/// re-establishing the websocket when it drops (if enabled).
+async fn supervise(
+    url: String,
+    ws: Arc<Mutex<Option<WsClient>>>,
+    mut notifications: Notifications,
+    tx: mpsc::Sender<SignalNotification>,
+    reconnect: Option<ReconnectConfig>,
+    states: StateSubscribers,
+) {
+    loop {
+        forward_notifications(notifications, tx.clone()).await;
+        if tx.is_closed() {
+            return;
+        }
+        ws.lock().await.take();
+        states.publish(ConnectionState::Disconnected);
+
+        let config = match &reconnect {
+            Some(config) => config,
+            None => return,
+        };
+
+        notifications = match reconnect_with_backoff(&url, &ws, config, &states).await {
+            Some(notifications) => notifications,
+            None => {
+                states.publish(ConnectionState::Failed);
+                return;
+            }
+        };
+        states.publish(ConnectionState::Reconnected);
+
+        if tx
+            .clone()
+            .send(SignalNotification::Reconnected)
+            .await
+            .is_err()
+        {
+            warn!("could not deliver reconnected notification, receiver dropped");
+            return;
+        }
+    }
+}
+
+#[async_trait]
+impl Signal for JsonRPCSignaler {
+    async fn open(&mut self) -> Result<mpsc::Receiver<SignalNotification>, Error> {
+        let (client, notifications) = connect(&self.url).await?;
+        *self.ws.lock().await = Some(client);
+        self.states.publish(ConnectionState::Connected);
+
+        let (tx, rx) = mpsc::channel(16);
+
+        let (task, handle) = future::abortable(supervise(
+            self.url.clone(),
+            self.ws.clone(),
+            notifications,
+            tx,
+            self.reconnect.clone(),
+            self.states.clone(),
+        ));
+        self.executor.spawn(task.map(|_| ()));
+        self.tasks.push(handle);
 
         Ok(rx)
     }
 
     async fn close(&mut self) -> Result<(), Error> {
+        if self.tasks.is_empty() {
+            return Err(Error::NotConnected);
+        }
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+
+        // dropping the client shuts down its background task and the websocket with it
+        self.ws.lock().await.take();
+        self.states.publish(ConnectionState::Closed);
+
         Ok(())
     }
     async fn ping(&self) -> Result<(), Error> {
-        if let Some(ws) = &self.ws {
-            println!("sending ping");
+        if let Some(ws) = &*self.ws.lock().await {
+            trace!("sending ping");
 
-            let response: String = ws.request("ping", JsonRpcParams::NoParams).await?;
-            println!("got response: {}", response);
+            let response: String = ws
+                .request("ping", JsonRpcParams::NoParams)
+                .await
+                .map_err(|e| Error::signal("ping", e))?;
+            trace!("ping response: {}", response);
 
             return Ok(());
         }
@@ -114,13 +333,16 @@ impl<'a> Signal for JsonRPCSignaler<'a> {
         sid: String,
         offer: SessionDescription,
     ) -> Result<SessionDescription, Error> {
-        if let Some(ws) = &self.ws {
+        if let Some(ws) = &*self.ws.lock().await {
             let msg: BTreeMap<&str, Value> = btreemap! {
-                "sid" => serde_json::to_value(sid).unwrap(),
-                "offer" => serde_json::to_value(offer).unwrap(),
+                "sid" => serde_json::to_value(sid)?,
+                "offer" => serde_json::to_value(offer)?,
             };
 
-            let answer: SessionDescription = ws.request("join", JsonRpcParams::Map(msg)).await?;
+            let answer: SessionDescription = ws
+                .request("join", JsonRpcParams::Map(msg))
+                .await
+                .map_err(|e| Error::signal("join", e))?;
             return Ok(answer);
         }
 
@@ -128,12 +350,15 @@ impl<'a> Signal for JsonRPCSignaler<'a> {
     }
 
     async fn offer(&self, offer: SessionDescription) -> Result<SessionDescription, Error> {
-        if let Some(ws) = &self.ws {
+        if let Some(ws) = &*self.ws.lock().await {
             let msg: BTreeMap<&str, Value> = btreemap! {
-                "desc" => serde_json::to_value(offer).unwrap(),
+                "desc" => serde_json::to_value(offer)?,
             };
 
-            let answer: SessionDescription = ws.request("offer", JsonRpcParams::Map(msg)).await?;
+            let answer: SessionDescription = ws
+                .request("offer", JsonRpcParams::Map(msg))
+                .await
+                .map_err(|e| Error::signal("offer", e))?;
             return Ok(answer);
         }
 
@@ -141,12 +366,14 @@ impl<'a> Signal for JsonRPCSignaler<'a> {
     }
 
     async fn answer(&self, answer: SessionDescription) -> Result<(), Error> {
-        if let Some(ws) = &self.ws {
+        if let Some(ws) = &*self.ws.lock().await {
             let msg: BTreeMap<&str, Value> = btreemap! {
-                "desc" => serde_json::to_value(answer).unwrap(),
+                "desc" => serde_json::to_value(answer)?,
             };
 
-            ws.notification("answer", JsonRpcParams::Map(msg)).await?;
+            ws.notification("answer", JsonRpcParams::Map(msg))
+                .await
+                .map_err(|e| Error::signal("answer", e))?;
             return Ok(());
         }
 
@@ -154,13 +381,15 @@ impl<'a> Signal for JsonRPCSignaler<'a> {
     }
 
     async fn trickle(&self, target: u32, candidate: TrickleCandidate) -> Result<(), Error> {
-        if let Some(ws) = &self.ws {
+        if let Some(ws) = &*self.ws.lock().await {
             let msg: BTreeMap<&str, Value> = btreemap! {
-                "target" => serde_json::to_value(target).unwrap(),
-                "candidate" => serde_json::to_value(candidate).unwrap(),
+                "target" => serde_json::to_value(target)?,
+                "candidate" => serde_json::to_value(candidate)?,
             };
 
-            ws.notification("trickle", JsonRpcParams::Map(msg)).await?;
+            ws.notification("trickle", JsonRpcParams::Map(msg))
+                .await
+                .map_err(|e| Error::signal("trickle", e))?;
             return Ok(());
         }
 
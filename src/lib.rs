@@ -1,32 +1,64 @@
 use async_mutex::Mutex;
 use async_trait::async_trait;
-use derive_more::Display;
-use futures::channel::mpsc;
+use codec::CodecPreferences;
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, AbortHandle, FutureExt};
 use futures::stream::StreamExt;
 use gst::prelude::*;
 use log::*;
+use record::Recorder;
+use sdp::Sdp;
 use serde::{Deserialize, Serialize};
+use simulcast::{Simulcast, SimulcastGroup};
+use state::StateWatch;
+use std::collections::HashSet;
 use std::sync::Arc;
-
+use std::time::Duration;
+use tracks::TrackTable;
+use transports::{Observers, Session, Transports};
+
+pub mod abr;
+pub mod codec;
+pub mod config;
+pub mod datachannel;
+mod error;
+pub mod executor;
+pub mod ion;
 pub mod jsonrpc;
 pub mod macos;
-
-const STUN_SERVER: &str = "stun://stun.l.google.com:19302";
-
-#[derive(Debug, Display)]
-pub enum Error {
-    WebsocketError(jsonrpsee::ws_client::Error),
-    SDPError,
-    NotConnected,
-}
-
-impl From<jsonrpsee::ws_client::Error> for Error {
-    fn from(i: jsonrpsee::ws_client::Error) -> Error {
-        Error::WebsocketError(i)
-    }
-}
-
-impl std::error::Error for Error {}
+mod negotiation;
+pub mod publish;
+pub mod record;
+pub mod relay;
+mod sdp;
+pub mod sfu_api;
+pub mod simulcast;
+pub mod state;
+pub mod stats;
+pub mod subscribe;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod tracks;
+mod transports;
+
+pub use abr::{AbrConfig, AbrController, EncoderControl, Resolution};
+pub use codec::{AudioCodec, CodecPreferences, VideoCodec};
+pub use config::{ClientConfig, IceServer};
+pub use datachannel::{DataChannel, DataChannelMessage, DataChannelOptions};
+pub use error::Error;
+pub use executor::{Executor, MainLoopThread};
+pub use publish::{LocalAudioTrack, LocalVideoTrack, PublishedTrack, Publisher};
+pub use record::{Container, Grouping, RecordConfig};
+pub use relay::{EncodedTrack, Relay, RelayedTrack};
+pub use sfu_api::{Layer, SfuApi};
+pub use simulcast::SimulcastLayer;
+pub use state::{StateChange, Transport, TransportState};
+pub use stats::{
+    IcePairStats, InboundRtpStats, OutboundRtpStats, RemoteInboundRtpStats, RtpRate, Stats,
+    StatsReport, TransportStats,
+};
+pub use subscribe::{DecodedTrack, Subscriber};
+pub use tracks::{RemoteTrack, TrackEvent, TrackKind, TrackReceiver};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SessionDescription {
@@ -53,11 +85,74 @@ pub enum SignalNotification {
         target: u32,
         candidate: TrickleCandidate,
     },
+    /// The signal transport was re-established after a drop. The sfu dropped the peers of
+    /// the old connection, so the client joins again with new transports.
+    Reconnected,
+}
+
+/// Events from a joined session, returned by `Client::join`.
+#[derive(Debug)]
+pub enum ClientEvent {
+    PublisherNegotiated,
+    SubscriberNegotiated,
+    /// The session was joined again after the signal reconnected, from new transports:
+    /// the remote tracks are added again, data channels created with
+    /// `Client::create_data_channel` were closed with the old publisher.
+    Rejoined,
+    /// Ice was restarted on a transport, by `Client::restart_ice` or automatically for the
+    /// publisher, or by an sfu offer with new credentials for the subscriber.
+    IceRestarted(Transport),
+    /// The codec an m-line was negotiated with, reported after every negotiation of its
+    /// transport. Subscriber m-lines match `RemoteTrack::mline`.
+    CodecNegotiated {
+        transport: Transport,
+        mline: u32,
+        codec: String,
+    },
+    /// A background negotiation or trickle step failed. The session keeps running,
+    /// but the affected transport may need a rejoin.
+    Error(Error),
+}
+
+impl ClientEvent {
+    fn dispatch(
+        events: &mpsc::UnboundedSender<ClientEvent>,
+        result: Result<Option<ClientEvent>, Error>,
+    ) {
+        let event = match result {
+            Ok(Some(event)) => event,
+            Ok(None) => return,
+            Err(err) => {
+                warn!("session error: {}", err);
+                ClientEvent::Error(err)
+            }
+        };
+
+        // the caller may not be interested in events at all
+        let _ = events.unbounded_send(event);
+    }
+
+    /// Reports the codecs of a completed negotiation.
+    fn codecs(
+        events: &mpsc::UnboundedSender<ClientEvent>,
+        transport: Transport,
+        codecs: Vec<(u32, String)>,
+    ) {
+        for (mline, codec) in codecs {
+            let _ = events.unbounded_send(ClientEvent::CodecNegotiated {
+                transport,
+                mline,
+                codec,
+            });
+        }
+    }
 }
 
 enum WebrtcBinEvent {
     NegotiationNeeded,
     IceCandidate(TrickleCandidate),
+    /// Renegotiate with fresh ice credentials, replying to `Client::restart_ice` if it asked.
+    IceRestart(Option<oneshot::Sender<Result<(), Error>>>),
 }
 
 #[async_trait]
@@ -79,310 +174,673 @@ pub trait Signal {
 
 pub struct Client<S: Signal + Send + Sync + 'static> {
     signal: Arc<Mutex<S>>,
-
-    pub publisher: gst::Element,
-    pub subscriber: gst::Element,
+    pipeline: gst::Pipeline,
+    transports: Transports,
+
+    tasks: Vec<AbortHandle>,
+    publisher_events: Option<mpsc::UnboundedSender<WebrtcBinEvent>>,
+    remote_channels: Option<mpsc::UnboundedReceiver<DataChannel>>,
+    sfu_api: SfuApi,
+    simulcast: Simulcast,
+    tracks: TrackTable,
+    recorder: Option<Recorder>,
+    states: StateWatch,
+    config: ClientConfig,
 }
 
 impl<S: Signal + Send + Sync> Client<S> {
-    pub fn new<'a>(
+    /// Creates a client that owns its transports: a publisher and subscriber webrtcbin
+    /// are created, configured and added to `pipeline`.
+    /// Publish sources are linked through `publish`, or as rtp through `request_publish_pad`
+    /// or `link_publish_source`.
+    pub fn new(
+        signal: S,
+        pipeline: &gst::Pipeline,
+        config: ClientConfig,
+    ) -> Result<Client<S>, Error> {
+        let publisher = gst::ElementFactory::make("webrtcbin", None)
+            .map_err(|_| Error::ElementNotFound("webrtcbin".to_string()))?;
+        let subscriber = gst::ElementFactory::make("webrtcbin", None)
+            .map_err(|_| Error::ElementNotFound("webrtcbin".to_string()))?;
+
+        pipeline
+            .add_many(&[&publisher, &subscriber])
+            .map_err(|e| Error::pipeline("adding transports to pipeline", e))?;
+
+        let client = Client::configure(signal, pipeline, publisher, subscriber, &config)?;
+
+        for pc in &[client.publisher(), client.subscriber()] {
+            pc.sync_state_with_parent()
+                .map_err(|e| Error::pipeline("syncing transport state", e))?;
+        }
+
+        Ok(client)
+    }
+
+    /// Creates a client from webrtcbin elements that already exist in `pipeline`,
+    /// e.g. `webrtcbin name=publisher` from a `parse_launch` description. Like owned
+    /// transports they are replaced on `leave`, by new webrtcbins of the same names.
+    pub fn adopt<'a>(
         signal: S,
-        pipeline: gst::Pipeline,
+        pipeline: &gst::Pipeline,
         publisher: &'a str,
         subscriber: &'a str,
-    ) -> Client<S> {
-        let publisher = pipeline.get_by_name(publisher).unwrap();
-        let subscriber = pipeline.get_by_name(subscriber).unwrap();
-        //let publisher = gst::ElementFactory::make("webrtcbin", Some("publisher"))
-        //    .expect("error creating webrtcbin");
-        publisher.set_property_from_str("stun-server", STUN_SERVER);
-        publisher.set_property_from_str("bundle-policy", "max-bundle");
-
-        //let subscriber = gst::ElementFactory::make("webrtcbin", Some("subscriber"))
-        //    .expect("error creating webrtcbin");
-        subscriber.set_property_from_str("stun-server", STUN_SERVER);
-        subscriber.set_property_from_str("bundle-policy", "max-bundle");
-
-        //pipeline
-        //    .add_many(&[&publisher, &subscriber])
-        //    .expect("error adding transports to pipeline");
-
-        //publisher.sync_state_with_parent().unwrap();
-        //subscriber.sync_state_with_parent().unwrap();
-
-        Client {
+        config: ClientConfig,
+    ) -> Result<Client<S>, Error> {
+        let publisher = pipeline
+            .get_by_name(publisher)
+            .ok_or_else(|| Error::ElementNotFound(publisher.to_string()))?;
+        let subscriber = pipeline
+            .get_by_name(subscriber)
+            .ok_or_else(|| Error::ElementNotFound(subscriber.to_string()))?;
+
+        Client::configure(signal, pipeline, publisher, subscriber, &config)
+    }
+
+    fn configure(
+        signal: S,
+        pipeline: &gst::Pipeline,
+        publisher: gst::Element,
+        subscriber: gst::Element,
+        config: &ClientConfig,
+    ) -> Result<Client<S>, Error> {
+        let (channels_tx, channels_rx) = mpsc::unbounded();
+        let observers = Observers {
+            tracks: TrackTable::new(pipeline),
+            states: StateWatch::default(),
+            sfu_api: SfuApi::default(),
+            channels: channels_tx,
+        };
+        let transports =
+            Transports::new(pipeline, publisher, subscriber, config, observers.clone())?;
+
+        Ok(Client {
             signal: Arc::new(Mutex::new(signal)),
-            publisher: publisher,
-            subscriber: subscriber,
-        }
+            pipeline: pipeline.clone(),
+            transports,
+            tasks: vec![],
+            publisher_events: None,
+            remote_channels: Some(channels_rx),
+            sfu_api: observers.sfu_api,
+            simulcast: Simulcast::default(),
+            tracks: observers.tracks,
+            recorder: None,
+            states: observers.states,
+            config: config.clone(),
+        })
     }
 
-    pub async fn join(&mut self, sid: String) -> Result<(), Error> {
-        let mut rx = { self.signal.lock().await.open().await? };
+    pub fn pipeline(&self) -> &gst::Pipeline {
+        &self.pipeline
+    }
 
-        let pub_clone = self.publisher.clone();
-        let sub_clone = self.subscriber.clone();
-        let signal = self.signal.clone();
+    /// The publisher webrtcbin. It is replaced by a new one on `leave`, don't hold on to it
+    /// across sessions.
+    pub fn publisher(&self) -> gst::Element {
+        self.transports.publisher()
+    }
 
-        glib::MainContext::default().spawn(async move {
-            use SignalNotification::*;
-            while let Some(notification) = rx.next().await {
-                match notification {
-                    Trickle { target, candidate } => {
-                        let pc: &gst::Element = match target {
-                            0 => &pub_clone,
-                            1 => &sub_clone,
-                            _ => panic!("got unexpected trickle target = {}", target),
-                        };
-                        debug!("adding ice candidate");
+    /// The subscriber webrtcbin, replaced like the publisher.
+    pub fn subscriber(&self) -> gst::Element {
+        self.transports.subscriber()
+    }
 
-                        pc.emit(
-                            "add-ice-candidate",
-                            &[&candidate.sdp_mline_index, &candidate.candidate],
-                        )
-                        .expect("could not call add-ice-candidate on pc");
-                    }
+    /// Requests a new sink pad on the publisher webrtcbin.
+    /// The pad expects `application/x-rtp` caps; linking it triggers renegotiation once joined.
+    pub fn request_publish_pad(&self) -> Result<gst::Pad, Error> {
+        publish::request_sink_pad(&self.publisher())
+    }
 
-                    Negotiate { offer } => {
-                        let pc = &sub_clone;
+    /// Publishes raw media sources, building the encode and payload chains for them.
+    pub fn publish(&self) -> Publisher {
+        Publisher::new(&self.pipeline, &self.transports, &self.config.codecs)
+    }
 
-                        let ret = gst_sdp::SDPMessage::parse_buffer(offer.sdp.as_bytes())
-                            .map_err(|_| Error::SDPError)
-                            .expect("error parsing inbound offer");
-                        let offer = gst_webrtc::WebRTCSessionDescription::new(
-                            gst_webrtc::WebRTCSDPType::Offer,
-                            ret,
-                        );
+    /// Publishes video frames pushed from Rust, encoded with the preferred video codec.
+    /// Once joined, the new track triggers renegotiation.
+    pub fn publish_raw_video(&self, caps: &gst::Caps) -> Result<LocalVideoTrack, Error> {
+        self.publish().add_raw_video(caps)
+    }
 
-                        pc.emit("set-remote-description", &[&offer, &None::<gst::Promise>])
-                            .expect("sub failed setting remote description");
+    /// Publishes pcm audio pushed from Rust, encoded with the preferred audio codec.
+    pub fn publish_raw_audio(&self, caps: &gst::Caps) -> Result<LocalAudioTrack, Error> {
+        self.publish().add_raw_audio(caps)
+    }
 
-                        let (promise, fut) = gst::Promise::new_future();
+    /// Links the `src` pad of an rtp payloading element (already in the pipeline)
+    /// to a new publisher sink pad.
+    pub fn link_publish_source(&self, source: &gst::Element) -> Result<gst::Pad, Error> {
+        let src = source
+            .get_static_pad("src")
+            .ok_or_else(|| Error::Pipeline {
+                context: format!("{} has no src pad", source.get_name()),
+                source: None,
+            })?;
+        let sink = self.request_publish_pad()?;
+
+        src.link(&sink)
+            .map_err(|e| Error::pipeline("linking publish source", e))?;
+
+        Ok(sink)
+    }
 
-                        pc.emit("create-answer", &[&None::<gst::Structure>, &promise])
-                            .expect("sub failed to emit create-answer signal on");
+    /// Publishes one video track as up to three simulcast encodings. Each element is the
+    /// rtp payloader ending an encoder branch for that layer; the branches are linked to
+    /// the publisher and folded into a single rid based m-line when negotiating.
+    ///
+    /// Packets leaving each payloader are tagged with the rid of its layer, in the header
+    /// extension with id `simulcast::RID_EXTENSION_ID`.
+    pub fn publish_simulcast(
+        &self,
+        mut branches: Vec<(SimulcastLayer, gst::Element)>,
+    ) -> Result<(), Error> {
+        if branches.is_empty() || branches.len() > 3 {
+            return Err(Error::Simulcast(
+                "between one and three encodings are supported".to_string(),
+            ));
+        }
+        if self.simulcast.is_active() {
+            return Err(Error::Simulcast(
+                "only one simulcast track can be published".to_string(),
+            ));
+        }
 
-                        let reply = fut.await;
+        branches.sort_by_key(|(layer, _)| *layer);
+        if branches.windows(2).any(|w| w[0].0 == w[1].0) {
+            return Err(Error::Simulcast("duplicate simulcast layer".to_string()));
+        }
 
-                        // Check if we got a valid offer
-                        let reply = match reply {
-                            Ok(Some(reply)) => reply,
-                            Ok(None) => {
-                                error!("sub answer creation got no reponse");
-                                continue;
-                            }
-                            Err(err) => {
-                                error!("sub answer creation got error reponse: {:?}", err);
-                                continue;
-                            }
-                        };
+        let mut layers = vec![];
+        for (layer, payloader) in branches.iter() {
+            simulcast::tag_rid(payloader, *layer)?;
+            let pad = self.link_publish_source(payloader)?;
+            // webrtcbin names request pads after the m-line they end up in
+            let mline = pad
+                .get_name()
+                .trim_start_matches("sink_")
+                .parse::<u32>()
+                .map_err(|_| Error::Simulcast(format!("unexpected pad {}", pad.get_name())))?;
+            layers.push((*layer, mline));
+        }
 
-                        let answer = reply
-                            .get_value("answer")
-                            .expect("Invalid argument")
-                            .get::<gst_webrtc::WebRTCSessionDescription>()
-                            .expect("Invalid argument")
-                            .unwrap();
+        self.simulcast.set_group(SimulcastGroup { layers })
+    }
 
-                        pc.emit("set-local-description", &[&answer, &None::<gst::Promise>])
-                            .expect("sub answer error set-local-description");
+    /// Creates a data channel on the publisher transport. Creating the first channel
+    /// triggers renegotiation to add the sctp association.
+    pub fn create_data_channel(
+        &self,
+        label: &str,
+        options: DataChannelOptions,
+    ) -> Result<DataChannel, Error> {
+        DataChannel::create(&self.publisher(), label, &options)
+    }
 
-                        let answer = SessionDescription {
-                            t: "answer".to_string(),
-                            sdp: answer.get_sdp().as_text().unwrap(),
-                        };
+    /// Takes the stream of data channels the remote side opens on the subscriber transport.
+    /// Returns `None` if it was already taken.
+    pub fn remote_data_channels(&mut self) -> Option<mpsc::UnboundedReceiver<DataChannel>> {
+        self.remote_channels.take()
+    }
 
-                        // signal lock exists for this scope
-                        signal
-                            .lock()
-                            .await
-                            .answer(answer)
-                            .await
-                            .expect("sub error sending answer over signal");
-                    }
-                }
+    /// Handle to ion-sfu's api channel, usable once the sfu opened it on the subscriber.
+    pub fn sfu_api(&self) -> SfuApi {
+        self.sfu_api.clone()
+    }
+
+    /// Subscribes to the remote tracks of the session. Every receiver gets an `Added`
+    /// event per track as its pad appears and `Removed` once the sfu drops it.
+    /// Tracks that were added before subscribing are not replayed.
+    pub fn tracks(&self) -> mpsc::UnboundedReceiver<TrackEvent> {
+        self.tracks.subscribe()
+    }
+
+    /// Decodes the remote tracks added from now on to appsinks, see `Subscriber`.
+    pub fn subscribe(&self) -> Subscriber {
+        Subscriber::new(&self.pipeline, &self.tracks, &self.config.executor)
+    }
+
+    /// Depayloads the remote tracks added from now on without decoding them. The raw rtp
+    /// of a track is available from `RemoteTrack::request_pad` instead. The branches are
+    /// removed once the receiver is dropped.
+    pub fn encoded_tracks(&self) -> TrackReceiver<EncodedTrack> {
+        relay::encoded_tracks(&self.pipeline, &self.tracks, &self.config.executor)
+    }
+
+    /// Republishes the remote tracks added from now on through another client's
+    /// publisher, see `Relay`.
+    pub fn relay(&self) -> Relay {
+        Relay::new(&self.pipeline, &self.tracks, &self.config.executor)
+    }
+
+    /// Records the remote tracks added from now on to files, without re-encoding. Keeps
+    /// recording across `leave` and `join` until `stop_recording`, replacing a running
+    /// recorder finalizes its files first.
+    pub async fn record(&mut self, config: RecordConfig) -> Result<(), Error> {
+        self.stop_recording().await;
+        self.recorder = Some(Recorder::start(
+            &self.pipeline,
+            &self.tracks,
+            &self.config.executor,
+            config,
+        )?);
+        Ok(())
+    }
+
+    /// Stops recording and finalizes the files being written.
+    pub async fn stop_recording(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            recorder.finish().await;
+        }
+    }
+
+    /// Subscribes to the connection state of both transports. The current state of each is
+    /// delivered first, then every change of its ice connection, peer connection or
+    /// gathering state.
+    pub fn states(&self) -> mpsc::UnboundedReceiver<StateChange> {
+        self.states.subscribe()
+    }
+
+    pub fn transport_state(&self, transport: Transport) -> TransportState {
+        self.states.current(transport)
+    }
+
+    /// Collects the rtp and ice pair stats of both transports.
+    pub async fn stats(&self) -> Result<Stats, Error> {
+        stats::collect(&self.publisher(), &self.subscriber()).await
+    }
+
+    /// Samples the stats of both transports every `interval` on the client's executor,
+    /// with bitrate and loss computed over each interval. Sampling stops once the
+    /// receiver is dropped, independently of `leave`.
+    pub fn stats_stream(&self, interval: Duration) -> mpsc::UnboundedReceiver<StatsReport> {
+        let (tx, rx) = mpsc::unbounded();
+        self.config
+            .executor
+            .spawn(stats::sample(self.transports.clone(), interval, tx));
+        rx
+    }
+
+    /// Starts adapting the registered encoders to the publisher's congestion feedback,
+    /// sampling stats every `controller.interval()`. Runs until `leave`, start it again
+    /// after a new `join`.
+    pub fn adapt_bitrate(&mut self, controller: AbrController) {
+        let mut reports = self.stats_stream(controller.interval());
+        let (task, handle) = future::abortable(async move {
+            let mut controller = controller;
+            while let Some(report) = reports.next().await {
+                controller.update(&report);
             }
         });
+        self.config.executor.spawn(task.map(|_| ()));
+        self.tasks.push(handle);
+    }
 
-        //        self.publisher
-        //            .emit(
-        //                "create-data-channel",
-        //                &[&"ion-sfu", &None::<gst::Structure>],
-        //            )
-        //            .unwrap();
-        //
-        let (promise, fut) = gst::Promise::new_future();
-        self.publisher
-            .emit("create-offer", &[&None::<gst::Structure>, &promise])
-            .expect("Failed to emit create-offer signal");
-
-        let reply = fut.await;
-
-        // Check if we got a valid offer
-        let reply = match reply {
-            Ok(Some(reply)) => reply,
-            Ok(None) => {
-                error!("Offer creation got no reponse");
-                return Err(Error::SDPError);
-            }
-            Err(err) => {
-                error!("Offer creation got error reponse: {:?}", err);
-                return Err(Error::SDPError);
-            }
-        };
+    /// Waits until every linked publisher sink pad has negotiated caps. webrtcbin can only
+    /// describe a sending m-line once caps (including the ssrc) reached its pad, an offer
+    /// created earlier leaves the track out.
+    ///
+    /// Only pads linked when the wait starts are considered. Fails with `Error::Timeout`
+    /// if some pad has no caps after `timeout`.
+    pub async fn wait_until_ready(&self, timeout: Duration) -> Result<(), Error> {
+        self.transports.wait_until_ready(timeout).await
+    }
 
-        let offer = reply
-            .get_value("offer")
-            .expect("Invalid argument")
-            .get::<gst_webrtc::WebRTCSessionDescription>()
-            .expect("Invalid argument")
-            .unwrap();
+    /// Joins session `sid`. Failures that happen in the background after the join
+    /// (trickle, renegotiation, rejoining) are reported on the returned event stream.
+    pub async fn join(
+        &mut self,
+        sid: String,
+    ) -> Result<mpsc::UnboundedReceiver<ClientEvent>, Error> {
+        if let Some(timeout) = self.config.ready_timeout {
+            self.wait_until_ready(timeout).await?;
+        }
 
-        debug!("Created pub offer {:#?}", offer.get_sdp());
+        let mut rx = { self.signal.lock().await.open().await? };
+        let (events_tx, events_rx) = mpsc::unbounded();
 
-        self.publisher
-            .emit("set-local-description", &[&offer, &None::<gst::Promise>])
-            .expect("Failed to emit set-local-description signal");
+        let transports = self.transports.clone();
+        let signal = self.signal.clone();
+        let session = sid.clone();
+        let events = events_tx.clone();
+        let simulcast = self.simulcast.clone();
+        let tracks = self.tracks.clone();
+        let config = self.config.clone();
 
-        let offer = SessionDescription {
-            t: "offer".to_string(),
-            sdp: offer.get_sdp().as_text().unwrap(),
-        };
+        let (notifications, handle) = future::abortable(async move {
+            use SignalNotification::*;
+            let mut subscriber_ufrag: Option<String> = None;
+            while let Some(notification) = rx.next().await {
+                let result = match notification {
+                    Trickle { target, candidate } => {
+                        debug!("adding ice candidate");
+                        let added = match target {
+                            0 => {
+                                negotiation::add_ice_candidate(&transports.publisher(), &candidate)
+                            }
+                            1 => {
+                                negotiation::add_ice_candidate(&transports.subscriber(), &candidate)
+                            }
+                            _ => Err(Error::UnknownTrickleTarget(target)),
+                        };
+                        added.map(|_| None)
+                    }
+
+                    Negotiate { offer } => {
+                        // pads for new m-lines appear while the offer is applied, the
+                        // tracks carry the codec our answer will pick
+                        tracks.update_from_offer(&config.codecs.munge(&offer.sdp));
+
+                        // the sfu restarts the subscriber by offering new credentials,
+                        // webrtcbin applies them like any other remote description
+                        let ufrag = Sdp::parse(&offer.sdp).ice_ufrag().map(str::to_string);
+                        let restarted = subscriber_ufrag.is_some() && ufrag != subscriber_ufrag;
+                        subscriber_ufrag = ufrag;
+
+                        let answered = Client::answer_subscriber(
+                            &signal,
+                            &transports.subscriber(),
+                            offer,
+                            &config.codecs,
+                        )
+                        .await;
+                        if restarted && answered.is_ok() {
+                            info!("sfu restarted ice on the subscriber");
+                            let event = ClientEvent::IceRestarted(Transport::Subscriber);
+                            ClientEvent::dispatch(&events, Ok(Some(event)));
+                        }
+                        answered.map(|negotiated| {
+                            ClientEvent::codecs(&events, Transport::Subscriber, negotiated);
+                            Some(ClientEvent::SubscriberNegotiated)
+                        })
+                    }
 
-        // send join offer to server and await answer
-        let answer = self.signal.lock().await.join(sid, offer).await?;
+                    Reconnected => {
+                        // the sfu closed both peer connections with the old socket, their
+                        // dtls can't be restarted: join again as a new peer
+                        info!("signal reconnected, rejoining {}", session);
+                        subscriber_ufrag = None;
+                        let rejoined = Client::rejoin(
+                            &signal,
+                            &transports,
+                            &simulcast,
+                            session.clone(),
+                            &config,
+                        )
+                        .await;
+                        rejoined.map(|negotiated| {
+                            ClientEvent::codecs(&events, Transport::Publisher, negotiated);
+                            Some(ClientEvent::Rejoined)
+                        })
+                    }
+                };
 
-        trace!("Received pub answer");
+                ClientEvent::dispatch(&events, result);
+            }
+        });
+        self.config.executor.spawn(notifications.map(|_| ()));
+        self.tasks.push(handle);
 
-        let ret = gst_sdp::SDPMessage::parse_buffer(answer.sdp.as_bytes())
-            .map_err(|_| Error::SDPError)?;
-        let answer =
-            gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Answer, ret);
+        if self.config.sfu_api {
+            self.transports.open_api_channel()?;
+        }
 
-        self.publisher
-            .emit("set-remote-description", &[&answer, &None::<gst::Promise>])
-            .unwrap();
+        let codecs = Client::negotiate_publisher(
+            &self.signal,
+            &self.publisher(),
+            &self.simulcast,
+            Some(sid),
+            false,
+        )
+        .await?;
+        ClientEvent::codecs(&events_tx, Transport::Publisher, codecs);
 
         let (tx, mut rx) = mpsc::unbounded();
-        let tx_clone = tx.clone();
+        self.publisher_events = Some(tx.clone());
+
+        if self.config.auto_ice_restart {
+            let restart = tx.clone();
+            let mut states = self.states.subscribe();
+            let (watch, handle) = future::abortable(async move {
+                // each transport fails and recovers on its own
+                let mut failed = HashSet::new();
+                while let Some(change) = states.next().await {
+                    let state = change.state;
+                    let now_failed = state.ice_connection == state::IceConnectionState::Failed
+                        || state.connection == state::PeerConnectionState::Failed;
+
+                    // only restart on the transition, the restart itself reports states
+                    // until the new candidates connect
+                    if now_failed && !failed.contains(&change.transport) {
+                        match change.transport {
+                            Transport::Publisher => {
+                                info!("publisher transport failed, restarting ice");
+                                let _ = restart.unbounded_send(WebrtcBinEvent::IceRestart(None));
+                            }
+                            Transport::Subscriber => {
+                                warn!("subscriber transport failed, waiting for the sfu to restart it")
+                            }
+                        }
+                    }
+                    if now_failed {
+                        failed.insert(change.transport);
+                    } else {
+                        failed.remove(&change.transport);
+                    }
+                }
+            });
+            self.config.executor.spawn(watch.map(|_| ()));
+            self.tasks.push(handle);
+        }
+
+        self.transports.attach(Session {
+            webrtcbin: tx,
+            events: events_tx.clone(),
+        })?;
+
         let signal = self.signal.clone();
-        let pub_clone = self.publisher.clone();
-
-        self.publisher
-            .connect("on-negotiation-needed", false, move |_| {
-                info!("pub negotiation needed");
-                tx.unbounded_send(WebrtcBinEvent::NegotiationNeeded)
-                    .unwrap();
-                None
-            })
-            .unwrap();
-
-        self.publisher
-            .connect("on-ice-candidate", false, move |values| {
-                let pc = values[0].get::<gst::Element>().expect("Invalid argument").unwrap();
-                let mlineindex = values[1].get_some::<u32>().expect("Invalid argument");
-                let candidate = values[2]
-                    .get::<String>()
-                    .expect("Invalid argument")
-                    .unwrap();
-
-                pc.emit("add-ice-candidate", &[&mlineindex, &candidate]).unwrap();
-
-                tx_clone
-                    .unbounded_send(WebrtcBinEvent::IceCandidate(TrickleCandidate {
-                        sdp_mline_index: mlineindex,
-                        sdp_mid: None,
-                        candidate: candidate,
-                    }))
-                    .unwrap();
-            
-
-                None
-            })
-            .unwrap();
-
-        glib::MainContext::default().spawn(async move {
+        let transports = self.transports.clone();
+        let events = events_tx;
+        let simulcast = self.simulcast.clone();
+
+        let (publisher_events, handle) = future::abortable(async move {
             while let Some(evt) = rx.next().await {
-                match evt {
+                let result = match evt {
                     WebrtcBinEvent::NegotiationNeeded => {
-                        Client::on_pub_negotiation_needed(&signal, &pub_clone)
-                            .await
-                            .unwrap()
+                        let renegotiated = Client::negotiate_publisher(
+                            &signal,
+                            &transports.publisher(),
+                            &simulcast,
+                            None,
+                            false,
+                        )
+                        .await;
+                        renegotiated.map(|negotiated| {
+                            ClientEvent::codecs(&events, Transport::Publisher, negotiated);
+                            Some(ClientEvent::PublisherNegotiated)
+                        })
+                    }
+                    WebrtcBinEvent::IceRestart(reply) => {
+                        // an ice restart keeps the codecs, nothing to report
+                        let restarted = Client::negotiate_publisher(
+                            &signal,
+                            &transports.publisher(),
+                            &simulcast,
+                            None,
+                            true,
+                        )
+                        .await
+                        .map(|_| ());
+                        let event = ClientEvent::IceRestarted(Transport::Publisher);
+                        match reply {
+                            // the caller of restart_ice gets the error instead of the event stream
+                            Some(reply) => {
+                                let result = restarted.as_ref().ok().map(|_| event);
+                                let _ = reply.send(restarted);
+                                Ok(result)
+                            }
+                            None => restarted.map(|_| Some(event)),
+                        }
                     }
                     WebrtcBinEvent::IceCandidate(candidate) => {
                         //send pub ice candidate to server
                         debug!("publisher sending ice candidate");
-                        signal.lock().await.trickle(0, candidate).await.unwrap()
+                        signal
+                            .lock()
+                            .await
+                            .trickle(0, candidate)
+                            .await
+                            .map(|_| None)
                     }
-                }
+                };
+
+                ClientEvent::dispatch(&events, result);
             }
 
-            panic!("done");
+            debug!("publisher event loop finished");
         });
+        self.config.executor.spawn(publisher_events.map(|_| ()));
+        self.tasks.push(handle);
 
-        Ok(())
+        Ok(events_rx)
+    }
+
+    /// Restarts ice on the publisher: a new offer with fresh credentials is sent over
+    /// `Signal::offer`, for example after a network change. Runs in line with the
+    /// renegotiations of the joined session, fails with `Error::NotConnected` before `join`.
+    ///
+    /// The subscriber can't be restarted from this side, it follows once the sfu sends an
+    /// offer with new credentials, reported as `ClientEvent::IceRestarted`.
+    pub async fn restart_ice(&self) -> Result<(), Error> {
+        let (reply, result) = oneshot::channel();
+        self.publisher_events
+            .as_ref()
+            .ok_or(Error::NotConnected)?
+            .unbounded_send(WebrtcBinEvent::IceRestart(Some(reply)))
+            .map_err(|_| Error::NotConnected)?;
+
+        result.await.map_err(|_| Error::NotConnected)?
     }
 
-    async fn on_pub_negotiation_needed(
+    /// Leaves the session: stops the background tasks, tears down both peer connections
+    /// and closes the signal. The client can `join` again afterwards, with new transports:
+    /// publish sources are moved over to the new publisher, data channels created with
+    /// `create_data_channel` are closed with the old one.
+    pub async fn leave(&mut self) -> Result<(), Error> {
+        self.transports.detach();
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        self.publisher_events = None;
+        self.tracks.clear();
+        // muxers need the end of stream before their transports stop feeding them
+        if let Some(recorder) = &self.recorder {
+            recorder.finish().await;
+        }
+
+        // webrtcbin has no close action and can't be reset, the next join needs fresh ones
+        self.transports.rebuild()?;
+
+        self.signal.lock().await.close().await
+    }
+
+    /// Joins `sid` again from fresh transports once the signal reconnected. The publisher
+    /// is connected to the session's event loop again after the join answer is applied.
+    async fn rejoin(
+        signal: &Arc<Mutex<S>>,
+        transports: &Transports,
+        simulcast: &Simulcast,
+        sid: String,
+        config: &ClientConfig,
+    ) -> Result<Vec<(u32, String)>, Error> {
+        transports.rebuild()?;
+        if let Some(timeout) = config.ready_timeout {
+            transports.wait_until_ready(timeout).await?;
+        }
+        if config.sfu_api {
+            transports.open_api_channel()?;
+        }
+
+        let negotiated = Client::negotiate_publisher(
+            signal,
+            &transports.publisher(),
+            simulcast,
+            Some(sid),
+            false,
+        )
+        .await?;
+        transports.resume()?;
+        Ok(negotiated)
+    }
+
+    /// Creates a publisher offer and exchanges it with the sfu. With `join` set the offer
+    /// is sent as a join for that session, otherwise as a renegotiation. Returns the
+    /// negotiated codec of each m-line.
+    async fn negotiate_publisher(
         signal: &Arc<Mutex<S>>,
         publisher: &gst::Element,
-    ) -> Result<(), Error> {
+        simulcast: &Simulcast,
+        join: Option<String>,
+        ice_restart: bool,
+    ) -> Result<Vec<(u32, String)>, Error> {
         info!("pub negotiations, creating offer");
-        let (promise, fut) = gst::Promise::new_future();
-        publisher
-            .emit("create-offer", &[&None::<gst::Structure>, &promise])
-            .expect("Failed to emit create-offer signal");
-
-        let reply = fut.await;
-
-        // Check if we got a valid offer
-        let reply = match reply {
-            Ok(Some(reply)) => reply,
-            Ok(None) => {
-                error!("Offer creation got no reponse");
-                return Err(Error::SDPError);
-            }
-            Err(err) => {
-                error!("Offer creation got error reponse: {:?}", err);
-                return Err(Error::SDPError);
-            }
+        let options = if ice_restart {
+            Some(gst::Structure::new("options", &[("ice-restart", &true)]))
+        } else {
+            None
         };
 
-        let offer = reply
-            .get_value("offer")
-            .expect("Invalid argument")
-            .get::<gst_webrtc::WebRTCSessionDescription>()
-            .expect("Invalid argument")
-            .unwrap();
-
+        let offer = negotiation::create_offer(publisher, options).await?;
         debug!("Created pub offer {:#?}", offer.get_sdp());
 
-        publisher
-            .emit("set-local-description", &[&offer, &None::<gst::Promise>])
-            .expect("Failed to emit set-local-description signal");
+        negotiation::set_local_description(publisher, &offer)?;
+        let mut offer = negotiation::to_signal(&offer)?;
+        // the fold travels with this negotiation, another may start before the answer
+        let (sdp, folded) = simulcast.munge_offer(&offer.sdp)?;
+        offer.sdp = sdp;
 
-        let offer = SessionDescription {
-            t: "offer".to_string(),
-            sdp: offer.get_sdp().as_text().unwrap(),
+        // send offer to server and await answer
+        let mut answer = match join {
+            Some(sid) => signal.lock().await.join(sid, offer).await?,
+            None => signal.lock().await.offer(offer).await?,
         };
 
-        // send join offer to server and await answer
-        let answer = signal.lock().await.offer(offer).await?;
-
         debug!("Received pub answer");
+        if let Some(folded) = folded {
+            answer.sdp = simulcast.restore_answer(&answer.sdp, folded)?;
+        }
+        let codecs = codec::negotiated(&answer.sdp);
 
-        let ret = gst_sdp::SDPMessage::parse_buffer(answer.sdp.as_bytes())
-            .map_err(|_| Error::SDPError)?;
-        let answer =
-            gst_webrtc::WebRTCSessionDescription::new(gst_webrtc::WebRTCSDPType::Answer, ret);
-
-        publisher
-            .emit("set-remote-description", &[&answer, &None::<gst::Promise>])
-            .unwrap();
+        let answer = negotiation::from_signal(&answer, gst_webrtc::WebRTCSDPType::Answer)?;
+        negotiation::set_remote_description(publisher, &answer)?;
 
         info!("pub negotiation completed");
 
-        Ok(())
+        Ok(codecs)
+    }
+
+    /// Answers an offer the sfu sent for the subscriber transport, restricted to the
+    /// preferred codecs. Returns the negotiated codec of each m-line.
+    async fn answer_subscriber(
+        signal: &Arc<Mutex<S>>,
+        subscriber: &gst::Element,
+        offer: SessionDescription,
+        codecs: &CodecPreferences,
+    ) -> Result<Vec<(u32, String)>, Error> {
+        let offer = negotiation::from_signal(&offer, gst_webrtc::WebRTCSDPType::Offer)?;
+        negotiation::set_remote_description(subscriber, &offer)?;
+
+        // webrtcbin answers with every codec it can receive, in the order of the offer
+        let answer = negotiation::create_answer(subscriber).await?;
+        let mut answer = negotiation::to_signal(&answer)?;
+        answer.sdp = codecs.munge(&answer.sdp);
+        let negotiated = codec::negotiated(&answer.sdp);
+
+        let local = negotiation::from_signal(&answer, gst_webrtc::WebRTCSDPType::Answer)?;
+        negotiation::set_local_description(subscriber, &local)?;
+
+        // signal lock exists for this scope
+        signal.lock().await.answer(answer).await?;
+        Ok(negotiated)
     }
 
     pub async fn ping(&self) -> Result<(), Error> {
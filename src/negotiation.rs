@@ -0,0 +1,111 @@
+//! Thin wrappers around the webrtcbin action signals used during negotiation.
+//! They report failures as `Error` instead of panicking so background tasks can
+//! surface them through `ClientEvent::Error`.
+
+use super::{Error, SessionDescription, TrickleCandidate};
+use gst::prelude::*;
+use gst_webrtc::{WebRTCSDPType, WebRTCSessionDescription};
+
+pub(crate) fn emit(
+    pc: &gst::Element,
+    signal: &'static str,
+    args: &[&dyn glib::ToValue],
+) -> Result<Option<glib::Value>, Error> {
+    pc.emit(signal, args).map_err(|e| Error::Action {
+        action: signal,
+        source: e,
+    })
+}
+
+async fn create_description(
+    pc: &gst::Element,
+    signal: &'static str,
+    field: &str,
+    options: Option<gst::Structure>,
+) -> Result<WebRTCSessionDescription, Error> {
+    let (promise, fut) = gst::Promise::new_future();
+    emit(pc, signal, &[&options, &promise])?;
+
+    let reply = match fut.await {
+        Ok(Some(reply)) => reply,
+        Ok(None) => return Err(Error::EmptyReply { action: signal }),
+        Err(err) => {
+            return Err(Error::Promise {
+                action: signal,
+                reason: format!("{:?}", err),
+            })
+        }
+    };
+
+    reply
+        .get_value(field)
+        .ok()
+        .and_then(|value| value.get::<WebRTCSessionDescription>().ok())
+        .flatten()
+        .ok_or(Error::EmptyReply { action: signal })
+}
+
+pub(crate) async fn create_offer(
+    pc: &gst::Element,
+    options: Option<gst::Structure>,
+) -> Result<WebRTCSessionDescription, Error> {
+    create_description(pc, "create-offer", "offer", options).await
+}
+
+pub(crate) async fn create_answer(pc: &gst::Element) -> Result<WebRTCSessionDescription, Error> {
+    create_description(pc, "create-answer", "answer", None).await
+}
+
+pub(crate) fn set_local_description(
+    pc: &gst::Element,
+    desc: &WebRTCSessionDescription,
+) -> Result<(), Error> {
+    emit(pc, "set-local-description", &[desc, &None::<gst::Promise>]).map(|_| ())
+}
+
+pub(crate) fn set_remote_description(
+    pc: &gst::Element,
+    desc: &WebRTCSessionDescription,
+) -> Result<(), Error> {
+    emit(pc, "set-remote-description", &[desc, &None::<gst::Promise>]).map(|_| ())
+}
+
+pub(crate) fn add_ice_candidate(
+    pc: &gst::Element,
+    candidate: &TrickleCandidate,
+) -> Result<(), Error> {
+    emit(
+        pc,
+        "add-ice-candidate",
+        &[&candidate.sdp_mline_index, &candidate.candidate],
+    )
+    .map(|_| ())
+}
+
+/// Parses a signaled description into the webrtcbin representation.
+pub(crate) fn from_signal(
+    desc: &SessionDescription,
+    t: WebRTCSDPType,
+) -> Result<WebRTCSessionDescription, Error> {
+    let sdp =
+        gst_sdp::SDPMessage::parse_buffer(desc.sdp.as_bytes()).map_err(|e| Error::SdpParse {
+            sdp: desc.sdp.clone(),
+            source: e,
+        })?;
+    Ok(WebRTCSessionDescription::new(t, sdp))
+}
+
+/// Converts a webrtcbin description into its signaled form.
+pub(crate) fn to_signal(desc: &WebRTCSessionDescription) -> Result<SessionDescription, Error> {
+    let t = match desc.get_type() {
+        WebRTCSDPType::Offer => "offer",
+        WebRTCSDPType::Answer => "answer",
+        WebRTCSDPType::Pranswer => "pranswer",
+        _ => "rollback",
+    };
+
+    Ok(SessionDescription {
+        t: t.to_string(),
+        sdp: desc.get_sdp().as_text().map_err(Error::SdpSerialize)?,
+    })
+}
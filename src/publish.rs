@@ -0,0 +1,375 @@
+//! Builds the convert → encode → pay chains between a raw media source and a publisher
+//! sink pad, so callers don't have to write them as `parse_launch` descriptions.
+
+use super::codec::{AudioCodec, CodecPreferences, VideoCodec};
+use super::tracks::TrackKind;
+use super::transports::Transports;
+use super::Error;
+use futures::channel::mpsc;
+use gst::prelude::*;
+use log::*;
+use std::sync::Mutex;
+
+/// A track linked to the publisher.
+#[derive(Clone, Debug)]
+pub struct PublishedTrack {
+    pub kind: TrackKind,
+    /// The encoder of the chain, e.g. for an `EncoderControl`.
+    pub encoder: gst::Element,
+    /// The publisher webrtcbin sink pad the track is sent from, replaced by the pad of the
+    /// same name when the publisher is rebuilt on `Client::leave`.
+    pub pad: gst::Pad,
+}
+
+pub(crate) fn request_sink_pad(publisher: &gst::Element) -> Result<gst::Pad, Error> {
+    publisher
+        .get_request_pad("sink_%u")
+        .ok_or_else(|| Error::Pipeline {
+            context: "publisher refused sink pad request".to_string(),
+            source: None,
+        })
+}
+
+/// An appsrc fed from Rust. Timestamps of pushed buffers are relative to the first one,
+/// which is played out at the pipeline's running time when it's pushed. Pushing blocks
+/// while the appsrc queue is full, so a producer faster than the encoder is paced by it
+/// instead of growing the queue without bound.
+#[derive(Debug)]
+struct RawSource {
+    appsrc: gst_app::AppSrc,
+    track: PublishedTrack,
+    /// Pts of the first buffer and the running time it was mapped to.
+    origin: Mutex<Option<(gst::ClockTime, gst::ClockTime)>>,
+}
+
+impl RawSource {
+    fn running_time(&self) -> gst::ClockTime {
+        match self.appsrc.get_clock() {
+            Some(clock) => clock.get_time() - self.appsrc.get_base_time(),
+            None => gst::ClockTime::from_seconds(0),
+        }
+    }
+
+    fn push(&self, mut buffer: gst::Buffer, pts: gst::ClockTime) -> Result<(), Error> {
+        let pts = {
+            let mut origin = self.origin.lock().unwrap();
+            let (first, running) = *origin.get_or_insert_with(|| (pts, self.running_time()));
+            // ClockTime subtraction gives none instead of going negative
+            let offset = pts - first;
+            if offset.is_none() {
+                return Err(Error::Pipeline {
+                    context: format!(
+                        "{}: pts {} is earlier than the first one, {}",
+                        self.appsrc.get_name(),
+                        pts,
+                        first
+                    ),
+                    source: None,
+                });
+            }
+            running + offset
+        };
+        buffer.make_mut().set_pts(pts);
+
+        self.appsrc
+            .push_buffer(buffer)
+            .map(|_| ())
+            .map_err(|err| Error::Pipeline {
+                context: format!("pushing to {}: {:?}", self.appsrc.get_name(), err),
+                source: None,
+            })
+    }
+
+    fn end(&self) -> Result<(), Error> {
+        self.appsrc
+            .end_of_stream()
+            .map(|_| ())
+            .map_err(|err| Error::Pipeline {
+                context: format!("ending {}: {:?}", self.appsrc.get_name(), err),
+                source: None,
+            })
+    }
+}
+
+/// A video track whose raw frames are pushed from Rust, see `Client::publish_raw_video`.
+#[derive(Debug)]
+pub struct LocalVideoTrack(RawSource);
+
+impl LocalVideoTrack {
+    /// Pushes one frame matching the track's caps. `pts` only needs to be monotonic,
+    /// e.g. the capture time of the frame, a pts earlier than the first pushed one is
+    /// refused. Blocks while the encoder is behind, so don't push from the main loop
+    /// thread.
+    pub fn push_frame(&self, buffer: gst::Buffer, pts: gst::ClockTime) -> Result<(), Error> {
+        self.0.push(buffer, pts)
+    }
+
+    /// Ends the track, the encoder drains and the publisher stops sending it.
+    pub fn end(&self) -> Result<(), Error> {
+        self.0.end()
+    }
+
+    pub fn track(&self) -> &PublishedTrack {
+        &self.0.track
+    }
+}
+
+/// An audio track whose pcm samples are pushed from Rust, see `Client::publish_raw_audio`.
+#[derive(Debug)]
+pub struct LocalAudioTrack(RawSource);
+
+impl LocalAudioTrack {
+    /// Pushes interleaved samples matching the track's caps, see `LocalVideoTrack::push_frame`.
+    pub fn push_samples(&self, buffer: gst::Buffer, pts: gst::ClockTime) -> Result<(), Error> {
+        self.0.push(buffer, pts)
+    }
+
+    pub fn end(&self) -> Result<(), Error> {
+        self.0.end()
+    }
+
+    pub fn track(&self) -> &PublishedTrack {
+        &self.0.track
+    }
+}
+
+/// Publishes raw media sources, created by `Client::publish`. Encodes with the first
+/// installed codec of `ClientConfig::codecs` unless set with `video_codec`/`audio_codec`.
+/// Each `add_` call builds and links its chain right away; once joined, the new sink pad
+/// triggers renegotiation.
+#[derive(Clone, Debug)]
+pub struct Publisher {
+    pipeline: gst::Pipeline,
+    transports: Transports,
+    video: VideoCodec,
+    audio: AudioCodec,
+}
+
+impl Publisher {
+    pub(crate) fn new(
+        pipeline: &gst::Pipeline,
+        transports: &Transports,
+        codecs: &CodecPreferences,
+    ) -> Publisher {
+        Publisher {
+            pipeline: pipeline.clone(),
+            transports: transports.clone(),
+            video: codecs.publish_video(),
+            audio: codecs.publish_audio(),
+        }
+    }
+
+    pub fn video_codec(mut self, codec: VideoCodec) -> Publisher {
+        self.video = codec;
+        self
+    }
+
+    pub fn audio_codec(mut self, codec: AudioCodec) -> Publisher {
+        self.audio = codec;
+        self
+    }
+
+    /// Publishes the raw video on the `src` pad of `source`, which is added to the
+    /// pipeline unless it already is in a bin.
+    pub fn add_video_source(&self, source: &gst::Element) -> Result<PublishedTrack, Error> {
+        self.add_source(source, TrackKind::Video)
+    }
+
+    /// Publishes the raw audio on the `src` pad of `source`, see `add_video_source`.
+    pub fn add_audio_source(&self, source: &gst::Element) -> Result<PublishedTrack, Error> {
+        self.add_source(source, TrackKind::Audio)
+    }
+
+    /// Publishes raw video pushed through the returned track, `caps` are `video/x-raw`
+    /// with format, size and framerate.
+    pub fn add_raw_video(&self, caps: &gst::Caps) -> Result<LocalVideoTrack, Error> {
+        self.add_raw(caps, TrackKind::Video).map(LocalVideoTrack)
+    }
+
+    /// Publishes raw audio pushed through the returned track, `caps` are `audio/x-raw`
+    /// with format, rate and channels.
+    pub fn add_raw_audio(&self, caps: &gst::Caps) -> Result<LocalAudioTrack, Error> {
+        self.add_raw(caps, TrackKind::Audio).map(LocalAudioTrack)
+    }
+
+    fn add_raw(&self, caps: &gst::Caps, kind: TrackKind) -> Result<RawSource, Error> {
+        let appsrc = gst::ElementFactory::make("appsrc", None)
+            .map_err(|_| Error::ElementNotFound("appsrc".to_string()))?
+            .downcast::<gst_app::AppSrc>()
+            .unwrap();
+        appsrc.set_caps(Some(caps));
+        appsrc
+            .set_property("format", &gst::Format::Time)
+            .and_then(|_| appsrc.set_property("is-live", &true))
+            // push_buffer waits once max-bytes are queued instead of queueing forever
+            .and_then(|_| appsrc.set_property("block", &true))
+            .map_err(|e| Error::pipeline("configuring appsrc", e))?;
+
+        let track = self.add_source(appsrc.upcast_ref(), kind)?;
+        Ok(RawSource {
+            appsrc,
+            track,
+            origin: Mutex::new(None),
+        })
+    }
+
+    /// Publishes a live test pattern or tone.
+    pub fn add_test_source(&self, kind: TrackKind) -> Result<PublishedTrack, Error> {
+        let description = match kind {
+            TrackKind::Video => "videotestsrc is-live=true ! video/x-raw,width=640,height=480",
+            TrackKind::Audio => "audiotestsrc is-live=true wave=ticks",
+        };
+        self.add_source(&self.parse_source(description)?, kind)
+    }
+
+    /// Publishes the default camera, as picked by `autovideosrc`.
+    pub fn add_camera(&self) -> Result<PublishedTrack, Error> {
+        let source = gst::ElementFactory::make("autovideosrc", None)
+            .map_err(|_| Error::ElementNotFound("autovideosrc".to_string()))?;
+        self.add_video_source(&source)
+    }
+
+    /// Publishes every audio and video stream of `uri`: files (`file:///..`), network
+    /// streams and rtsp cameras (`rtsp://..`, through `rtspsrc`). Streams are found while
+    /// the pipeline prerolls, each is sent on the returned channel once it's linked.
+    pub fn add_uri(&self, uri: &str) -> Result<mpsc::UnboundedReceiver<PublishedTrack>, Error> {
+        let decodebin = gst::ElementFactory::make("uridecodebin", None)
+            .map_err(|_| Error::ElementNotFound("uridecodebin".to_string()))?;
+        decodebin
+            .set_property("uri", &uri)
+            .map_err(|e| Error::pipeline("setting uri", e))?;
+
+        let (tx, rx) = mpsc::unbounded();
+        let publisher = self.clone();
+        let uri = uri.to_string();
+        decodebin.connect_pad_added(move |_, pad| {
+            let kind = match pad
+                .get_current_caps()
+                .and_then(|caps| caps.get_structure(0).map(|s| s.get_name().to_string()))
+            {
+                Some(ref name) if name.starts_with("video/x-raw") => TrackKind::Video,
+                Some(ref name) if name.starts_with("audio/x-raw") => TrackKind::Audio,
+                other => {
+                    debug!("{}: ignoring stream {:?}", uri, other);
+                    return;
+                }
+            };
+
+            match publisher.link_chain(pad, kind) {
+                Ok(track) => {
+                    let _ = tx.unbounded_send(track);
+                }
+                Err(err) => warn!("{}: could not publish {:?} stream: {}", uri, kind, err),
+            }
+        });
+
+        self.pipeline
+            .add(&decodebin)
+            .map_err(|e| Error::pipeline("adding uridecodebin", e))?;
+        decodebin
+            .sync_state_with_parent()
+            .map_err(|e| Error::pipeline("starting uridecodebin", e))?;
+
+        Ok(rx)
+    }
+
+    fn parse_source(&self, description: &str) -> Result<gst::Element, Error> {
+        gst::parse_bin_from_description(description, true)
+            .map(|bin| bin.upcast::<gst::Element>())
+            .map_err(|e| Error::pipeline("building source", e))
+    }
+
+    fn add_source(&self, source: &gst::Element, kind: TrackKind) -> Result<PublishedTrack, Error> {
+        let added = source.get_parent().is_none();
+        if added {
+            self.pipeline
+                .add(source)
+                .map_err(|e| Error::pipeline("adding source", e))?;
+        }
+
+        let linked = source
+            .get_static_pad("src")
+            .ok_or_else(|| Error::Pipeline {
+                context: format!("{} has no src pad", source.get_name()),
+                source: None,
+            })
+            .and_then(|src| self.link_chain(&src, kind));
+        let track = match linked {
+            Ok(track) => track,
+            Err(err) => {
+                // a source the caller had in a bin stays theirs to clean up
+                if added {
+                    let _ = self.pipeline.remove(source);
+                }
+                return Err(err);
+            }
+        };
+
+        if added {
+            source
+                .sync_state_with_parent()
+                .map_err(|e| Error::pipeline("starting source", e))?;
+        }
+        Ok(track)
+    }
+
+    /// Links raw media on `src` through a new encode chain to a new publisher sink pad.
+    fn link_chain(&self, src: &gst::Pad, kind: TrackKind) -> Result<PublishedTrack, Error> {
+        let description = match kind {
+            TrackKind::Video => format!(
+                "videoconvert ! videoscale ! queue ! {}",
+                self.video.encode_description()
+            ),
+            TrackKind::Audio => format!(
+                "audioconvert ! audioresample ! queue ! {}",
+                self.audio.encode_description()
+            ),
+        };
+        let chain = gst::parse_bin_from_description(&description, true)
+            .map_err(|e| Error::pipeline("building encode chain", e))?;
+        let encoder = chain
+            .get_by_name("encoder")
+            .ok_or_else(|| Error::ElementNotFound("encoder".to_string()))?;
+
+        self.pipeline
+            .add(&chain)
+            .map_err(|e| Error::pipeline("adding encode chain", e))?;
+
+        let webrtcbin = self.transports.publisher();
+        let pad = match request_sink_pad(&webrtcbin) {
+            Ok(pad) => pad,
+            Err(err) => {
+                let _ = self.pipeline.remove(&chain);
+                return Err(err);
+            }
+        };
+        // parse_bin_from_description ghosts the unlinked pads as `sink` and `src`
+        let sink = chain.get_static_pad("sink").unwrap();
+        let chain_src = chain.get_static_pad("src").unwrap();
+        let linked = src
+            .link(&sink)
+            .map_err(|e| Error::pipeline("linking source to encode chain", e))
+            .and_then(|_| {
+                chain_src
+                    .link(&pad)
+                    .map_err(|e| Error::pipeline("linking encode chain to publisher", e))
+            })
+            .and_then(|_| {
+                chain
+                    .sync_state_with_parent()
+                    .map(|_| ())
+                    .map_err(|e| Error::pipeline("starting encode chain", e))
+            });
+
+        if let Err(err) = linked {
+            // removing the chain unlinks it from the source
+            let _ = chain.set_state(gst::State::Null);
+            webrtcbin.release_request_pad(&pad);
+            let _ = self.pipeline.remove(&chain);
+            return Err(err);
+        }
+
+        info!("publishing {:?} as {:?}", kind, pad.get_name());
+        Ok(PublishedTrack { kind, encoder, pad })
+    }
+}
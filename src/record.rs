@@ -0,0 +1,503 @@
+//! Records remote tracks to files without re-encoding: each track's rtp is depayloaded
+//! and muxed into Matroska, WebM or fragmented MP4, one file per stream or per track.
+//!
+//! Muxers can't take new streams once they started writing, so with per-stream files the
+//! tracks of a stream announced within `RecordConfig::settle` of each other share a file
+//! and later ones get a file of their own. A file is finalized once all of its tracks
+//! were removed or the client leaves.
+
+use super::codec::{depay_description, encoded_media_type};
+use super::executor::Executor;
+use super::tracks::{RemoteTrack, TeeBranch, TrackEvent, TrackKind, TrackTable};
+use super::Error;
+use futures::future::{self, AbortHandle, FutureExt};
+use futures::stream::StreamExt;
+use gst::prelude::*;
+use log::*;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    Matroska,
+    /// Vp8, vp9, av1 and opus only, tracks of other codecs are left out of the file.
+    WebM,
+    /// Fragmented, so a file cut short is still playable up to the last fragment.
+    Mp4,
+}
+
+impl Container {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Container::Matroska => "mkv",
+            Container::WebM => "webm",
+            Container::Mp4 => "mp4",
+        }
+    }
+
+    fn muxer(&self) -> Result<gst::Element, Error> {
+        let name = match self {
+            Container::Matroska => "matroskamux",
+            Container::WebM => "webmmux",
+            Container::Mp4 => "mp4mux",
+        };
+        let muxer = gst::ElementFactory::make(name, None)
+            .map_err(|_| Error::ElementNotFound(name.to_string()))?;
+        if *self == Container::Mp4 {
+            muxer
+                .set_property("fragment-duration", &1000u32)
+                .map_err(|e| Error::pipeline("configuring mp4mux", e))?;
+        }
+        Ok(muxer)
+    }
+}
+
+/// Whether `muxer` has a request pad for the encoded stream of `codec`. Checked against
+/// its pad templates, the codecs a muxer takes vary between GStreamer releases.
+fn muxer_accepts(muxer: &gst::Element, codec: &str) -> bool {
+    let media_type = match encoded_media_type(codec) {
+        Some(media_type) => media_type,
+        None => return false,
+    };
+    muxer.get_pad_template_list().iter().any(|template| {
+        template.get_property_direction() == gst::PadDirection::Sink
+            && template.get_caps().map_or(false, |caps| {
+                caps.iter().any(|s| s.get_name() == media_type)
+            })
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Grouping {
+    /// One file per stream id, which ion uses per participant.
+    Stream,
+    Track,
+}
+
+const STREAM_TEMPLATE: &str = "{stream}-{time}.{ext}";
+const TRACK_TEMPLATE: &str = "{stream}-{track}-{time}.{ext}";
+
+#[derive(Clone, Debug)]
+pub struct RecordConfig {
+    pub directory: PathBuf,
+    /// File name with the placeholders `{stream}`, `{track}`, `{kind}`, `{codec}`,
+    /// `{time}` (unix seconds) and `{ext}`. A counter is appended when the file exists.
+    pub template: String,
+    pub container: Container,
+    pub grouping: Grouping,
+    /// How long to wait for more tracks of a stream before its file is started.
+    pub settle: Duration,
+}
+
+impl RecordConfig {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> RecordConfig {
+        RecordConfig {
+            directory: directory.into(),
+            template: STREAM_TEMPLATE.to_string(),
+            container: Container::Matroska,
+            grouping: Grouping::Stream,
+            settle: Duration::from_secs(1),
+        }
+    }
+
+    pub fn template(mut self, template: &str) -> RecordConfig {
+        self.template = template.to_string();
+        self
+    }
+
+    pub fn container(mut self, container: Container) -> RecordConfig {
+        self.container = container;
+        self
+    }
+
+    /// Per-track files, their default template includes `{track}`.
+    pub fn grouping(mut self, grouping: Grouping) -> RecordConfig {
+        if grouping == Grouping::Track && self.template == STREAM_TEMPLATE {
+            self.template = TRACK_TEMPLATE.to_string();
+        }
+        self.grouping = grouping;
+        self
+    }
+
+    pub fn settle(mut self, settle: Duration) -> RecordConfig {
+        self.settle = settle;
+        self
+    }
+
+    fn path_for(&self, track: &RemoteTrack) -> PathBuf {
+        // ids come from the remote side, keep them from escaping the directory
+        let clean = |s: &str| -> String {
+            s.chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+                    _ => '_',
+                })
+                .collect()
+        };
+        let kind = match track.kind {
+            TrackKind::Audio => "audio",
+            TrackKind::Video => "video",
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let name = self
+            .template
+            .replace("{stream}", &clean(&track.stream_id))
+            .replace("{track}", &clean(&track.track_id))
+            .replace("{kind}", kind)
+            .replace("{codec}", &clean(&track.codec))
+            .replace("{time}", &time.to_string())
+            .replace("{ext}", self.container.extension());
+
+        let path = self.directory.join(&name);
+        if !path.exists() {
+            return path;
+        }
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        (1..)
+            .map(|n| {
+                self.directory
+                    .join(format!("{}-{}.{}", stem, n, self.container.extension()))
+            })
+            .find(|p| !p.exists())
+            .unwrap()
+    }
+}
+
+struct Recording {
+    key: String,
+    path: PathBuf,
+    elements: Vec<gst::Element>,
+    branches: Vec<TeeBranch>,
+    finished: Arc<AtomicBool>,
+}
+
+impl Recording {
+    /// Stops feeding one track and lets the muxer finish its stream.
+    fn end_track(&mut self, track: &RemoteTrack) {
+        let (ended, kept) = self
+            .branches
+            .drain(..)
+            .partition::<Vec<_>, _>(|b| b.is_of(track));
+        self.branches = kept;
+        ended.iter().for_each(|b| b.end());
+    }
+
+    fn end_all(&mut self) {
+        self.branches.drain(..).for_each(|b| b.end());
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Tracks waiting for more of their stream, with the time their file starts.
+    pending: Vec<(String, Instant, Vec<RemoteTrack>)>,
+    recordings: Vec<Recording>,
+    /// Set while `finish` waits for the muxers, no file is started meanwhile.
+    finishing: bool,
+}
+
+pub(crate) struct Recorder {
+    pipeline: gst::Pipeline,
+    state: Arc<Mutex<State>>,
+    task: AbortHandle,
+}
+
+const TICK: Duration = Duration::from_millis(200);
+/// How long `finish` waits for the muxers to write their trailers.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Recorder {
+    pub fn start(
+        pipeline: &gst::Pipeline,
+        tracks: &TrackTable,
+        executor: &Executor,
+        config: RecordConfig,
+    ) -> Result<Recorder, Error> {
+        std::fs::create_dir_all(&config.directory).map_err(|e| Error::Pipeline {
+            context: format!("creating {}", config.directory.display()),
+            source: Some(Box::new(e)),
+        })?;
+
+        let state = Arc::new(Mutex::new(State::default()));
+        let worker = Worker {
+            pipeline: pipeline.clone(),
+            config,
+            state: state.clone(),
+        };
+        let mut events = tracks.subscribe();
+        let (task, handle) = future::abortable(async move {
+            loop {
+                match async_std::future::timeout(TICK, events.next()).await {
+                    Ok(Some(event)) => worker.handle(event),
+                    Ok(None) => break,
+                    Err(_) => {}
+                }
+                worker.start_settled();
+                worker.reap();
+            }
+        });
+        executor.spawn(task.map(|_| ()));
+
+        Ok(Recorder {
+            pipeline: pipeline.clone(),
+            state,
+            task: handle,
+        })
+    }
+
+    /// Ends every recording and waits for the muxers to finalize their files. Tracks
+    /// waiting to settle are dropped, tracks added meanwhile are recorded once it's done.
+    pub async fn finish(&self) {
+        let mut recordings = {
+            let mut state = self.state.lock().unwrap();
+            state.finishing = true;
+            state.pending.clear();
+            state.recordings.drain(..).collect::<Vec<_>>()
+        };
+        for recording in recordings.iter_mut() {
+            recording.end_all();
+        }
+
+        let deadline = Instant::now() + FINISH_TIMEOUT;
+        while recordings
+            .iter()
+            .any(|r| !r.finished.load(Ordering::SeqCst))
+            && Instant::now() < deadline
+        {
+            async_std::task::sleep(Duration::from_millis(50)).await;
+        }
+
+        for recording in recordings {
+            if !recording.finished.load(Ordering::SeqCst) {
+                warn!("{} was not finalized in time", recording.path.display());
+            }
+            teardown(&self.pipeline, recording);
+        }
+        self.state.lock().unwrap().finishing = false;
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Worker {
+    pipeline: gst::Pipeline,
+    config: RecordConfig,
+    state: Arc<Mutex<State>>,
+}
+
+impl Worker {
+    fn key(&self, track: &RemoteTrack) -> String {
+        match self.config.grouping {
+            Grouping::Stream => track.stream_id.clone(),
+            Grouping::Track => format!("{}/{}", track.stream_id, track.track_id),
+        }
+    }
+
+    fn handle(&self, event: TrackEvent) {
+        let mut state = self.state.lock().unwrap();
+        match event {
+            TrackEvent::Added(track) => {
+                let key = self.key(&track);
+                match state.pending.iter_mut().find(|(k, _, _)| *k == key) {
+                    Some((_, _, tracks)) => tracks.push(track),
+                    None => {
+                        let settle = match self.config.grouping {
+                            Grouping::Stream => self.config.settle,
+                            Grouping::Track => Duration::from_secs(0),
+                        };
+                        state
+                            .pending
+                            .push((key, Instant::now() + settle, vec![track]));
+                    }
+                }
+            }
+            TrackEvent::Removed(track) => {
+                for (_, _, tracks) in state.pending.iter_mut() {
+                    tracks.retain(|t| !(t.mline == track.mline && t.track_id == track.track_id));
+                }
+                state.pending.retain(|(_, _, tracks)| !tracks.is_empty());
+
+                let key = self.key(&track);
+                for recording in state.recordings.iter_mut().filter(|r| r.key == key) {
+                    recording.end_track(&track);
+                }
+            }
+        }
+    }
+
+    /// Starts the files of pending tracks whose settle time passed.
+    fn start_settled(&self) {
+        let now = Instant::now();
+        // started under the lock, so `finish` either ends a new file or finds it unstarted
+        let mut state = self.state.lock().unwrap();
+        if state.finishing {
+            return;
+        }
+        let (settled, waiting) = state
+            .pending
+            .drain(..)
+            .partition::<Vec<_>, _>(|(_, at, _)| *at <= now);
+        state.pending = waiting;
+
+        for (key, _, tracks) in settled {
+            match self.record(key, tracks) {
+                Ok(recording) => state.recordings.push(recording),
+                Err(err) => error!("could not start recording: {}", err),
+            }
+        }
+    }
+
+    /// Tears down recordings whose file was finalized.
+    fn reap(&self) {
+        let finished = {
+            let mut state = self.state.lock().unwrap();
+            let (finished, running) = state
+                .recordings
+                .drain(..)
+                .partition::<Vec<_>, _>(|r| r.finished.load(Ordering::SeqCst));
+            state.recordings = running;
+            finished
+        };
+        for recording in finished {
+            info!("finished recording {}", recording.path.display());
+            teardown(&self.pipeline, recording);
+        }
+    }
+
+    fn record(&self, key: String, tracks: Vec<RemoteTrack>) -> Result<Recording, Error> {
+        let path = self.config.path_for(&tracks[0]);
+        let mut recording = Recording {
+            key,
+            path,
+            elements: vec![],
+            branches: vec![],
+            finished: Arc::new(AtomicBool::new(false)),
+        };
+
+        match self.build(&mut recording, &tracks) {
+            Ok(_) => {
+                info!(
+                    "recording {} track(s) to {}",
+                    recording.branches.len(),
+                    recording.path.display()
+                );
+                Ok(recording)
+            }
+            Err(err) => {
+                teardown(&self.pipeline, recording);
+                Err(err)
+            }
+        }
+    }
+
+    fn build(&self, recording: &mut Recording, tracks: &[RemoteTrack]) -> Result<(), Error> {
+        let muxer = self.config.container.muxer()?;
+        let filesink = gst::ElementFactory::make("filesink", None)
+            .map_err(|_| Error::ElementNotFound("filesink".to_string()))?;
+        filesink
+            .set_property("location", &recording.path.to_string_lossy().to_string())
+            .and_then(|_| filesink.set_property("async", &false))
+            .map_err(|e| Error::pipeline("configuring filesink", e))?;
+
+        self.pipeline
+            .add_many(&[&muxer, &filesink])
+            .map_err(|e| Error::pipeline("adding muxer", e))?;
+        recording.elements.push(filesink.clone());
+        recording.elements.push(muxer.clone());
+        muxer
+            .link(&filesink)
+            .map_err(|e| Error::pipeline("linking muxer", e))?;
+
+        let finished = recording.finished.clone();
+        filesink.get_static_pad("sink").unwrap().add_probe(
+            gst::PadProbeType::EVENT_DOWNSTREAM,
+            move |_, info| {
+                if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+                    if event.get_type() == gst::EventType::Eos {
+                        finished.store(true, Ordering::SeqCst);
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            },
+        );
+
+        // start the file at zero, with the same offset for every track to keep them in sync
+        let running = self
+            .pipeline
+            .get_clock()
+            .map(|clock| clock.get_time() - self.pipeline.get_base_time())
+            .and_then(|t| t.nseconds())
+            .unwrap_or(0) as i64;
+
+        let mut sinks = vec![];
+        for track in tracks {
+            let depay = match depay_description(&track.codec) {
+                Some(depay) if muxer_accepts(&muxer, &track.codec) => depay,
+                _ => {
+                    warn!(
+                        "cannot record {} track {} to {:?}",
+                        track.codec, track.track_id, self.config.container
+                    );
+                    continue;
+                }
+            };
+            let branch = gst::parse_bin_from_description(&format!("queue ! {}", depay), true)
+                .map_err(|e| Error::pipeline("building record branch", e))?
+                .upcast::<gst::Element>();
+            self.pipeline
+                .add(&branch)
+                .map_err(|e| Error::pipeline("adding record branch", e))?;
+            recording.elements.push(branch.clone());
+            branch
+                .link(&muxer)
+                .map_err(|e| Error::pipeline("linking record branch", e))?;
+
+            let sink = branch.get_static_pad("sink").unwrap();
+            sink.set_offset(-running);
+            sinks.push((track, sink));
+        }
+        if sinks.is_empty() {
+            return Err(Error::Pipeline {
+                context: "no recordable track".to_string(),
+                source: None,
+            });
+        }
+
+        // downstream first, so nothing is pushed into an element that isn't running yet
+        for element in recording.elements.iter() {
+            element
+                .sync_state_with_parent()
+                .map_err(|e| Error::pipeline("starting recording", e))?;
+        }
+
+        for (track, sink) in sinks {
+            let branch = TeeBranch::request(track, &sink)?;
+            // kept before linking so a failed recording releases the pad on teardown
+            recording.branches.push(branch.clone());
+            branch.link()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn teardown(pipeline: &gst::Pipeline, mut recording: Recording) {
+    recording.branches.drain(..).for_each(|b| b.release());
+    for element in recording.elements.iter() {
+        let _ = element.set_state(gst::State::Null);
+        let _ = pipeline.remove(element);
+    }
+}
@@ -0,0 +1,297 @@
+//! Forwards remote tracks without decoding them: as depayloaded, still encoded pads for
+//! other consumers (an rtsp server, a muxer), or republished through another client's
+//! publisher to relay a session into a different one.
+//!
+//! Keyframe requests travel upstream: a PLI the target sfu sends to the relaying
+//! publisher reaches the source subscriber as a force-key-unit event, which asks the
+//! source sfu for a keyframe.
+
+use super::codec::{depay_description, AudioCodec, VideoCodec};
+use super::executor::Executor;
+use super::publish;
+use super::tracks::{
+    follow_tracks, track_channel, RemoteTrack, TeeBranch, TrackBranch, TrackKind, TrackReceiver,
+    TrackSender, TrackTable,
+};
+use super::{Client, Error, Signal};
+use gst::prelude::*;
+use log::*;
+use std::sync::Arc;
+
+/// A remote track depayloaded to its encoded stream, e.g. `video/x-vp8`.
+#[derive(Clone, Debug)]
+pub struct EncodedTrack {
+    pub track: RemoteTrack,
+    /// Src pad for the caller to link. It gets an end of stream once the track is removed.
+    pub pad: gst::Pad,
+}
+
+/// A remote track republished through the relay target.
+#[derive(Clone, Debug)]
+pub struct RelayedTrack {
+    pub track: RemoteTrack,
+    /// The target publisher sink pad the track is sent from.
+    pub pad: gst::Pad,
+}
+
+struct Branch {
+    tee: TeeBranch,
+    bin: gst::Bin,
+    /// Whether the branch feeds a request pad of the relay target's publisher.
+    relayed: bool,
+}
+
+impl TrackBranch for Branch {
+    fn tee_branch(&self) -> &TeeBranch {
+        &self.tee
+    }
+}
+
+type Filter = Arc<dyn Fn(&RemoteTrack) -> bool + Send + Sync>;
+
+/// Builds a branch per added track and removes it with the track, see `follow_tracks`.
+fn forward<T, F>(
+    pipeline: &gst::Pipeline,
+    tracks: &TrackTable,
+    executor: &Executor,
+    tx: TrackSender<T>,
+    build: F,
+) where
+    T: Send + 'static,
+    F: FnMut(&RemoteTrack) -> Result<Option<(Branch, T)>, Error> + Send + 'static,
+{
+    let events = tracks.subscribe();
+    let pipeline = pipeline.clone();
+
+    executor.spawn(async move {
+        follow_tracks(events, tx, build, |branch| remove(&pipeline, branch)).await;
+        debug!("forwarding stopped");
+    });
+}
+
+/// Adds a `queue ! {description}` bin fed by the track, with its src pad unlinked.
+fn add_branch(
+    pipeline: &gst::Pipeline,
+    track: &RemoteTrack,
+    description: &str,
+) -> Result<Branch, Error> {
+    let bin = gst::parse_bin_from_description(&format!("queue ! {}", description), true)
+        .map_err(|e| Error::pipeline("building forward branch", e))?;
+    pipeline
+        .add(&bin)
+        .map_err(|e| Error::pipeline("adding forward branch", e))?;
+
+    match TeeBranch::request(track, &bin.get_static_pad("sink").unwrap()) {
+        Ok(tee) => Ok(Branch {
+            tee,
+            bin,
+            relayed: false,
+        }),
+        Err(err) => {
+            let _ = pipeline.remove(&bin);
+            Err(err)
+        }
+    }
+}
+
+/// Links the track to the branch and starts it.
+fn start(pipeline: &gst::Pipeline, branch: Branch) -> Result<Branch, Error> {
+    let started = branch.tee.link().and_then(|_| {
+        branch
+            .bin
+            .sync_state_with_parent()
+            .map_err(|e| Error::pipeline("starting forward branch", e))
+    });
+
+    match started {
+        Ok(_) => Ok(branch),
+        Err(err) => {
+            remove(pipeline, branch);
+            Err(err)
+        }
+    }
+}
+
+fn remove(pipeline: &gst::Pipeline, branch: Branch) {
+    branch.tee.release();
+
+    if let Some(src) = branch.bin.get_static_pad("src") {
+        if let Some(peer) = src.get_peer() {
+            let _ = src.unlink(&peer);
+            // the target's publisher may have been rebuilt since, the pad tells whose it is
+            match peer.get_parent_element() {
+                Some(publisher) if branch.relayed => publisher.release_request_pad(&peer),
+                _ => {
+                    peer.send_event(gst::event::Eos::new());
+                }
+            }
+        }
+    }
+
+    let _ = branch.bin.set_state(gst::State::Null);
+    let _ = pipeline.remove(&branch.bin);
+}
+
+/// Depayloads every remote track added from now on, see `Client::encoded_tracks`.
+pub(crate) fn encoded_tracks(
+    pipeline: &gst::Pipeline,
+    tracks: &TrackTable,
+    executor: &Executor,
+) -> TrackReceiver<EncodedTrack> {
+    let (tx, rx) = track_channel();
+    let owner = pipeline.clone();
+
+    forward(pipeline, tracks, executor, tx, move |track| {
+        let depay = depay_description(&track.codec).ok_or_else(|| Error::Pipeline {
+            context: format!("no depayloader for {}", track.codec),
+            source: None,
+        })?;
+        let branch = add_branch(&owner, track, depay)?;
+        if let Err(err) = branch.bin.sync_state_with_parent() {
+            remove(&owner, branch);
+            return Err(Error::pipeline("starting forward branch", err));
+        }
+
+        // feeding the branch before the caller linked it would fail it as not-linked
+        let pad = branch.bin.get_static_pad("src").unwrap();
+        let tee = branch.tee.clone();
+        pad.connect_linked(move |_, _| {
+            if let Err(err) = tee.link() {
+                warn!("could not link encoded track: {}", err);
+            }
+        });
+
+        Ok(Some((
+            branch,
+            EncodedTrack {
+                track: track.clone(),
+                pad,
+            },
+        )))
+    });
+
+    rx
+}
+
+/// Republishes the remote tracks of one client through the publisher of another, created
+/// by `Client::relay`. Tracks are depayloaded and payloaded again, without decoding, so
+/// the target negotiates fresh ssrcs and payload types for them.
+#[derive(Clone)]
+pub struct Relay {
+    pipeline: gst::Pipeline,
+    tracks: TrackTable,
+    executor: Executor,
+    filter: Option<Filter>,
+}
+
+impl Relay {
+    pub(crate) fn new(pipeline: &gst::Pipeline, tracks: &TrackTable, executor: &Executor) -> Relay {
+        Relay {
+            pipeline: pipeline.clone(),
+            tracks: tracks.clone(),
+            executor: executor.clone(),
+            filter: None,
+        }
+    }
+
+    /// Only relays the tracks `f` returns true for.
+    pub fn filter<F>(mut self, f: F) -> Relay
+    where
+        F: Fn(&RemoteTrack) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Arc::new(f));
+        self
+    }
+
+    /// Starts relaying the tracks added from now on to `target`, which must be in the
+    /// same pipeline. Once the target joined, each new track triggers its renegotiation.
+    /// Relaying stops, and the target's pads are released, once the receiver is dropped.
+    pub fn to<T: Signal + Send + Sync + 'static>(
+        self,
+        target: &Client<T>,
+    ) -> Result<TrackReceiver<RelayedTrack>, Error> {
+        if target.pipeline() != &self.pipeline {
+            return Err(Error::Pipeline {
+                context: "relay target is in another pipeline".to_string(),
+                source: None,
+            });
+        }
+
+        let (tx, rx) = track_channel();
+        let transports = target.transports.clone();
+        let pipeline = self.pipeline.clone();
+        let filter = self.filter.clone();
+
+        forward(
+            &self.pipeline,
+            &self.tracks,
+            &self.executor,
+            tx,
+            move |track| {
+                if !filter.as_ref().map_or(true, |f| f(track)) {
+                    return Ok(None);
+                }
+                relay(&pipeline, &transports.publisher(), track).map(Some)
+            },
+        );
+
+        Ok(rx)
+    }
+}
+
+fn relay(
+    pipeline: &gst::Pipeline,
+    publisher: &gst::Element,
+    track: &RemoteTrack,
+) -> Result<(Branch, RelayedTrack), Error> {
+    let pay = match track.kind {
+        TrackKind::Video => {
+            VideoCodec::from_encoding_name(&track.codec).map(|c| c.pay_description())
+        }
+        TrackKind::Audio => {
+            AudioCodec::from_encoding_name(&track.codec).map(|c| c.pay_description())
+        }
+    };
+    let description = depay_description(&track.codec)
+        .and_then(|depay| Some(format!("{} ! {}", depay, pay?)))
+        .ok_or_else(|| Error::Pipeline {
+            context: format!("cannot relay {}", track.codec),
+            source: None,
+        })?;
+
+    let mut branch = add_branch(pipeline, track, &description)?;
+    let pad = match publish::request_sink_pad(publisher) {
+        Ok(pad) => pad,
+        Err(err) => {
+            remove(pipeline, branch);
+            return Err(err);
+        }
+    };
+    branch.relayed = true;
+    if let Err(err) = branch
+        .bin
+        .get_static_pad("src")
+        .unwrap()
+        .link(&pad)
+        .map_err(|e| Error::pipeline("linking relay to publisher", e))
+    {
+        publisher.release_request_pad(&pad);
+        remove(pipeline, branch);
+        return Err(err);
+    }
+
+    let branch = start(pipeline, branch)?;
+    info!(
+        "relaying {:?} track {} as {:?}",
+        track.kind,
+        track.track_id,
+        pad.get_name()
+    );
+    Ok((
+        branch,
+        RelayedTrack {
+            track: track.clone(),
+            pad,
+        },
+    ))
+}
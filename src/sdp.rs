@@ -0,0 +1,242 @@
+//! Minimal line based sdp model for the munging and inspection webrtcbin doesn't do itself.
+//! Lines are kept verbatim so anything not touched round-trips unchanged.
+
+use std::fmt;
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Sdp {
+    pub session: Vec<String>,
+    pub media: Vec<Media>,
+}
+
+/// A media section, `lines[0]` is the `m=` line.
+#[derive(Clone, Debug)]
+pub(crate) struct Media {
+    pub lines: Vec<String>,
+}
+
+impl Sdp {
+    pub fn parse(text: &str) -> Sdp {
+        let mut sdp = Sdp::default();
+
+        for line in text.lines().map(str::trim_end).filter(|l| !l.is_empty()) {
+            if line.starts_with("m=") {
+                sdp.media.push(Media {
+                    lines: vec![line.to_string()],
+                });
+            } else if let Some(media) = sdp.media.last_mut() {
+                media.lines.push(line.to_string());
+            } else {
+                sdp.session.push(line.to_string());
+            }
+        }
+
+        sdp
+    }
+
+    /// Rewrites the mids of the `a=group:BUNDLE` line.
+    pub fn retain_bundle<F: Fn(&str) -> bool>(&mut self, keep: F) {
+        for line in self.session.iter_mut() {
+            if let Some(mids) = line.strip_prefix("a=group:BUNDLE") {
+                let mids: Vec<&str> = mids.split_whitespace().filter(|m| keep(m)).collect();
+                *line = format!("a=group:BUNDLE {}", mids.join(" "));
+            }
+        }
+    }
+
+    /// The ice username fragment, from the session level or the first media section that
+    /// has one. With bundle every section shares the same credentials.
+    pub fn ice_ufrag(&self) -> Option<&str> {
+        self.session
+            .iter()
+            .find_map(|line| line.strip_prefix("a=ice-ufrag:"))
+            .or_else(|| self.media.iter().find_map(|m| m.attribute("ice-ufrag")))
+    }
+
+    pub fn add_to_bundle(&mut self, mid: &str) {
+        for line in self.session.iter_mut() {
+            if line.starts_with("a=group:BUNDLE") {
+                line.push(' ');
+                line.push_str(mid);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Sdp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in self
+            .session
+            .iter()
+            .chain(self.media.iter().flat_map(|m| m.lines.iter()))
+        {
+            write!(f, "{}\r\n", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Media {
+    fn m_line(&self) -> Vec<&str> {
+        self.lines[0][2..].split_whitespace().collect()
+    }
+
+    /// `audio`, `video` or `application`.
+    pub fn kind(&self) -> &str {
+        self.lines[0][2..].split_whitespace().next().unwrap_or("")
+    }
+
+    /// A port of 0 marks a rejected or removed section.
+    pub fn is_disabled(&self) -> bool {
+        self.m_line().get(1) == Some(&"0")
+    }
+
+    /// Payload types in preference order.
+    pub fn formats(&self) -> Vec<String> {
+        self.m_line()
+            .iter()
+            .skip(3)
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    pub fn set_formats(&mut self, formats: &[String]) {
+        let head: Vec<String> = self
+            .m_line()
+            .iter()
+            .take(3)
+            .map(|s| s.to_string())
+            .collect();
+        self.lines[0] = format!("m={} {}", head.join(" "), formats.join(" "));
+    }
+
+    /// Keeps only the payload types in `formats`, in that order, along with their
+    /// `rtpmap`, `fmtp` and `rtcp-fb` lines.
+    pub fn retain_formats(&mut self, formats: &[String]) {
+        let dropped: Vec<String> = self
+            .formats()
+            .into_iter()
+            .filter(|pt| !formats.contains(pt))
+            .collect();
+        self.lines.retain(|line| {
+            !["a=rtpmap:", "a=fmtp:", "a=rtcp-fb:"].iter().any(|attr| {
+                line.strip_prefix(attr)
+                    .and_then(|rest| rest.split_whitespace().next())
+                    .map_or(false, |pt| dropped.iter().any(|d| d == pt))
+            })
+        });
+        self.set_formats(formats);
+    }
+
+    /// Values of every `a=name:value` (or bare `a=name`) attribute.
+    pub fn attributes<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.lines.iter().filter_map(move |line| {
+            let attr = line.strip_prefix("a=")?;
+            if attr == name {
+                Some("")
+            } else {
+                attr.strip_prefix(name)?.strip_prefix(':')
+            }
+        })
+    }
+
+    pub fn attribute<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        self.attributes(name).next()
+    }
+
+    pub fn mid(&self) -> Option<&str> {
+        self.attribute("mid")
+    }
+
+    pub fn direction(&self) -> Option<&str> {
+        ["sendrecv", "sendonly", "recvonly", "inactive"]
+            .iter()
+            .find(|d| self.attribute(d).is_some())
+            .copied()
+    }
+
+    /// Encoding name of payload type `pt` from its `a=rtpmap`, e.g. `VP8`.
+    pub fn codec(&self, pt: &str) -> Option<&str> {
+        self.attributes("rtpmap").find_map(|rtpmap| {
+            let mut parts = rtpmap.splitn(2, ' ');
+            if parts.next()? != pt {
+                return None;
+            }
+            parts.next()?.split('/').next()
+        })
+    }
+
+    /// Encoding name of the preferred payload type.
+    pub fn first_codec(&self) -> Option<&str> {
+        let formats = self.m_line();
+        let pt = formats.get(3)?;
+        self.codec(pt)
+    }
+
+    pub fn remove_attributes(&mut self, name: &str) {
+        let bare = format!("a={}", name);
+        let prefixed = format!("a={}:", name);
+        self.lines
+            .retain(|line| line != &bare && !line.starts_with(&prefixed));
+    }
+
+    pub fn push_attribute(&mut self, attr: &str) {
+        self.lines.push(format!("a={}", attr));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video() -> Media {
+        Sdp::parse(
+            "m=video 9 UDP/TLS/RTP/SAVPF 96 97 98\r\n\
+             a=mid:0\r\n\
+             a=rtpmap:96 VP8/90000\r\n\
+             a=rtcp-fb:96 nack\r\n\
+             a=rtpmap:97 rtx/90000\r\n\
+             a=fmtp:97 apt=96\r\n\
+             a=rtpmap:98 H264/90000\r\n\
+             a=rtcp-fb:98 nack\r\n\
+             a=fmtp:98 packetization-mode=1\r\n\
+             a=ssrc:1 cname:test\r\n",
+        )
+        .media
+        .remove(0)
+    }
+
+    fn formats(pts: &[&str]) -> Vec<String> {
+        pts.iter().map(|pt| pt.to_string()).collect()
+    }
+
+    #[test]
+    fn retain_formats_drops_lines_of_removed_formats() {
+        let mut media = video();
+        media.retain_formats(&formats(&["96", "97"]));
+
+        assert_eq!(media.lines[0], "m=video 9 UDP/TLS/RTP/SAVPF 96 97");
+        assert!(media.codec("98").is_none());
+        assert_eq!(
+            media.attributes("rtcp-fb").collect::<Vec<_>>(),
+            vec!["96 nack"]
+        );
+        assert_eq!(
+            media.attributes("fmtp").collect::<Vec<_>>(),
+            vec!["97 apt=96"]
+        );
+        // unrelated lines stay
+        assert_eq!(media.mid(), Some("0"));
+        assert_eq!(media.attribute("ssrc"), Some("1 cname:test"));
+    }
+
+    #[test]
+    fn retain_formats_reorders() {
+        let mut media = video();
+        media.retain_formats(&formats(&["98", "96", "97"]));
+
+        assert_eq!(media.formats(), formats(&["98", "96", "97"]));
+        assert_eq!(media.first_codec(), Some("H264"));
+        assert_eq!(media.lines.len(), video().lines.len());
+    }
+}
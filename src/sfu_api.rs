@@ -0,0 +1,105 @@
+use super::{DataChannel, Error};
+use log::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Label of the data channel ion-sfu opens on the subscriber transport for api calls.
+pub const API_CHANNEL: &str = "ion-sfu";
+
+/// Simulcast layer to receive for a remote stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layer {
+    High,
+    Medium,
+    Low,
+    /// Stop forwarding video for the stream.
+    None,
+}
+
+#[derive(Serialize, Debug)]
+struct SetRemoteMedia<'a> {
+    #[serde(rename = "streamId")]
+    stream_id: &'a str,
+    video: Layer,
+    audio: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct MediaPreference {
+    video: Layer,
+    audio: bool,
+}
+
+impl Default for MediaPreference {
+    fn default() -> MediaPreference {
+        MediaPreference {
+            video: Layer::High,
+            audio: true,
+        }
+    }
+}
+
+/// Typed access to ion-sfu's api data channel.
+///
+/// The channel only exists once the sfu has opened it on the subscriber transport,
+/// which requires the publisher to negotiate sctp (see `ClientConfig::sfu_api`).
+/// Until then every call fails with `Error::NotConnected`.
+#[derive(Clone, Default)]
+pub struct SfuApi {
+    channel: Arc<Mutex<Option<DataChannel>>>,
+    // ion-sfu expects the full media selection in every message
+    preferences: Arc<Mutex<HashMap<String, MediaPreference>>>,
+}
+
+impl SfuApi {
+    pub(crate) fn bind(&self, mut channel: DataChannel) {
+        debug!("sfu api channel bound");
+        // ion-sfu pushes audio level updates we have no use for, drop them instead of buffering
+        let _ = channel.messages();
+        *self.channel.lock().unwrap() = Some(channel);
+    }
+
+    /// Forgets the channel of a subscriber that was torn down, calls fail with
+    /// `Error::NotConnected` until the sfu opens it again.
+    pub(crate) fn unbind(&self) {
+        if self.channel.lock().unwrap().take().is_some() {
+            debug!("sfu api channel unbound");
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.channel.lock().unwrap().is_some()
+    }
+
+    /// Asks the sfu to forward `layer` of the simulcast video of `stream_id`.
+    pub fn set_preferred_layer(&self, stream_id: &str, layer: Layer) -> Result<(), Error> {
+        self.update(stream_id, |pref| pref.video = layer)
+    }
+
+    /// Stops (or resumes) forwarding the audio of `stream_id`.
+    pub fn mute_audio(&self, stream_id: &str, mute: bool) -> Result<(), Error> {
+        self.update(stream_id, |pref| pref.audio = !mute)
+    }
+
+    fn update<F: FnOnce(&mut MediaPreference)>(&self, stream_id: &str, f: F) -> Result<(), Error> {
+        let channel = self.channel.lock().unwrap();
+        let channel = channel.as_ref().ok_or(Error::NotConnected)?;
+
+        let mut preferences = self.preferences.lock().unwrap();
+        let mut pref = preferences.get(stream_id).cloned().unwrap_or_default();
+        f(&mut pref);
+
+        let msg = serde_json::to_string(&SetRemoteMedia {
+            stream_id,
+            video: pref.video,
+            audio: pref.audio,
+        })?;
+        trace!("sfu api: {}", msg);
+        channel.send_string(&msg)?;
+
+        preferences.insert(stream_id.to_string(), pref);
+        Ok(())
+    }
+}
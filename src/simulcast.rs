@@ -0,0 +1,358 @@
+//! Simulcast publishing.
+//!
+//! webrtcbin has no notion of rid based simulcast, so each encoding is linked to its own
+//! publisher sink pad and ends up in its own m-line. Before the offer goes to the sfu those
+//! m-lines are folded into the first one with `a=rid`/`a=simulcast`, and the answer is
+//! expanded back to the original m-lines before it is handed to webrtcbin.
+
+use super::sdp::{Media, Sdp};
+use super::Error;
+use gst::prelude::*;
+use log::*;
+use std::sync::{Arc, Mutex};
+
+pub const RID_EXTENSION_URI: &str = "urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id";
+
+/// Extension id announced for `RID_EXTENSION_URI`, packets of each layer carry their rid
+/// in it for the sfu to tell layers apart, see `tag_rid`.
+pub const RID_EXTENSION_ID: u8 = 10;
+
+/// Simulcast encodings, named with the rids ion-sfu understands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SimulcastLayer {
+    High,
+    Medium,
+    Low,
+}
+
+impl SimulcastLayer {
+    pub fn rid(&self) -> &'static str {
+        match self {
+            SimulcastLayer::High => "f",
+            SimulcastLayer::Medium => "h",
+            SimulcastLayer::Low => "q",
+        }
+    }
+}
+
+fn add_rid(buffer: &mut gst::BufferRef, rid: &str) {
+    let tagged = gst_rtp::RTPBuffer::from_buffer_writable(buffer)
+        .and_then(|mut rtp| rtp.add_extension_onebyte_header(RID_EXTENSION_ID, rid.as_bytes()));
+    if let Err(err) = tagged {
+        trace!("could not tag packet with rid {}: {}", rid, err);
+    }
+}
+
+/// Adds the rid of `layer` to every rtp packet leaving the payloader's `src` pad.
+/// Payloaders only add header extensions themselves from GStreamer 1.20 on, tagging the
+/// packets here works with any release and survives the publisher being replaced.
+pub(crate) fn tag_rid(payloader: &gst::Element, layer: SimulcastLayer) -> Result<(), Error> {
+    let pad = payloader
+        .get_static_pad("src")
+        .ok_or_else(|| Error::Simulcast(format!("{} has no src pad", payloader.get_name())))?;
+
+    let rid = layer.rid();
+    pad.add_probe(
+        gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+        move |_, info| {
+            match &mut info.data {
+                Some(gst::PadProbeData::Buffer(buffer)) => add_rid(buffer.make_mut(), rid),
+                Some(gst::PadProbeData::BufferList(list)) => {
+                    let list = list.make_mut();
+                    for idx in 0..list.len() as u32 {
+                        if let Some(buffer) = list.get_writable(idx) {
+                            add_rid(buffer, rid);
+                        }
+                    }
+                }
+                _ => {}
+            }
+            gst::PadProbeReturn::Ok
+        },
+    );
+    Ok(())
+}
+
+/// Publisher m-lines carrying the encodings of one simulcast track, highest layer first.
+#[derive(Clone, Debug)]
+pub(crate) struct SimulcastGroup {
+    pub layers: Vec<(SimulcastLayer, u32)>,
+}
+
+/// What a munged offer removed, needed to expand the matching answer.
+#[derive(Clone, Debug)]
+pub(crate) struct Folded {
+    /// Index of the simulcast m-line in the munged offer.
+    primary: usize,
+    /// Original index and mid of every folded m-line, ascending.
+    removed: Vec<(usize, String)>,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Simulcast {
+    group: Arc<Mutex<Option<SimulcastGroup>>>,
+}
+
+impl Simulcast {
+    pub fn is_active(&self) -> bool {
+        self.group.lock().unwrap().is_some()
+    }
+
+    pub fn set_group(&self, group: SimulcastGroup) -> Result<(), Error> {
+        let mut current = self.group.lock().unwrap();
+        if current.is_some() {
+            return Err(Error::Simulcast(
+                "only one simulcast track can be published".to_string(),
+            ));
+        }
+        *current = Some(group);
+        Ok(())
+    }
+
+    /// Folds the simulcast m-lines of a webrtcbin offer into a single rid based m-line.
+    /// The returned `Folded` expands the answer to this offer, `None` if nothing was folded.
+    pub fn munge_offer(&self, offer: &str) -> Result<(String, Option<Folded>), Error> {
+        let group = match &*self.group.lock().unwrap() {
+            Some(group) => group.clone(),
+            None => return Ok((offer.to_string(), None)),
+        };
+
+        let mut sdp = Sdp::parse(offer);
+        let indices: Vec<usize> = group.layers.iter().map(|(_, idx)| *idx as usize).collect();
+        if indices.iter().any(|idx| *idx >= sdp.media.len()) {
+            // the branches were linked but are not negotiated yet
+            debug!("simulcast m-lines not in offer yet, sending as is");
+            return Ok((offer.to_string(), None));
+        }
+
+        let primary = indices[0];
+        let mut removed = vec![];
+        for idx in indices.iter().skip(1) {
+            let mid = sdp.media[*idx]
+                .mid()
+                .ok_or_else(|| Error::Simulcast(format!("m-line {} has no mid", idx)))?
+                .to_string();
+            removed.push((*idx, mid));
+        }
+
+        let media = &mut sdp.media[primary];
+        // layers are identified by rid, not by ssrc
+        media.remove_attributes("ssrc");
+        media.remove_attributes("ssrc-group");
+        if !media
+            .attributes("extmap")
+            .any(|ext| ext.ends_with(RID_EXTENSION_URI))
+        {
+            media.push_attribute(&format!(
+                "extmap:{} {}",
+                RID_EXTENSION_ID, RID_EXTENSION_URI
+            ));
+        }
+        for (layer, _) in group.layers.iter() {
+            media.push_attribute(&format!("rid:{} send", layer.rid()));
+        }
+        let rids: Vec<&str> = group.layers.iter().map(|(l, _)| l.rid()).collect();
+        media.push_attribute(&format!("simulcast:send {}", rids.join(";")));
+
+        let removed_mids: Vec<String> = removed.iter().map(|(_, mid)| mid.clone()).collect();
+        sdp.retain_bundle(|mid| !removed_mids.iter().any(|m| m == mid));
+
+        removed.sort_by_key(|(idx, _)| *idx);
+        for (idx, _) in removed.iter().rev() {
+            sdp.media.remove(*idx);
+        }
+        let primary = primary - removed.iter().filter(|(idx, _)| *idx < primary).count();
+
+        Ok((sdp.to_string(), Some(Folded { primary, removed })))
+    }
+
+    /// Checks that the sfu accepted the simulcast m-line and expands the answer back
+    /// into the m-lines webrtcbin offered, as folded by `munge_offer`.
+    pub fn restore_answer(&self, answer: &str, folded: Folded) -> Result<String, Error> {
+        let group = self.group.lock().unwrap().clone();
+        let group = match group {
+            Some(group) => group,
+            None => return Ok(answer.to_string()),
+        };
+
+        let mut sdp = Sdp::parse(answer);
+        let primary = sdp.media.get_mut(folded.primary).ok_or_else(|| {
+            Error::Simulcast("answer is missing the simulcast m-line".to_string())
+        })?;
+
+        if primary.is_disabled() {
+            return Err(Error::Simulcast(
+                "sfu rejected the simulcast m-line".to_string(),
+            ));
+        }
+        let accepted = primary
+            .attribute("simulcast")
+            .and_then(|s| s.strip_prefix("recv "))
+            .map(|rids| rids.split(';').map(str::to_string).collect::<Vec<_>>())
+            .unwrap_or_default();
+        for (layer, _) in group.layers.iter() {
+            if !accepted.iter().any(|rid| rid == layer.rid()) {
+                return Err(Error::Simulcast(format!(
+                    "sfu did not accept layer {}",
+                    layer.rid()
+                )));
+            }
+        }
+
+        primary.remove_attributes("rid");
+        primary.remove_attributes("simulcast");
+        let template = primary.clone();
+
+        for (idx, mid) in folded.removed {
+            let mut media = Media {
+                lines: template
+                    .lines
+                    .iter()
+                    .filter(|l| !l.starts_with("a=mid:"))
+                    .cloned()
+                    .collect(),
+            };
+            media.push_attribute(&format!("mid:{}", mid));
+            sdp.add_to_bundle(&mid);
+            sdp.media.insert(idx.min(sdp.media.len()), media);
+        }
+
+        Ok(sdp.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(mid: &str, ssrc: u32, direction: &str) -> String {
+        format!(
+            "m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+             c=IN IP4 0.0.0.0\r\n\
+             a=mid:{}\r\n\
+             a={}\r\n\
+             a=rtpmap:96 VP8/90000\r\n\
+             a=ssrc:{} cname:test\r\n",
+            mid, direction, ssrc
+        )
+    }
+
+    fn session(mids: &str) -> String {
+        format!(
+            "v=0\r\no=- 1 0 IN IP4 0.0.0.0\r\ns=-\r\nt=0 0\r\na=group:BUNDLE {}\r\n",
+            mids
+        )
+    }
+
+    fn three_layers() -> Simulcast {
+        let simulcast = Simulcast::default();
+        simulcast
+            .set_group(SimulcastGroup {
+                layers: vec![
+                    (SimulcastLayer::High, 0),
+                    (SimulcastLayer::Medium, 1),
+                    (SimulcastLayer::Low, 2),
+                ],
+            })
+            .unwrap();
+        simulcast
+    }
+
+    fn offer() -> String {
+        session("video0 video1 video2")
+            + &video("video0", 1, "sendonly")
+            + &video("video1", 2, "sendonly")
+            + &video("video2", 3, "sendonly")
+    }
+
+    fn answer(rids: &str) -> String {
+        session("video0")
+            + &video("video0", 4, "recvonly")
+            + "a=rid:f recv\r\na=rid:h recv\r\na=rid:q recv\r\n"
+            + &format!("a=simulcast:recv {}\r\n", rids)
+    }
+
+    #[test]
+    fn folds_layers_into_one_mline() {
+        let (munged, folded) = three_layers().munge_offer(&offer()).unwrap();
+        let folded = folded.unwrap();
+        let sdp = Sdp::parse(&munged);
+
+        assert_eq!(sdp.media.len(), 1);
+        assert_eq!(folded.primary, 0);
+        assert_eq!(
+            folded.removed,
+            vec![(1, "video1".to_string()), (2, "video2".to_string())]
+        );
+        assert!(sdp.session.contains(&"a=group:BUNDLE video0".to_string()));
+
+        let media = &sdp.media[0];
+        assert_eq!(media.mid(), Some("video0"));
+        assert_eq!(media.attribute("ssrc"), None);
+        assert_eq!(
+            media.attributes("rid").collect::<Vec<_>>(),
+            vec!["f send", "h send", "q send"]
+        );
+        assert_eq!(media.attribute("simulcast"), Some("send f;h;q"));
+        assert!(media
+            .attributes("extmap")
+            .any(|ext| ext == format!("{} {}", RID_EXTENSION_ID, RID_EXTENSION_URI)));
+    }
+
+    #[test]
+    fn answer_expands_to_offered_mlines() {
+        let simulcast = three_layers();
+        let (_, folded) = simulcast.munge_offer(&offer()).unwrap();
+        let restored = simulcast
+            .restore_answer(&answer("f;h;q"), folded.unwrap())
+            .unwrap();
+        let sdp = Sdp::parse(&restored);
+
+        assert_eq!(sdp.media.len(), 3);
+        let mids: Vec<_> = sdp.media.iter().map(|m| m.mid().unwrap()).collect();
+        assert_eq!(mids, vec!["video0", "video1", "video2"]);
+        assert!(sdp
+            .session
+            .contains(&"a=group:BUNDLE video0 video1 video2".to_string()));
+        for media in sdp.media.iter() {
+            assert_eq!(media.attribute("rid"), None);
+            assert_eq!(media.attribute("simulcast"), None);
+            assert_eq!(media.direction(), Some("recvonly"));
+            assert_eq!(media.first_codec(), Some("VP8"));
+        }
+    }
+
+    #[test]
+    fn folds_of_overlapping_negotiations_stay_apart() {
+        let simulcast = three_layers();
+        let (_, first) = simulcast.munge_offer(&offer()).unwrap();
+        let (_, second) = simulcast.munge_offer(&offer()).unwrap();
+
+        let restore = |folded: Option<Folded>| {
+            let restored = simulcast
+                .restore_answer(&answer("f;h;q"), folded.unwrap())
+                .unwrap();
+            Sdp::parse(&restored).media.len()
+        };
+        assert_eq!(restore(second), 3);
+        assert_eq!(restore(first), 3);
+    }
+
+    #[test]
+    fn missing_layer_is_rejected() {
+        let simulcast = three_layers();
+        let (_, folded) = simulcast.munge_offer(&offer()).unwrap();
+        assert!(matches!(
+            simulcast.restore_answer(&answer("f;h"), folded.unwrap()),
+            Err(Error::Simulcast(_))
+        ));
+    }
+
+    #[test]
+    fn offer_without_layers_is_left_alone() {
+        let offer = session("video0") + &video("video0", 1, "sendonly");
+        let (munged, folded) = three_layers().munge_offer(&offer).unwrap();
+        assert!(folded.is_none());
+        assert_eq!(munged, offer);
+    }
+}
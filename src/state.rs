@@ -0,0 +1,380 @@
+//! Connection state of the publisher and subscriber transports, tracked through
+//! webrtcbin's `notify::` signals for its state properties, and those of the dtls
+//! transports of its transceivers.
+
+use super::negotiation;
+use futures::channel::mpsc;
+use gst::prelude::*;
+use gst_webrtc::{
+    WebRTCDTLSTransport, WebRTCDTLSTransportState, WebRTCICEConnectionState,
+    WebRTCICEGatheringState, WebRTCPeerConnectionState, WebRTCRTPTransceiver,
+};
+use log::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Publisher,
+    Subscriber,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IceConnectionState {
+    New,
+    Checking,
+    Connected,
+    Completed,
+    Failed,
+    Disconnected,
+    Closed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerConnectionState {
+    New,
+    Connecting,
+    Connected,
+    Disconnected,
+    Failed,
+    Closed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IceGatheringState {
+    New,
+    Gathering,
+    Complete,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DtlsTransportState {
+    New,
+    Closed,
+    Failed,
+    Connecting,
+    Connected,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransportState {
+    pub ice_connection: IceConnectionState,
+    /// Overall peer connection state, combining ice and dtls.
+    pub connection: PeerConnectionState,
+    pub ice_gathering: IceGatheringState,
+    /// State of the dtls transports of the negotiated transceivers, failed if any of them
+    /// failed and connected once all are. Stays `New` on GStreamer before 1.20, whose
+    /// transceivers don't expose their transport.
+    pub dtls: DtlsTransportState,
+}
+
+impl Default for TransportState {
+    fn default() -> TransportState {
+        TransportState {
+            ice_connection: IceConnectionState::New,
+            connection: PeerConnectionState::New,
+            ice_gathering: IceGatheringState::New,
+            dtls: DtlsTransportState::New,
+        }
+    }
+}
+
+impl TransportState {
+    /// Whether media stopped flowing and the transport needs an ice restart or a rejoin.
+    pub fn is_down(&self) -> bool {
+        matches!(
+            self.ice_connection,
+            IceConnectionState::Failed | IceConnectionState::Disconnected
+        ) || matches!(
+            self.connection,
+            PeerConnectionState::Failed | PeerConnectionState::Disconnected
+        ) || self.dtls == DtlsTransportState::Failed
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StateChange {
+    pub transport: Transport,
+    pub state: TransportState,
+}
+
+fn ice_connection(state: WebRTCICEConnectionState) -> Option<IceConnectionState> {
+    Some(match state {
+        WebRTCICEConnectionState::New => IceConnectionState::New,
+        WebRTCICEConnectionState::Checking => IceConnectionState::Checking,
+        WebRTCICEConnectionState::Connected => IceConnectionState::Connected,
+        WebRTCICEConnectionState::Completed => IceConnectionState::Completed,
+        WebRTCICEConnectionState::Failed => IceConnectionState::Failed,
+        WebRTCICEConnectionState::Disconnected => IceConnectionState::Disconnected,
+        WebRTCICEConnectionState::Closed => IceConnectionState::Closed,
+        _ => return None,
+    })
+}
+
+fn peer_connection(state: WebRTCPeerConnectionState) -> Option<PeerConnectionState> {
+    Some(match state {
+        WebRTCPeerConnectionState::New => PeerConnectionState::New,
+        WebRTCPeerConnectionState::Connecting => PeerConnectionState::Connecting,
+        WebRTCPeerConnectionState::Connected => PeerConnectionState::Connected,
+        WebRTCPeerConnectionState::Disconnected => PeerConnectionState::Disconnected,
+        WebRTCPeerConnectionState::Failed => PeerConnectionState::Failed,
+        WebRTCPeerConnectionState::Closed => PeerConnectionState::Closed,
+        _ => return None,
+    })
+}
+
+fn ice_gathering(state: WebRTCICEGatheringState) -> Option<IceGatheringState> {
+    Some(match state {
+        WebRTCICEGatheringState::New => IceGatheringState::New,
+        WebRTCICEGatheringState::Gathering => IceGatheringState::Gathering,
+        WebRTCICEGatheringState::Complete => IceGatheringState::Complete,
+        _ => return None,
+    })
+}
+
+fn dtls(state: WebRTCDTLSTransportState) -> Option<DtlsTransportState> {
+    Some(match state {
+        WebRTCDTLSTransportState::New => DtlsTransportState::New,
+        WebRTCDTLSTransportState::Closed => DtlsTransportState::Closed,
+        WebRTCDTLSTransportState::Failed => DtlsTransportState::Failed,
+        WebRTCDTLSTransportState::Connecting => DtlsTransportState::Connecting,
+        WebRTCDTLSTransportState::Connected => DtlsTransportState::Connected,
+        _ => return None,
+    })
+}
+
+/// The distinct dtls transports of the senders and receivers of `pc`'s transceivers,
+/// shared by all of them once bundled. Transceivers get theirs with the local description.
+fn dtls_transports(pc: &gst::Element) -> Vec<WebRTCDTLSTransport> {
+    let mut transports: Vec<WebRTCDTLSTransport> = vec![];
+    for index in 0i32.. {
+        let transceiver = match negotiation::emit(pc, "get-transceiver", &[&index])
+            .ok()
+            .flatten()
+            .and_then(|v| v.get::<WebRTCRTPTransceiver>().ok().flatten())
+        {
+            Some(transceiver) => transceiver,
+            None => break,
+        };
+
+        for end in &["sender", "receiver"] {
+            let transport = transceiver
+                .get_property(end)
+                .ok()
+                .and_then(|v| v.get::<glib::Object>().ok().flatten())
+                .and_then(|end| end.get_property("transport").ok())
+                .and_then(|v| v.get::<WebRTCDTLSTransport>().ok().flatten());
+            if let Some(transport) = transport {
+                if !transports.contains(&transport) {
+                    transports.push(transport);
+                }
+            }
+        }
+    }
+    transports
+}
+
+/// Combines the states of the transports, `None` without any.
+fn combined_dtls(states: &[DtlsTransportState]) -> Option<DtlsTransportState> {
+    use DtlsTransportState::*;
+    if states.is_empty() {
+        return None;
+    }
+
+    Some(if states.contains(&Failed) {
+        Failed
+    } else if states.contains(&Connecting) {
+        Connecting
+    } else if states.iter().all(|s| *s == Closed) {
+        Closed
+    } else if states.iter().all(|s| *s == Connected || *s == Closed) {
+        Connected
+    } else {
+        New
+    })
+}
+
+/// Reads the state properties of `pc` into `state`, fields webrtcbin reports an
+/// unknown value for keep their previous value.
+fn read(pc: &gst::Element, mut state: TransportState) -> TransportState {
+    if let Some(s) = pc
+        .get_property("ice-connection-state")
+        .ok()
+        .and_then(|v| v.get_some::<WebRTCICEConnectionState>().ok())
+        .and_then(ice_connection)
+    {
+        state.ice_connection = s;
+    }
+    if let Some(s) = pc
+        .get_property("connection-state")
+        .ok()
+        .and_then(|v| v.get_some::<WebRTCPeerConnectionState>().ok())
+        .and_then(peer_connection)
+    {
+        state.connection = s;
+    }
+    if let Some(s) = pc
+        .get_property("ice-gathering-state")
+        .ok()
+        .and_then(|v| v.get_some::<WebRTCICEGatheringState>().ok())
+        .and_then(ice_gathering)
+    {
+        state.ice_gathering = s;
+    }
+
+    let states: Vec<DtlsTransportState> = dtls_transports(pc)
+        .iter()
+        .filter_map(|transport| {
+            transport
+                .get_property("state")
+                .ok()
+                .and_then(|v| v.get_some::<WebRTCDTLSTransportState>().ok())
+                .and_then(dtls)
+        })
+        .collect();
+    if let Some(s) = combined_dtls(&states) {
+        state.dtls = s;
+    }
+    state
+}
+
+#[derive(Default)]
+struct Inner {
+    publisher: TransportState,
+    subscriber: TransportState,
+    /// The element tracked for each transport, notifications of replaced ones are ignored.
+    watched: HashMap<Transport, gst::Element>,
+    /// Dtls transports whose state is tracked already.
+    dtls: Vec<WebRTCDTLSTransport>,
+    subscribers: Vec<mpsc::UnboundedSender<StateChange>>,
+}
+
+impl Inner {
+    fn get_mut(&mut self, transport: Transport) -> &mut TransportState {
+        match transport {
+            Transport::Publisher => &mut self.publisher,
+            Transport::Subscriber => &mut self.subscriber,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct StateWatch {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl StateWatch {
+    /// Starts tracking the state of `pc` as `transport`, replacing the element tracked
+    /// before. Returns the handlers to disconnect once `pc` is replaced in turn.
+    pub fn watch(&self, transport: Transport, pc: &gst::Element) -> Vec<glib::SignalHandlerId> {
+        self.inner
+            .lock()
+            .unwrap()
+            .watched
+            .insert(transport, pc.clone());
+        self.update(transport, pc);
+
+        let mut handlers: Vec<glib::SignalHandlerId> = [
+            "ice-connection-state",
+            "connection-state",
+            "ice-gathering-state",
+        ]
+        .iter()
+        .map(|property| {
+            let watch = self.clone();
+            pc.connect_notify(Some(*property), move |pc, _| watch.update(transport, pc))
+        })
+        .collect();
+
+        // transceivers get their dtls transport with a description, look for new ones
+        // after each
+        let watch = self.clone();
+        handlers.push(pc.connect_notify(Some("signaling-state"), move |pc, _| {
+            watch.watch_dtls(transport, pc);
+            watch.update(transport, pc);
+        }));
+        handlers
+    }
+
+    fn watch_dtls(&self, transport: Transport, pc: &gst::Element) {
+        let mut inner = self.inner.lock().unwrap();
+        for dtls in dtls_transports(pc) {
+            if inner.dtls.contains(&dtls) {
+                continue;
+            }
+
+            // the transport goes with pc, the handler with it
+            let watch = self.clone();
+            let pc = pc.downgrade();
+            dtls.connect_notify(Some("state"), move |_, _| {
+                if let Some(pc) = pc.upgrade() {
+                    watch.update(transport, &pc);
+                }
+            });
+            inner.dtls.push(dtls);
+        }
+    }
+
+    fn update(&self, transport: Transport, pc: &gst::Element) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.watched.get(&transport) != Some(pc) {
+            return;
+        }
+        let current = inner.get_mut(transport);
+        let state = read(pc, *current);
+        if state == *current {
+            return;
+        }
+        *current = state;
+
+        let change = StateChange { transport, state };
+        debug!("transport state: {:?}", change);
+        inner
+            .subscribers
+            .retain(|tx| tx.unbounded_send(change).is_ok());
+    }
+
+    pub fn current(&self, transport: Transport) -> TransportState {
+        *self.inner.lock().unwrap().get_mut(transport)
+    }
+
+    /// Subscribes to state changes, starting with the current state of both transports.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<StateChange> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut inner = self.inner.lock().unwrap();
+        for transport in &[Transport::Publisher, Transport::Subscriber] {
+            let _ = tx.unbounded_send(StateChange {
+                transport: *transport,
+                state: *inner.get_mut(*transport),
+            });
+        }
+        inner.subscribers.push(tx);
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use DtlsTransportState::*;
+
+    #[test]
+    fn dtls_combines_the_worst_state() {
+        assert_eq!(combined_dtls(&[]), None);
+        assert_eq!(combined_dtls(&[Connected, Failed]), Some(Failed));
+        assert_eq!(combined_dtls(&[Connected, Connecting]), Some(Connecting));
+        assert_eq!(combined_dtls(&[Connected, New]), Some(New));
+        assert_eq!(combined_dtls(&[Connected, Closed]), Some(Connected));
+        assert_eq!(combined_dtls(&[Closed, Closed]), Some(Closed));
+    }
+
+    #[test]
+    fn dtls_failure_takes_the_transport_down() {
+        let state = TransportState {
+            dtls: Failed,
+            ..TransportState::default()
+        };
+        assert!(state.is_down());
+        assert!(!TransportState::default().is_down());
+    }
+}
@@ -0,0 +1,609 @@
+//! Typed view of webrtcbin's `get-stats` reply. webrtcbin returns one structure holding a
+//! structure per stats object, named after its type (`inbound-rtp`, `candidate-pair`, ..)
+//! with fields following the W3C webrtc-stats names. Fields the running webrtcbin
+//! doesn't report are left at zero or `None`.
+
+use super::negotiation;
+use super::transports::Transports;
+use super::Error;
+use futures::channel::mpsc;
+use gst::prelude::*;
+use log::*;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Rtp stream received on the subscriber.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InboundRtpStats {
+    pub id: String,
+    pub ssrc: u32,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// Cumulative, negative when duplicates outnumber losses.
+    pub packets_lost: i64,
+    /// Interarrival jitter in seconds.
+    pub jitter: f64,
+    pub fir_count: u64,
+    pub pli_count: u64,
+    pub nack_count: u64,
+    pub frames_per_second: Option<f64>,
+}
+
+/// Rtp stream sent by the publisher.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OutboundRtpStats {
+    pub id: String,
+    pub ssrc: u32,
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    /// Keyframe requests received from the sfu.
+    pub fir_count: u64,
+    pub pli_count: u64,
+    pub nack_count: u64,
+    pub frames_per_second: Option<f64>,
+}
+
+/// What the sfu reported about a published stream through rtcp receiver reports.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RemoteInboundRtpStats {
+    pub id: String,
+    pub ssrc: u32,
+    pub packets_lost: i64,
+    pub jitter: f64,
+    /// Round trip time in seconds, `None` until a report arrived.
+    pub round_trip_time: Option<f64>,
+    pub fraction_lost: Option<f64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IcePairStats {
+    pub id: String,
+    pub local_candidate_id: Option<String>,
+    pub remote_candidate_id: Option<String>,
+    pub nominated: bool,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub current_round_trip_time: Option<f64>,
+    pub available_outgoing_bitrate: Option<f64>,
+}
+
+/// The stats of one webrtcbin, other stats types are skipped.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TransportStats {
+    pub inbound_rtp: Vec<InboundRtpStats>,
+    pub outbound_rtp: Vec<OutboundRtpStats>,
+    pub remote_inbound_rtp: Vec<RemoteInboundRtpStats>,
+    pub ice_pairs: Vec<IcePairStats>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub publisher: TransportStats,
+    pub subscriber: TransportStats,
+    pub collected_at: Instant,
+}
+
+/// Rates of one rtp stream over the interval between two `Stats`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RtpRate {
+    pub ssrc: u32,
+    /// Payload and header bits per second.
+    pub bitrate: f64,
+    pub packet_rate: f64,
+    /// Packets lost in the interval.
+    pub packets_lost: i64,
+    /// Lost packets over expected packets in the interval, 0 to 1.
+    pub loss_fraction: f64,
+}
+
+/// A periodic sample from `Client::stats_stream`.
+#[derive(Clone, Debug)]
+pub struct StatsReport {
+    pub stats: Stats,
+    pub elapsed: Duration,
+    /// Published streams, losses as reported back by the sfu.
+    pub outbound: Vec<RtpRate>,
+    /// Subscribed streams.
+    pub inbound: Vec<RtpRate>,
+}
+
+fn uint(s: &gst::StructureRef, field: &str) -> Option<u64> {
+    let value = s.get_value(field).ok()?;
+    value
+        .get_some::<u64>()
+        .ok()
+        .or_else(|| value.get_some::<u32>().ok().map(u64::from))
+        .or_else(|| value.get_some::<i64>().ok().map(|v| v.max(0) as u64))
+        .or_else(|| value.get_some::<i32>().ok().map(|v| v.max(0) as u64))
+}
+
+fn int(s: &gst::StructureRef, field: &str) -> Option<i64> {
+    let value = s.get_value(field).ok()?;
+    value
+        .get_some::<i64>()
+        .ok()
+        .or_else(|| value.get_some::<i32>().ok().map(i64::from))
+        .or_else(|| value.get_some::<u32>().ok().map(i64::from))
+        .or_else(|| value.get_some::<u64>().ok().map(|v| v as i64))
+}
+
+fn float(s: &gst::StructureRef, field: &str) -> Option<f64> {
+    let value = s.get_value(field).ok()?;
+    value
+        .get_some::<f64>()
+        .ok()
+        .or_else(|| value.get_some::<f32>().ok().map(f64::from))
+}
+
+fn string(s: &gst::StructureRef, field: &str) -> Option<String> {
+    s.get::<String>(field).ok().flatten()
+}
+
+fn id(s: &gst::StructureRef) -> String {
+    string(s, "id").unwrap_or_default()
+}
+
+fn ssrc(s: &gst::StructureRef) -> u32 {
+    uint(s, "ssrc").unwrap_or_default() as u32
+}
+
+impl TransportStats {
+    /// Parses the reply of `get-stats`.
+    pub fn parse(reply: &gst::StructureRef) -> TransportStats {
+        let mut stats = TransportStats::default();
+
+        for (name, value) in reply.iter() {
+            let s = match value.get::<gst::Structure>() {
+                Ok(Some(s)) => s,
+                _ => {
+                    trace!("skipping stats field {}", name);
+                    continue;
+                }
+            };
+
+            match s.get_name() {
+                "inbound-rtp" => stats.inbound_rtp.push(InboundRtpStats {
+                    id: id(&s),
+                    ssrc: ssrc(&s),
+                    packets_received: uint(&s, "packets-received").unwrap_or_default(),
+                    bytes_received: uint(&s, "bytes-received").unwrap_or_default(),
+                    packets_lost: int(&s, "packets-lost").unwrap_or_default(),
+                    jitter: float(&s, "jitter").unwrap_or_default(),
+                    fir_count: uint(&s, "fir-count").unwrap_or_default(),
+                    pli_count: uint(&s, "pli-count").unwrap_or_default(),
+                    nack_count: uint(&s, "nack-count").unwrap_or_default(),
+                    frames_per_second: float(&s, "frames-per-second"),
+                }),
+                "outbound-rtp" => stats.outbound_rtp.push(OutboundRtpStats {
+                    id: id(&s),
+                    ssrc: ssrc(&s),
+                    packets_sent: uint(&s, "packets-sent").unwrap_or_default(),
+                    bytes_sent: uint(&s, "bytes-sent").unwrap_or_default(),
+                    fir_count: uint(&s, "fir-count").unwrap_or_default(),
+                    pli_count: uint(&s, "pli-count").unwrap_or_default(),
+                    nack_count: uint(&s, "nack-count").unwrap_or_default(),
+                    frames_per_second: float(&s, "frames-per-second"),
+                }),
+                "remote-inbound-rtp" => stats.remote_inbound_rtp.push(RemoteInboundRtpStats {
+                    id: id(&s),
+                    ssrc: ssrc(&s),
+                    packets_lost: int(&s, "packets-lost").unwrap_or_default(),
+                    jitter: float(&s, "jitter").unwrap_or_default(),
+                    round_trip_time: float(&s, "round-trip-time"),
+                    fraction_lost: float(&s, "fraction-lost"),
+                }),
+                "candidate-pair" => stats.ice_pairs.push(IcePairStats {
+                    id: id(&s),
+                    local_candidate_id: string(&s, "local-candidate-id"),
+                    remote_candidate_id: string(&s, "remote-candidate-id"),
+                    nominated: s.get_some::<bool>("nominated").unwrap_or_default(),
+                    bytes_sent: uint(&s, "bytes-sent").unwrap_or_default(),
+                    bytes_received: uint(&s, "bytes-received").unwrap_or_default(),
+                    current_round_trip_time: float(&s, "current-round-trip-time"),
+                    available_outgoing_bitrate: float(&s, "available-outgoing-bitrate"),
+                }),
+                _ => {}
+            }
+        }
+
+        stats
+    }
+}
+
+/// Emits `get-stats` on `pc` for all of its streams.
+pub(crate) async fn get_stats(pc: &gst::Element) -> Result<TransportStats, Error> {
+    let (promise, fut) = gst::Promise::new_future();
+    negotiation::emit(pc, "get-stats", &[&None::<gst::Pad>, &promise])?;
+
+    match fut.await {
+        Ok(Some(reply)) => Ok(TransportStats::parse(&reply)),
+        Ok(None) => Err(Error::EmptyReply {
+            action: "get-stats",
+        }),
+        Err(err) => Err(Error::Promise {
+            action: "get-stats",
+            reason: format!("{:?}", err),
+        }),
+    }
+}
+
+pub(crate) async fn collect(
+    publisher: &gst::Element,
+    subscriber: &gst::Element,
+) -> Result<Stats, Error> {
+    Ok(Stats {
+        publisher: get_stats(publisher).await?,
+        subscriber: get_stats(subscriber).await?,
+        collected_at: Instant::now(),
+    })
+}
+
+fn rate(ssrc: u32, seconds: f64, bytes: u64, packets: u64, lost: i64) -> RtpRate {
+    let expected = packets as i64 + lost.max(0);
+    RtpRate {
+        ssrc,
+        bitrate: bytes as f64 * 8.0 / seconds,
+        packet_rate: packets as f64 / seconds,
+        packets_lost: lost,
+        loss_fraction: if expected > 0 {
+            lost.max(0) as f64 / expected as f64
+        } else {
+            0.0
+        },
+    }
+}
+
+impl StatsReport {
+    /// Computes the rates between two samples. Streams that are new in `current` are
+    /// measured from zero, counters that went backwards (a restarted stream) count as zero.
+    pub fn between(previous: &Stats, current: Stats) -> StatsReport {
+        let elapsed = current
+            .collected_at
+            .saturating_duration_since(previous.collected_at);
+        let seconds = elapsed.as_secs_f64().max(0.001);
+
+        let sent: HashMap<u32, &OutboundRtpStats> = previous
+            .publisher
+            .outbound_rtp
+            .iter()
+            .map(|s| (s.ssrc, s))
+            .collect();
+        let reported: HashMap<u32, i64> = previous
+            .publisher
+            .remote_inbound_rtp
+            .iter()
+            .map(|s| (s.ssrc, s.packets_lost))
+            .collect();
+        let outbound = current
+            .publisher
+            .outbound_rtp
+            .iter()
+            .map(|s| {
+                let before = sent.get(&s.ssrc);
+                let lost = current
+                    .publisher
+                    .remote_inbound_rtp
+                    .iter()
+                    .find(|r| r.ssrc == s.ssrc)
+                    .map(|r| r.packets_lost - reported.get(&s.ssrc).copied().unwrap_or(0))
+                    .unwrap_or(0);
+                rate(
+                    s.ssrc,
+                    seconds,
+                    s.bytes_sent
+                        .saturating_sub(before.map(|b| b.bytes_sent).unwrap_or(0)),
+                    s.packets_sent
+                        .saturating_sub(before.map(|b| b.packets_sent).unwrap_or(0)),
+                    lost,
+                )
+            })
+            .collect();
+
+        let received: HashMap<u32, &InboundRtpStats> = previous
+            .subscriber
+            .inbound_rtp
+            .iter()
+            .map(|s| (s.ssrc, s))
+            .collect();
+        let inbound = current
+            .subscriber
+            .inbound_rtp
+            .iter()
+            .map(|s| {
+                let before = received.get(&s.ssrc);
+                rate(
+                    s.ssrc,
+                    seconds,
+                    s.bytes_received
+                        .saturating_sub(before.map(|b| b.bytes_received).unwrap_or(0)),
+                    s.packets_received
+                        .saturating_sub(before.map(|b| b.packets_received).unwrap_or(0)),
+                    s.packets_lost - before.map(|b| b.packets_lost).unwrap_or(0),
+                )
+            })
+            .collect();
+
+        StatsReport {
+            stats: current,
+            elapsed,
+            outbound,
+            inbound,
+        }
+    }
+}
+
+/// Samples both transports every `interval` until the receiver is dropped, following them
+/// when they are rebuilt. The first sample only sets the baseline, reports start after
+/// the second.
+pub(crate) async fn sample(
+    transports: Transports,
+    interval: Duration,
+    reports: mpsc::UnboundedSender<StatsReport>,
+) {
+    let mut previous: Option<Stats> = None;
+
+    while !reports.is_closed() {
+        let (publisher, subscriber) = (transports.publisher(), transports.subscriber());
+        match collect(&publisher, &subscriber).await {
+            Ok(current) => {
+                if let Some(previous) = previous.replace(current.clone()) {
+                    if reports
+                        .unbounded_send(StatsReport::between(&previous, current))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            }
+            Err(err) => warn!("could not collect stats: {}", err),
+        }
+
+        async_std::task::sleep(interval).await;
+    }
+
+    debug!("stats stream closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(entries: &[(&str, gst::Structure)]) -> gst::Structure {
+        let mut reply = gst::Structure::new_empty("application/x-webrtc-stats");
+        for (field, s) in entries {
+            reply.set(field, s);
+        }
+        // not a stats object, skipped
+        reply.set("timestamp", &1.5f64);
+        reply
+    }
+
+    fn outbound(ssrc: u32, packets: u64, bytes: u64) -> OutboundRtpStats {
+        OutboundRtpStats {
+            ssrc,
+            packets_sent: packets,
+            bytes_sent: bytes,
+            ..Default::default()
+        }
+    }
+
+    fn inbound(ssrc: u32, packets: u64, bytes: u64, lost: i64) -> InboundRtpStats {
+        InboundRtpStats {
+            ssrc,
+            packets_received: packets,
+            bytes_received: bytes,
+            packets_lost: lost,
+            ..Default::default()
+        }
+    }
+
+    fn stats(publisher: TransportStats, subscriber: TransportStats, at: Instant) -> Stats {
+        Stats {
+            publisher,
+            subscriber,
+            collected_at: at,
+        }
+    }
+
+    #[test]
+    fn parses_stats_objects_of_any_integer_width() {
+        gst::init().unwrap();
+        let reply = reply(&[
+            (
+                "inbound-rtp_1",
+                gst::Structure::builder("inbound-rtp")
+                    .field("id", &"in")
+                    .field("ssrc", &1234u32)
+                    .field("packets-received", &100u64)
+                    .field("bytes-received", &5000u32)
+                    .field("packets-lost", &-2i32)
+                    .field("jitter", &0.25f64)
+                    .field("pli-count", &3u32)
+                    .build(),
+            ),
+            (
+                "outbound-rtp_2",
+                gst::Structure::builder("outbound-rtp")
+                    .field("ssrc", &42u32)
+                    .field("packets-sent", &7i64)
+                    .field("frames-per-second", &30f32)
+                    .build(),
+            ),
+            (
+                "remote-inbound-rtp_3",
+                gst::Structure::builder("remote-inbound-rtp")
+                    .field("ssrc", &42u32)
+                    .field("packets-lost", &4i64)
+                    .field("round-trip-time", &0.05f64)
+                    .build(),
+            ),
+            (
+                "candidate-pair_4",
+                gst::Structure::builder("candidate-pair")
+                    .field("id", &"pair")
+                    .field("local-candidate-id", &"local")
+                    .field("nominated", &true)
+                    .build(),
+            ),
+            ("codec_5", gst::Structure::new_empty("codec")),
+        ]);
+
+        let stats = TransportStats::parse(&reply);
+        assert_eq!(
+            stats.inbound_rtp,
+            vec![InboundRtpStats {
+                id: "in".to_string(),
+                ssrc: 1234,
+                packets_received: 100,
+                bytes_received: 5000,
+                packets_lost: -2,
+                jitter: 0.25,
+                pli_count: 3,
+                ..Default::default()
+            }]
+        );
+        assert_eq!(
+            stats.outbound_rtp,
+            vec![OutboundRtpStats {
+                ssrc: 42,
+                packets_sent: 7,
+                frames_per_second: Some(30.0),
+                ..Default::default()
+            }]
+        );
+        assert_eq!(stats.remote_inbound_rtp[0].packets_lost, 4);
+        assert_eq!(stats.remote_inbound_rtp[0].round_trip_time, Some(0.05));
+        assert_eq!(stats.remote_inbound_rtp[0].fraction_lost, None);
+        assert_eq!(
+            stats.ice_pairs,
+            vec![IcePairStats {
+                id: "pair".to_string(),
+                local_candidate_id: Some("local".to_string()),
+                nominated: true,
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn missing_fields_default_to_zero() {
+        gst::init().unwrap();
+        let reply = reply(&[("inbound-rtp_1", gst::Structure::new_empty("inbound-rtp"))]);
+
+        let stats = TransportStats::parse(&reply);
+        assert_eq!(stats.inbound_rtp, vec![InboundRtpStats::default()]);
+        assert!(stats.outbound_rtp.is_empty());
+    }
+
+    #[test]
+    fn rates_over_the_interval() {
+        let start = Instant::now();
+        let previous = stats(
+            TransportStats {
+                outbound_rtp: vec![outbound(1, 100, 10_000)],
+                remote_inbound_rtp: vec![RemoteInboundRtpStats {
+                    ssrc: 1,
+                    packets_lost: 5,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            TransportStats {
+                inbound_rtp: vec![inbound(2, 50, 5_000, 1)],
+                ..Default::default()
+            },
+            start,
+        );
+        let current = stats(
+            TransportStats {
+                outbound_rtp: vec![outbound(1, 300, 30_000)],
+                remote_inbound_rtp: vec![RemoteInboundRtpStats {
+                    ssrc: 1,
+                    packets_lost: 15,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            TransportStats {
+                inbound_rtp: vec![inbound(2, 140, 14_000, 11)],
+                ..Default::default()
+            },
+            start + Duration::from_secs(2),
+        );
+
+        let report = StatsReport::between(&previous, current);
+        assert_eq!(report.elapsed, Duration::from_secs(2));
+        assert_eq!(
+            report.outbound,
+            vec![RtpRate {
+                ssrc: 1,
+                bitrate: 80_000.0,
+                packet_rate: 100.0,
+                packets_lost: 10,
+                loss_fraction: 10.0 / 210.0,
+            }]
+        );
+        assert_eq!(
+            report.inbound,
+            vec![RtpRate {
+                ssrc: 2,
+                bitrate: 36_000.0,
+                packet_rate: 45.0,
+                packets_lost: 10,
+                loss_fraction: 0.1,
+            }]
+        );
+    }
+
+    #[test]
+    fn new_streams_count_from_zero_and_restarted_ones_from_nothing() {
+        let start = Instant::now();
+        let previous = stats(
+            TransportStats {
+                outbound_rtp: vec![outbound(1, 1000, 100_000)],
+                ..Default::default()
+            },
+            TransportStats::default(),
+            start,
+        );
+        let current = stats(
+            TransportStats {
+                outbound_rtp: vec![outbound(1, 10, 1_000), outbound(3, 20, 2_000)],
+                ..Default::default()
+            },
+            TransportStats::default(),
+            start + Duration::from_secs(1),
+        );
+
+        let report = StatsReport::between(&previous, current);
+        assert_eq!(report.outbound[0].bitrate, 0.0);
+        assert_eq!(report.outbound[0].packet_rate, 0.0);
+        assert_eq!(report.outbound[1].bitrate, 16_000.0);
+        assert_eq!(report.outbound[1].packet_rate, 20.0);
+    }
+
+    #[test]
+    fn zero_elapsed_rates_stay_finite() {
+        let at = Instant::now();
+        let previous = stats(TransportStats::default(), TransportStats::default(), at);
+        let current = stats(
+            TransportStats {
+                outbound_rtp: vec![outbound(1, 0, 0)],
+                ..Default::default()
+            },
+            TransportStats {
+                inbound_rtp: vec![inbound(2, 10, 1_000, 0)],
+                ..Default::default()
+            },
+            at,
+        );
+
+        let report = StatsReport::between(&previous, current);
+        assert_eq!(report.elapsed, Duration::from_secs(0));
+        assert_eq!(report.outbound[0].bitrate, 0.0);
+        assert_eq!(report.outbound[0].loss_fraction, 0.0);
+        assert!(report.inbound[0].bitrate.is_finite());
+        assert!(report.inbound[0].packet_rate.is_finite());
+        assert_eq!(report.inbound[0].packet_rate, 10_000.0);
+    }
+}
@@ -0,0 +1,305 @@
+//! Decodes remote tracks into appsinks so their frames and audio buffers can be consumed
+//! from Rust, instead of every application wiring `decodebin` to the subscriber itself.
+
+use super::executor::Executor;
+use super::tracks::{
+    follow_tracks, track_channel, RemoteTrack, TeeBranch, TrackBranch, TrackKind, TrackReceiver,
+    TrackTable,
+};
+use super::Error;
+use futures::channel::mpsc;
+use gst::prelude::*;
+use log::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A remote track decoded to the caps asked for.
+#[derive(Debug)]
+pub struct DecodedTrack {
+    pub track: RemoteTrack,
+    /// Decoded buffers with their caps. When the consumer falls behind by more than the
+    /// queue size, new samples are dropped. Ends once the track is removed, dropping it
+    /// stops decoding the track.
+    pub samples: mpsc::Receiver<gst::Sample>,
+}
+
+/// Maps a decoded video sample for reading its planes, `None` for other samples.
+pub fn video_frame(
+    sample: &gst::Sample,
+) -> Option<gst_video::VideoFrame<gst_video::video_frame::Readable>> {
+    let info = gst_video::VideoInfo::from_caps(sample.get_caps()?).ok()?;
+    let buffer = sample.get_buffer_owned()?;
+    gst_video::VideoFrame::from_buffer_readable(buffer, &info).ok()
+}
+
+type CapsFor = Arc<dyn Fn(&RemoteTrack) -> Option<gst::Caps> + Send + Sync>;
+
+struct Branch {
+    tee: TeeBranch,
+    bin: gst::Bin,
+    /// Set once the consumer dropped the sample receiver.
+    disconnected: Arc<AtomicBool>,
+}
+
+impl TrackBranch for Branch {
+    fn tee_branch(&self) -> &TeeBranch {
+        &self.tee
+    }
+
+    fn is_closed(&self) -> bool {
+        self.disconnected.load(Ordering::SeqCst)
+    }
+}
+
+/// Decodes every remote track, created by `Client::subscribe`. Video is converted to
+/// `video/x-raw,format=RGBA` and audio to interleaved `F32LE` unless configured otherwise.
+#[derive(Clone)]
+pub struct Subscriber {
+    pipeline: gst::Pipeline,
+    tracks: TrackTable,
+    executor: Executor,
+    video_caps: gst::Caps,
+    audio_caps: gst::Caps,
+    caps_for: Option<CapsFor>,
+    queue_size: usize,
+}
+
+impl Subscriber {
+    pub(crate) fn new(
+        pipeline: &gst::Pipeline,
+        tracks: &TrackTable,
+        executor: &Executor,
+    ) -> Subscriber {
+        Subscriber {
+            pipeline: pipeline.clone(),
+            tracks: tracks.clone(),
+            executor: executor.clone(),
+            video_caps: gst::Caps::builder("video/x-raw")
+                .field("format", &gst_video::VideoFormat::Rgba.to_str())
+                .build(),
+            audio_caps: gst::Caps::builder("audio/x-raw")
+                .field("format", &"F32LE")
+                .field("layout", &"interleaved")
+                .build(),
+            caps_for: None,
+            queue_size: 4,
+        }
+    }
+
+    /// Raw video caps every video track is converted to.
+    pub fn video_caps(mut self, caps: gst::Caps) -> Subscriber {
+        self.video_caps = caps;
+        self
+    }
+
+    /// Shorthand for `video_caps` with a pixel format and optionally a size to scale to.
+    pub fn video_format(
+        self,
+        format: gst_video::VideoFormat,
+        size: Option<(i32, i32)>,
+    ) -> Subscriber {
+        let mut caps = gst::Caps::builder("video/x-raw").field("format", &format.to_str());
+        if let Some((width, height)) = size {
+            caps = caps
+                .field("width", &width)
+                .field("height", &height)
+                .field("pixel-aspect-ratio", &gst::Fraction::new(1, 1));
+        }
+        self.video_caps(caps.build())
+    }
+
+    /// Raw audio caps every audio track is converted to.
+    pub fn audio_caps(mut self, caps: gst::Caps) -> Subscriber {
+        self.audio_caps = caps;
+        self
+    }
+
+    /// Picks caps per track, tracks it returns `None` for get the default caps of their kind.
+    pub fn caps_for<F>(mut self, f: F) -> Subscriber
+    where
+        F: Fn(&RemoteTrack) -> Option<gst::Caps> + Send + Sync + 'static,
+    {
+        self.caps_for = Some(Arc::new(f));
+        self
+    }
+
+    /// How many decoded samples a track buffers for a slow consumer.
+    pub fn queue_size(mut self, size: usize) -> Subscriber {
+        self.queue_size = size.max(1);
+        self
+    }
+
+    /// Starts decoding the tracks added from now on. Decoding stops, and the branches are
+    /// removed, once the returned receiver is dropped.
+    pub fn start(self) -> TrackReceiver<DecodedTrack> {
+        let (tx, rx) = track_channel();
+        let closing = tx.closing();
+        let events = self.tracks.subscribe();
+        let executor = self.executor.clone();
+
+        executor.spawn(async move {
+            follow_tracks(
+                events,
+                tx,
+                |track| {
+                    let (branch, samples) = self.decode(track, &closing)?;
+                    let track = track.clone();
+                    Ok(Some((branch, DecodedTrack { track, samples })))
+                },
+                |branch| self.remove(branch),
+            )
+            .await;
+            debug!("subscriber decoding stopped");
+        });
+
+        rx
+    }
+
+    fn caps(&self, track: &RemoteTrack) -> gst::Caps {
+        self.caps_for
+            .as_ref()
+            .and_then(|f| f(track))
+            .unwrap_or_else(|| match track.kind {
+                TrackKind::Video => self.video_caps.clone(),
+                TrackKind::Audio => self.audio_caps.clone(),
+            })
+    }
+
+    /// Links a `queue ! decodebin` branch to the track, the convert → appsink tail is
+    /// added once decodebin exposes the decoded pad. `closing` is signalled once the
+    /// consumer drops the samples.
+    fn decode(
+        &self,
+        track: &RemoteTrack,
+        closing: &mpsc::UnboundedSender<()>,
+    ) -> Result<(Branch, mpsc::Receiver<gst::Sample>), Error> {
+        let bin = gst::parse_bin_from_description("queue ! decodebin name=decoder", true)
+            .map_err(|e| Error::pipeline("building decode branch", e))?;
+        let decodebin = bin.get_by_name("decoder").unwrap();
+
+        let (tx, samples) = mpsc::channel(self.queue_size);
+        let tx = Arc::new(Mutex::new(tx));
+        let disconnected = Arc::new(AtomicBool::new(false));
+        let sink_disconnected = disconnected.clone();
+        let closing = closing.clone();
+        let caps = self.caps(track);
+        let kind = track.kind;
+        let track_id = track.track_id.clone();
+        // weak, the bin owns decodebin and with it this closure
+        let parent = bin.downgrade();
+        decodebin.connect_pad_added(move |_, pad| {
+            let parent = match parent.upgrade() {
+                Some(parent) => parent,
+                None => return,
+            };
+            if let Err(err) =
+                link_sink(&parent, pad, kind, &caps, &tx, &sink_disconnected, &closing)
+            {
+                warn!("could not link decoded track {}: {}", track_id, err);
+            }
+        });
+
+        self.pipeline
+            .add(&bin)
+            .map_err(|e| Error::pipeline("adding decode branch", e))?;
+        let tee = match TeeBranch::request(track, &bin.get_static_pad("sink").unwrap()) {
+            Ok(tee) => tee,
+            Err(err) => {
+                let _ = self.pipeline.remove(&bin);
+                return Err(err);
+            }
+        };
+
+        let linked = tee.link().and_then(|_| {
+            bin.sync_state_with_parent()
+                .map_err(|e| Error::pipeline("starting decode branch", e))
+        });
+        let branch = Branch {
+            tee,
+            bin,
+            disconnected,
+        };
+        match linked {
+            Ok(_) => Ok((branch, samples)),
+            Err(err) => {
+                self.remove(branch);
+                Err(err)
+            }
+        }
+    }
+
+    fn remove(&self, branch: Branch) {
+        branch.tee.release();
+        let _ = branch.bin.set_state(gst::State::Null);
+        let _ = self.pipeline.remove(&branch.bin);
+    }
+}
+
+/// Links a decoded pad through conversion to an appsink feeding `tx`. `disconnected` is
+/// set and `closing` signalled once the receiver of `tx` is gone.
+fn link_sink(
+    bin: &gst::Bin,
+    pad: &gst::Pad,
+    kind: TrackKind,
+    caps: &gst::Caps,
+    tx: &Arc<Mutex<mpsc::Sender<gst::Sample>>>,
+    disconnected: &Arc<AtomicBool>,
+    closing: &mpsc::UnboundedSender<()>,
+) -> Result<(), Error> {
+    let convert = match kind {
+        TrackKind::Video => "videoconvert ! videoscale ! capsfilter name=caps ! appsink name=sink",
+        TrackKind::Audio => {
+            "audioconvert ! audioresample ! capsfilter name=caps ! appsink name=sink"
+        }
+    };
+    let tail = gst::parse_bin_from_description(convert, true)
+        .map_err(|e| Error::pipeline("building decode sink", e))?;
+    tail.get_by_name("caps")
+        .unwrap()
+        .set_property("caps", caps)
+        .map_err(|e| Error::pipeline("setting decode caps", e))?;
+
+    let appsink = tail
+        .get_by_name("sink")
+        .unwrap()
+        .downcast::<gst_app::AppSink>()
+        .unwrap();
+    // the consumer paces itself through the sample channel, not the clock
+    appsink
+        .set_property("sync", &false)
+        .map_err(|e| Error::pipeline("configuring appsink", e))?;
+    appsink.set_max_buffers(1);
+    appsink.set_drop(true);
+
+    let tx = tx.clone();
+    let disconnected = disconnected.clone();
+    let closing = closing.clone();
+    appsink.set_callbacks(
+        gst_app::AppSinkCallbacks::new()
+            .new_sample(move |sink| {
+                let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                if let Err(err) = tx.lock().unwrap().try_send(sample) {
+                    if err.is_full() {
+                        trace!("decoded sample dropped, consumer is behind");
+                    } else if err.is_disconnected() {
+                        // an error would travel up through the track's tee and stall its
+                        // other branches, the branch is removed by the decoding loop instead
+                        if !disconnected.swap(true, Ordering::SeqCst) {
+                            let _ = closing.unbounded_send(());
+                        }
+                    }
+                }
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+
+    bin.add(&tail)
+        .map_err(|e| Error::pipeline("adding decode sink", e))?;
+    tail.sync_state_with_parent()
+        .map_err(|e| Error::pipeline("starting decode sink", e))?;
+    pad.link(&tail.get_static_pad("sink").unwrap())
+        .map_err(|e| Error::pipeline("linking decode sink", e))?;
+
+    Ok(())
+}
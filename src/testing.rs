@@ -0,0 +1,19 @@
+//! Test support, enabled with the `testing` feature.
+//!
+//! `MockSignal` lets a `Client` join without any network, `MockSfuServer` serves ion-sfu's
+//! json-rpc protocol on localhost and forwards media between the clients of a session.
+//! Publisher offers are answered by a `MockPeer`, a local webrtcbin, so media really flows.
+//! `Loopback` wires two clients through a `MockSfuServer` for end to end checks.
+//! `MockIonServer` serves ion's grpc-web room signaling for `IonSignaler`.
+
+mod ion;
+mod loopback;
+mod peer;
+mod server;
+mod signal;
+
+pub use ion::{IonCall, MockIonServer};
+pub use loopback::{Loopback, LoopbackClient};
+pub use peer::MockPeer;
+pub use server::MockSfuServer;
+pub use signal::{MockSignal, SignalCall};
@@ -0,0 +1,391 @@
+use super::super::ion::grpcws::{self, ClientMessage};
+use super::super::ion::proto::{biz, ion, rtc};
+use super::super::ion::{BIZ_PATH, RTC_PATH};
+use super::super::{Error, Executor, SessionDescription, TrickleCandidate};
+use super::MockPeer;
+use async_std::net::{TcpListener, TcpStream};
+use async_tungstenite::tungstenite::handshake::server::{Request, Response};
+use async_tungstenite::tungstenite::http::HeaderValue;
+use async_tungstenite::tungstenite::Message as WsMessage;
+use futures::channel::mpsc;
+use futures::future::{self, AbortHandle, FutureExt};
+use futures::{SinkExt, StreamExt};
+use log::*;
+use prost::Message;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// A request the mock ion server received, descriptions are recorded by their type.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IonCall {
+    BizJoin {
+        sid: String,
+        uid: String,
+        token: String,
+    },
+    BizLeave {
+        uid: String,
+    },
+    Message {
+        to: String,
+        data: Vec<u8>,
+    },
+    RtcJoin {
+        sid: String,
+        uid: String,
+    },
+    Description {
+        target: i32,
+        t: String,
+    },
+    Trickle {
+        target: i32,
+    },
+}
+
+type Calls = Arc<Mutex<Vec<IonCall>>>;
+
+/// A local grpc-web gateway speaking ion's `biz` and `rtc` signaling, for testing
+/// `IonSignaler` without an ion deployment.
+///
+/// Every rtc call gets a `MockPeer` answering the client's publisher, its candidates are
+/// trickled back with the publisher target. The biz side accepts every join and echoes it
+/// as a peer join event, chat messages are echoed to their sender. Media isn't forwarded
+/// between calls. Its tasks run on the default `Executor` unless started with
+/// `start_with_executor`.
+pub struct MockIonServer {
+    addr: SocketAddr,
+    calls: Calls,
+    peers: Arc<Mutex<Vec<Arc<MockPeer>>>>,
+    task: AbortHandle,
+}
+
+impl MockIonServer {
+    /// Starts listening on a free port of 127.0.0.1.
+    pub async fn start() -> Result<MockIonServer, Error> {
+        MockIonServer::start_with_executor(Executor::default()).await
+    }
+
+    /// Like `start`, spawning the server's tasks on `executor`.
+    pub async fn start_with_executor(executor: Executor) -> Result<MockIonServer, Error> {
+        let connect_error = |e: std::io::Error| Error::Connect {
+            url: "127.0.0.1:0".to_string(),
+            source: Box::new(e),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(connect_error)?;
+        let addr = listener.local_addr().map_err(connect_error)?;
+
+        let calls: Calls = Arc::new(Mutex::new(vec![]));
+        let peers = Arc::new(Mutex::new(vec![]));
+        let (recorded, accepted) = (calls.clone(), peers.clone());
+        let spawner = executor.clone();
+        let (task, handle) = future::abortable(async move {
+            let mut incoming = listener.incoming();
+            while let Some(stream) = incoming.next().await {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("mock ion accept failed: {}", err);
+                        continue;
+                    }
+                };
+
+                let serving = serve(stream, recorded.clone(), accepted.clone(), spawner.clone());
+                spawner.spawn(async move {
+                    if let Err(err) = serving.await {
+                        warn!("mock ion call failed: {}", err);
+                    }
+                });
+            }
+        });
+        executor.spawn(task.map(|_| ()));
+
+        Ok(MockIonServer {
+            addr,
+            calls,
+            peers,
+            task: handle,
+        })
+    }
+
+    /// Url for `IonSignaler::new`.
+    pub fn url(&self) -> String {
+        format!("ws://{}", self.addr)
+    }
+
+    pub fn calls(&self) -> Vec<IonCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Peers of the rtc joins received so far.
+    pub fn peers(&self) -> Vec<Arc<MockPeer>> {
+        self.peers.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockIonServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn biz_reply(payload: biz::signal_reply::Payload) -> WsMessage {
+    grpcws::response(&biz::SignalReply {
+        payload: Some(payload),
+    })
+}
+
+fn rtc_reply(payload: rtc::reply::Payload) -> WsMessage {
+    grpcws::response(&rtc::Reply {
+        payload: Some(payload),
+    })
+}
+
+fn from_proto(desc: &rtc::SessionDescription) -> SessionDescription {
+    SessionDescription {
+        t: desc.r#type.clone(),
+        sdp: desc.sdp.clone(),
+    }
+}
+
+fn to_proto(desc: SessionDescription) -> rtc::SessionDescription {
+    rtc::SessionDescription {
+        target: rtc::Target::Publisher as i32,
+        r#type: desc.t,
+        sdp: desc.sdp,
+        track_infos: vec![],
+    }
+}
+
+/// Handles one biz request, returning the replies to send.
+fn handle_biz(request: biz::SignalRequest, calls: &Calls) -> Vec<WsMessage> {
+    use biz::signal_reply::Payload as Reply;
+    use biz::signal_request::Payload;
+
+    match request.payload {
+        Some(Payload::Join(join)) => {
+            let peer = join.peer.unwrap_or_default();
+            calls.lock().unwrap().push(IonCall::BizJoin {
+                sid: peer.sid.clone(),
+                uid: peer.uid.clone(),
+                token: join.token,
+            });
+            vec![
+                biz_reply(Reply::JoinReply(biz::JoinReply {
+                    success: true,
+                    reason: String::new(),
+                })),
+                biz_reply(Reply::PeerEvent(ion::PeerEvent {
+                    peer: Some(peer),
+                    state: ion::peer_event::State::Join as i32,
+                })),
+            ]
+        }
+        Some(Payload::Leave(leave)) => {
+            calls
+                .lock()
+                .unwrap()
+                .push(IonCall::BizLeave { uid: leave.uid });
+            vec![biz_reply(Reply::LeaveReply(biz::LeaveReply::default()))]
+        }
+        Some(Payload::Msg(msg)) => {
+            calls.lock().unwrap().push(IonCall::Message {
+                to: msg.to.clone(),
+                data: msg.data.clone(),
+            });
+            vec![biz_reply(Reply::Msg(msg))]
+        }
+        None => vec![],
+    }
+}
+
+/// The server side of one rtc call.
+struct RtcCall {
+    calls: Calls,
+    peers: Arc<Mutex<Vec<Arc<MockPeer>>>>,
+    peer: Option<Arc<MockPeer>>,
+    out: mpsc::UnboundedSender<WsMessage>,
+    executor: Executor,
+}
+
+impl RtcCall {
+    async fn handle(&mut self, request: rtc::Request) -> Result<(), Error> {
+        use rtc::reply::Payload as Reply;
+        use rtc::request::Payload;
+
+        match request.payload {
+            Some(Payload::Join(join)) => {
+                self.calls.lock().unwrap().push(IonCall::RtcJoin {
+                    sid: join.sid,
+                    uid: join.uid,
+                });
+
+                let peer = Arc::new(MockPeer::new()?);
+                self.peers.lock().unwrap().push(peer.clone());
+                self.trickle_candidates(&peer);
+
+                let offer = join.description.ok_or(Error::Signal {
+                    method: "join",
+                    source: "join without offer".into(),
+                })?;
+                let answer = peer.answer(&from_proto(&offer)).await?;
+                self.peer = Some(peer);
+                self.send(rtc_reply(Reply::Join(rtc::JoinReply {
+                    success: true,
+                    error: None,
+                    description: Some(to_proto(answer)),
+                })))
+            }
+            Some(Payload::Description(desc)) => {
+                self.calls.lock().unwrap().push(IonCall::Description {
+                    target: desc.target,
+                    t: desc.r#type.clone(),
+                });
+                if desc.r#type != "offer" {
+                    return Ok(());
+                }
+
+                let answer = self.peer()?.answer(&from_proto(&desc)).await?;
+                self.send(rtc_reply(Reply::Description(to_proto(answer))))
+            }
+            Some(Payload::Trickle(trickle)) => {
+                self.calls.lock().unwrap().push(IonCall::Trickle {
+                    target: trickle.target,
+                });
+                if trickle.target == rtc::Target::Publisher as i32 {
+                    let candidate: TrickleCandidate = serde_json::from_str(&trickle.init)?;
+                    self.peer()?.add_ice_candidate(&candidate)?;
+                }
+                Ok(())
+            }
+            Some(Payload::Subscription(_)) | None => Ok(()),
+        }
+    }
+
+    fn peer(&self) -> Result<&MockPeer, Error> {
+        self.peer.as_deref().ok_or(Error::NotConnected)
+    }
+
+    fn send(&self, msg: WsMessage) -> Result<(), Error> {
+        self.out
+            .unbounded_send(msg)
+            .map_err(|_| Error::NotConnected)
+    }
+
+    fn trickle_candidates(&self, peer: &MockPeer) {
+        use rtc::reply::Payload as Reply;
+
+        let mut candidates = peer.candidates();
+        let out = self.out.clone();
+        self.executor.spawn(async move {
+            while let Some(candidate) = candidates.next().await {
+                let init = match serde_json::to_string(&candidate) {
+                    Ok(init) => init,
+                    Err(err) => {
+                        warn!("mock ion could not encode candidate: {}", err);
+                        continue;
+                    }
+                };
+                let trickle = rtc_reply(Reply::Trickle(rtc::Trickle {
+                    target: rtc::Target::Publisher as i32,
+                    init,
+                }));
+                if out.unbounded_send(trickle).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    calls: Calls,
+    peers: Arc<Mutex<Vec<Arc<MockPeer>>>>,
+    executor: Executor,
+) -> Result<(), Error> {
+    let mut path = String::new();
+    let ws =
+        async_tungstenite::accept_hdr_async(stream, |request: &Request, mut response: Response| {
+            path = request.uri().path().trim_start_matches('/').to_string();
+            response.headers_mut().insert(
+                "Sec-WebSocket-Protocol",
+                HeaderValue::from_static(grpcws::PROTOCOL),
+            );
+            Ok(response)
+        })
+        .await
+        .map_err(|e| Error::signal("accept", e))?;
+    let (mut sink, mut source) = ws.split();
+
+    let (out, mut outgoing) = mpsc::unbounded::<WsMessage>();
+    executor.spawn(async move {
+        while let Some(msg) = outgoing.next().await {
+            if sink.send(msg).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut call = match path.as_str() {
+        BIZ_PATH => None,
+        RTC_PATH => Some(RtcCall {
+            calls: calls.clone(),
+            peers,
+            peer: None,
+            out: out.clone(),
+            executor,
+        }),
+        _ => {
+            let _ = out.unbounded_send(grpcws::trailers(12, "unknown method"));
+            return Ok(());
+        }
+    };
+
+    // the request headers come first and carry nothing the mock needs
+    let _ = source.next().await;
+    let _ = out.unbounded_send(grpcws::response_headers());
+
+    while let Some(msg) = source.next().await {
+        let data = match msg {
+            Ok(WsMessage::Binary(data)) => data,
+            Ok(WsMessage::Close(_)) => break,
+            Ok(_) => continue,
+            Err(err) => {
+                warn!("mock ion receive failed: {}", err);
+                break;
+            }
+        };
+
+        let payload = match grpcws::parse_client(&data) {
+            ClientMessage::Request(payload) => payload,
+            ClientMessage::FinishSend => break,
+            ClientMessage::Invalid => {
+                warn!("mock ion got an invalid frame on {}", path);
+                continue;
+            }
+        };
+
+        let handled = match call.as_mut() {
+            None => biz::SignalRequest::decode(&payload[..])
+                .map(|request| {
+                    for reply in handle_biz(request, &calls) {
+                        let _ = out.unbounded_send(reply);
+                    }
+                })
+                .map_err(|e| Error::signal("decode", e)),
+            Some(call) => match rtc::Request::decode(&payload[..]) {
+                Ok(request) => call.handle(request).await,
+                Err(err) => Err(Error::signal("decode", err)),
+            },
+        };
+        if let Err(err) = handled {
+            warn!("mock ion request on {} failed: {}", path, err);
+        }
+    }
+
+    let _ = out.unbounded_send(grpcws::trailers(0, ""));
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use super::super::jsonrpc::{JsonRPCSignaler, ReconnectConfig};
+use super::super::{Client, ClientConfig, ClientEvent, Error, Executor, TrackEvent};
+use super::MockSfuServer;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use gst::prelude::*;
+use log::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub type LoopbackClient = Client<JsonRPCSignaler>;
+
+/// End to end harness: two clients join the same session of a `MockSfuServer`, each
+/// publishing a vp8 test pattern, and count the rtp buffers arriving on their remote
+/// tracks. Everything runs in-process over host candidates, the tasks of the server and
+/// the clients on one `Executor`.
+pub struct Loopback {
+    pub server: MockSfuServer,
+    pub pipeline: gst::Pipeline,
+    pub first: LoopbackClient,
+    pub second: LoopbackClient,
+    /// Event streams of `first` and `second`, in that order.
+    pub events: Vec<mpsc::UnboundedReceiver<ClientEvent>>,
+    url: String,
+    executor: Executor,
+    received: [Arc<AtomicUsize>; 2],
+}
+
+fn test_source(pipeline: &gst::Pipeline, pattern: &str) -> Result<gst::Element, Error> {
+    let source = gst::parse_bin_from_description(
+        &format!(
+            "videotestsrc is-live=true pattern={} !
+            video/x-raw,width=320,height=240,framerate=15/1 !
+            vp8enc deadline=1 ! rtpvp8pay",
+            pattern
+        ),
+        true,
+    )
+    .map_err(|e| Error::pipeline("building test source", e))?;
+    let source = source.upcast::<gst::Element>();
+
+    pipeline
+        .add(&source)
+        .map_err(|e| Error::pipeline("adding test source", e))?;
+    Ok(source)
+}
+
+// counts on the tracks rather than the subscriber, which is replaced when rejoining
+fn count_received(client: &LoopbackClient, executor: &Executor) -> Arc<AtomicUsize> {
+    let received = Arc::new(AtomicUsize::new(0));
+    let counter = received.clone();
+    let mut tracks = client.tracks();
+    executor.spawn(async move {
+        while let Some(event) = tracks.next().await {
+            if let TrackEvent::Added(track) = event {
+                let counter = counter.clone();
+                track.pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    gst::PadProbeReturn::Ok
+                });
+            }
+        }
+    });
+    received
+}
+
+impl Loopback {
+    /// Starts the sfu and joins both clients to session `sid`.
+    pub async fn start(sid: &str) -> Result<Loopback, Error> {
+        Loopback::start_with(sid, None, Executor::default()).await
+    }
+
+    /// Like `start`, running the tasks of the server and the clients on `executor`.
+    pub async fn start_with_executor(sid: &str, executor: Executor) -> Result<Loopback, Error> {
+        Loopback::start_with(sid, None, executor).await
+    }
+
+    /// Like `start`, with signalers that reconnect once the server dropped them, see
+    /// `MockSfuServer::disconnect`.
+    pub async fn start_with_reconnect(
+        sid: &str,
+        reconnect: ReconnectConfig,
+    ) -> Result<Loopback, Error> {
+        Loopback::start_with(sid, Some(reconnect), Executor::default()).await
+    }
+
+    async fn start_with(
+        sid: &str,
+        reconnect: Option<ReconnectConfig>,
+        executor: Executor,
+    ) -> Result<Loopback, Error> {
+        let server = MockSfuServer::start_with_executor(executor.clone()).await?;
+        let url = server.url();
+        let signaler = || {
+            let signaler = JsonRPCSignaler::new(&url).with_executor(executor.clone());
+            match &reconnect {
+                Some(config) => signaler.with_reconnect(config.clone()),
+                None => signaler,
+            }
+        };
+        let config = || ClientConfig::new().executor(executor.clone());
+
+        let pipeline = gst::Pipeline::new(Some("loopback"));
+        let mut first = Client::new(signaler(), &pipeline, config())?;
+        let mut second = Client::new(signaler(), &pipeline, config())?;
+
+        first.link_publish_source(&test_source(&pipeline, "smpte")?)?;
+        second.link_publish_source(&test_source(&pipeline, "ball")?)?;
+        let received = [
+            count_received(&first, &executor),
+            count_received(&second, &executor),
+        ];
+
+        pipeline
+            .set_state(gst::State::Playing)
+            .map_err(|e| Error::pipeline("starting loopback pipeline", e))?;
+
+        let events = vec![
+            first.join(sid.to_string()).await?,
+            second.join(sid.to_string()).await?,
+        ];
+
+        Ok(Loopback {
+            server,
+            pipeline,
+            first,
+            second,
+            events,
+            url,
+            executor,
+            received,
+        })
+    }
+
+    /// Another client of the server in the same pipeline, publishing the test pattern
+    /// `pattern` once it joined, e.g. a relay target or a viewer of one.
+    pub fn client(&self, pattern: &str) -> Result<LoopbackClient, Error> {
+        let client = Client::new(
+            JsonRPCSignaler::new(&self.url).with_executor(self.executor.clone()),
+            &self.pipeline,
+            ClientConfig::new().executor(self.executor.clone()),
+        )?;
+        let source = test_source(&self.pipeline, pattern)?;
+        client.link_publish_source(&source)?;
+        source
+            .sync_state_with_parent()
+            .map_err(|e| Error::pipeline("starting test source", e))?;
+        Ok(client)
+    }
+
+    /// Rtp buffers received by the subscribers of `first` and `second`.
+    pub fn received(&self) -> (usize, usize) {
+        (
+            self.received[0].load(Ordering::Relaxed),
+            self.received[1].load(Ordering::Relaxed),
+        )
+    }
+
+    /// Waits until both clients received media published by the other.
+    pub async fn wait_for_media(&self, timeout: Duration) -> Result<(), Error> {
+        self.wait_for_new_media((0, 0), timeout).await
+    }
+
+    /// Waits until both clients received media beyond the counts of `since`, a snapshot
+    /// of `received`.
+    pub async fn wait_for_new_media(
+        &self,
+        since: (usize, usize),
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let step = Duration::from_millis(100);
+        let mut waited = Duration::from_secs(0);
+
+        loop {
+            let (first, second) = self.received();
+            if first > since.0 && second > since.1 {
+                return Ok(());
+            }
+            if waited >= timeout {
+                warn!("loopback received {} and {} buffers", first, second);
+                return Err(Error::Timeout("loopback media"));
+            }
+
+            async_std::task::sleep(step).await;
+            waited += step;
+        }
+    }
+
+    /// Leaves the session with both clients and stops the pipeline.
+    pub async fn stop(mut self) -> Result<(), Error> {
+        self.first.leave().await?;
+        self.second.leave().await?;
+        self.pipeline
+            .set_state(gst::State::Null)
+            .map_err(|e| Error::pipeline("stopping loopback pipeline", e))?;
+        Ok(())
+    }
+}
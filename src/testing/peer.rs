@@ -0,0 +1,207 @@
+use super::super::negotiation;
+use super::super::sdp::Sdp;
+use super::super::{Error, SessionDescription, TrickleCandidate};
+use futures::channel::mpsc;
+use gst::prelude::*;
+use gst_webrtc::WebRTCSDPType;
+use log::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Tracks {
+    tees: Vec<gst::Element>,
+    subscribers: Vec<mpsc::UnboundedSender<gst::Element>>,
+}
+
+/// The sfu end of a client's publisher transport: a webrtcbin that answers publisher
+/// offers and feeds every received track into a `tee` with a fakesink, counting buffers.
+pub struct MockPeer {
+    pipeline: gst::Pipeline,
+    owns_pipeline: bool,
+    webrtcbin: gst::Element,
+    candidates: Arc<Mutex<Vec<mpsc::UnboundedSender<TrickleCandidate>>>>,
+    tracks: Arc<Mutex<Tracks>>,
+    buffers: Arc<AtomicUsize>,
+    offers: Mutex<Vec<String>>,
+}
+
+impl MockPeer {
+    /// Creates a peer running in its own pipeline.
+    pub fn new() -> Result<MockPeer, Error> {
+        let pipeline = gst::Pipeline::new(None);
+        let peer = MockPeer::build(&pipeline, true)?;
+        pipeline
+            .set_state(gst::State::Playing)
+            .map_err(|e| Error::pipeline("starting mock peer", e))?;
+        Ok(peer)
+    }
+
+    /// Creates a peer in a running pipeline shared with other peers, so received tracks
+    /// can be forwarded between them.
+    pub(crate) fn in_pipeline(pipeline: &gst::Pipeline) -> Result<MockPeer, Error> {
+        let peer = MockPeer::build(pipeline, false)?;
+        peer.webrtcbin
+            .sync_state_with_parent()
+            .map_err(|e| Error::pipeline("starting mock peer", e))?;
+        Ok(peer)
+    }
+
+    fn build(pipeline: &gst::Pipeline, owns_pipeline: bool) -> Result<MockPeer, Error> {
+        let webrtcbin = gst::ElementFactory::make("webrtcbin", None)
+            .map_err(|_| Error::ElementNotFound("webrtcbin".to_string()))?;
+        webrtcbin.set_property_from_str("bundle-policy", "max-bundle");
+        pipeline
+            .add(&webrtcbin)
+            .map_err(|e| Error::pipeline("adding mock peer webrtcbin", e))?;
+
+        let buffers = Arc::new(AtomicUsize::new(0));
+        let tracks = Arc::new(Mutex::new(Tracks::default()));
+        let counter = buffers.clone();
+        let received = tracks.clone();
+        let bin = pipeline.clone();
+        webrtcbin.connect_pad_added(move |_, pad| {
+            if pad.get_direction() != gst::PadDirection::Src {
+                return;
+            }
+
+            let counter = counter.clone();
+            pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                gst::PadProbeReturn::Ok
+            });
+
+            let tee = gst::ElementFactory::make("tee", None).unwrap();
+            tee.set_property("allow-not-linked", &true).unwrap();
+            let sink = gst::ElementFactory::make("fakesink", None).unwrap();
+            sink.set_property("async", &false).unwrap();
+            bin.add_many(&[&tee, &sink]).unwrap();
+            tee.link(&sink).unwrap();
+            tee.sync_state_with_parent().unwrap();
+            sink.sync_state_with_parent().unwrap();
+            pad.link(&tee.get_static_pad("sink").unwrap()).unwrap();
+
+            let mut tracks = received.lock().unwrap();
+            tracks
+                .subscribers
+                .retain(|tx| tx.unbounded_send(tee.clone()).is_ok());
+            tracks.tees.push(tee);
+        });
+
+        let candidates: Arc<Mutex<Vec<mpsc::UnboundedSender<TrickleCandidate>>>> =
+            Arc::new(Mutex::new(vec![]));
+        let subscribers = candidates.clone();
+        webrtcbin
+            .connect("on-ice-candidate", false, move |values| {
+                let mlineindex = values[1].get_some::<u32>().expect("Invalid argument");
+                let candidate = values[2]
+                    .get::<String>()
+                    .expect("Invalid argument")
+                    .unwrap();
+
+                subscribers.lock().unwrap().retain(|tx| {
+                    tx.unbounded_send(TrickleCandidate {
+                        candidate: candidate.clone(),
+                        sdp_mid: None,
+                        sdp_mline_index: mlineindex,
+                    })
+                    .is_ok()
+                });
+                None
+            })
+            .map_err(|e| Error::Action {
+                action: "connect on-ice-candidate",
+                source: e,
+            })?;
+
+        Ok(MockPeer {
+            pipeline: pipeline.clone(),
+            owns_pipeline,
+            webrtcbin,
+            candidates,
+            tracks,
+            buffers,
+            offers: Mutex::new(vec![]),
+        })
+    }
+
+    pub fn webrtcbin(&self) -> &gst::Element {
+        &self.webrtcbin
+    }
+
+    /// Applies an offer from the client's publisher and returns the answer. Simulcast
+    /// m-lines are accepted with all their rids like ion-sfu does, webrtcbin itself
+    /// ignores them.
+    pub async fn answer(&self, offer: &SessionDescription) -> Result<SessionDescription, Error> {
+        self.offers.lock().unwrap().push(offer.sdp.clone());
+        let received = Sdp::parse(&offer.sdp);
+        let offer = negotiation::from_signal(offer, WebRTCSDPType::Offer)?;
+        negotiation::set_remote_description(&self.webrtcbin, &offer)?;
+
+        let answer = negotiation::create_answer(&self.webrtcbin).await?;
+        negotiation::set_local_description(&self.webrtcbin, &answer)?;
+        let mut answer = negotiation::to_signal(&answer)?;
+
+        let mut sdp = Sdp::parse(&answer.sdp);
+        for (offered, answered) in received.media.iter().zip(sdp.media.iter_mut()) {
+            let rids = match offered
+                .attribute("simulcast")
+                .and_then(|s| s.strip_prefix("send "))
+            {
+                Some(rids) => rids.to_string(),
+                None => continue,
+            };
+            for rid in rids.split(';') {
+                answered.push_attribute(&format!("rid:{} recv", rid));
+            }
+            answered.push_attribute(&format!("simulcast:recv {}", rids));
+        }
+        answer.sdp = sdp.to_string();
+        Ok(answer)
+    }
+
+    /// Sdp of the offers answered so far, as the client sent them.
+    pub fn offers(&self) -> Vec<String> {
+        self.offers.lock().unwrap().clone()
+    }
+
+    pub fn add_ice_candidate(&self, candidate: &TrickleCandidate) -> Result<(), Error> {
+        negotiation::add_ice_candidate(&self.webrtcbin, candidate)
+    }
+
+    /// Returns a stream of the local candidates gathered from now on, to be trickled to
+    /// the client with target 0.
+    pub fn candidates(&self) -> mpsc::UnboundedReceiver<TrickleCandidate> {
+        let (tx, rx) = mpsc::unbounded();
+        self.candidates.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Returns a stream of the tees of received tracks, starting with the ones that
+    /// already exist. Request a src pad on a tee to forward its track.
+    pub fn tracks(&self) -> mpsc::UnboundedReceiver<gst::Element> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut tracks = self.tracks.lock().unwrap();
+        for tee in tracks.tees.iter() {
+            let _ = tx.unbounded_send(tee.clone());
+        }
+        tracks.subscribers.push(tx);
+        rx
+    }
+
+    /// Number of rtp buffers received over all tracks.
+    pub fn buffers_received(&self) -> usize {
+        self.buffers.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for MockPeer {
+    fn drop(&mut self) {
+        if !self.owns_pipeline {
+            let _ = self.webrtcbin.set_state(gst::State::Null);
+            let _ = self.pipeline.remove(&self.webrtcbin);
+        } else if self.pipeline.set_state(gst::State::Null).is_err() {
+            warn!("could not stop mock peer pipeline");
+        }
+    }
+}
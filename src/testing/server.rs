@@ -0,0 +1,433 @@
+use super::super::jsonrpc::{JoinMsg, NegotiateMsg, TrickleNotification};
+use super::super::{negotiation, Error, Executor, TrickleCandidate};
+use super::MockPeer;
+use async_std::net::{TcpListener, TcpStream};
+use async_tungstenite::tungstenite::Message as WsMessage;
+use futures::channel::mpsc;
+use futures::future::{self, AbortHandle, FutureExt};
+use futures::{SinkExt, StreamExt};
+use gst::prelude::*;
+use gst_webrtc::WebRTCSDPType;
+use log::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Deserialize, Debug)]
+struct RpcRequest {
+    method: String,
+    #[serde(default)]
+    params: Value,
+    id: Option<Value>,
+}
+
+/// The sfu side of one connection: a peer receiving the client's publisher and a
+/// webrtcbin sending the tracks of the other session members to its subscriber.
+struct Member {
+    peer: Arc<MockPeer>,
+    subscriber: gst::Element,
+}
+
+type Sessions = Arc<Mutex<HashMap<String, Vec<Arc<Member>>>>>;
+type Connections = Arc<Mutex<Vec<mpsc::UnboundedSender<WsMessage>>>>;
+
+/// A local websocket server speaking ion-sfu's json-rpc protocol, for testing
+/// `JsonRPCSignaler` and `Client` without an sfu.
+///
+/// It is a minimal forwarding sfu: every connection gets a `MockPeer` answering its
+/// publisher, and the tracks it publishes are teed to the subscribers of the other
+/// connections joined to the same session. All webrtcbins share one pipeline and only
+/// gather host candidates. Its tasks run on the default `Executor` unless started with
+/// `start_with_executor`.
+pub struct MockSfuServer {
+    addr: SocketAddr,
+    pipeline: gst::Pipeline,
+    peers: Arc<Mutex<Vec<Arc<MockPeer>>>>,
+    connections: Connections,
+    task: AbortHandle,
+}
+
+impl MockSfuServer {
+    /// Starts listening on a free port of 127.0.0.1.
+    pub async fn start() -> Result<MockSfuServer, Error> {
+        MockSfuServer::start_with_executor(Executor::default()).await
+    }
+
+    /// Like `start`, spawning the server's tasks on `executor`.
+    pub async fn start_with_executor(executor: Executor) -> Result<MockSfuServer, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(|e| Error::Connect {
+                url: "127.0.0.1:0".to_string(),
+                source: Box::new(e),
+            })?;
+        let addr = listener.local_addr().map_err(|e| Error::Connect {
+            url: "127.0.0.1:0".to_string(),
+            source: Box::new(e),
+        })?;
+
+        let pipeline = gst::Pipeline::new(Some("mock-sfu"));
+        pipeline
+            .set_state(gst::State::Playing)
+            .map_err(|e| Error::pipeline("starting mock sfu", e))?;
+
+        let peers = Arc::new(Mutex::new(vec![]));
+        let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+        let connections: Connections = Arc::new(Mutex::new(vec![]));
+        let accepted = peers.clone();
+        let open = connections.clone();
+        let sfu = pipeline.clone();
+        let spawner = executor.clone();
+        let (task, handle) = future::abortable(async move {
+            let mut incoming = listener.incoming();
+            while let Some(stream) = incoming.next().await {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("mock sfu accept failed: {}", err);
+                        continue;
+                    }
+                };
+
+                let serving = serve(
+                    stream,
+                    sfu.clone(),
+                    accepted.clone(),
+                    open.clone(),
+                    sessions.clone(),
+                    spawner.clone(),
+                );
+                spawner.spawn(async move {
+                    if let Err(err) = serving.await {
+                        warn!("mock sfu connection failed: {}", err);
+                    }
+                });
+            }
+        });
+        executor.spawn(task.map(|_| ()));
+
+        Ok(MockSfuServer {
+            addr,
+            pipeline,
+            peers,
+            connections,
+            task: handle,
+        })
+    }
+
+    /// Url for `JsonRPCSignaler::new`.
+    pub fn url(&self) -> String {
+        format!("ws://{}/session/test", self.addr)
+    }
+
+    /// Peers of the connections accepted so far.
+    pub fn peers(&self) -> Vec<Arc<MockPeer>> {
+        self.peers.lock().unwrap().clone()
+    }
+
+    /// Closes the websocket of every open connection, like an sfu restart. The peers of
+    /// the connections leave their sessions once the clients acknowledged the close.
+    pub fn disconnect(&self) {
+        for out in self.connections.lock().unwrap().drain(..) {
+            let _ = out.unbounded_send(WsMessage::Close(None));
+        }
+    }
+}
+
+impl Drop for MockSfuServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+fn notification(method: &str, params: Value) -> WsMessage {
+    WsMessage::Text(json!({ "jsonrpc": "2.0", "method": method, "params": params }).to_string())
+}
+
+/// Links a new src pad of `tee` to a new sink pad of `subscriber`, which makes the
+/// subscriber renegotiate.
+fn forward(pipeline: &gst::Pipeline, tee: &gst::Element, subscriber: &gst::Element) {
+    let queue = gst::ElementFactory::make("queue", None).unwrap();
+    pipeline.add(&queue).unwrap();
+    queue.sync_state_with_parent().unwrap();
+
+    let src = tee.get_request_pad("src_%u").unwrap();
+    src.link(&queue.get_static_pad("sink").unwrap()).unwrap();
+    let sink = subscriber.get_request_pad("sink_%u").unwrap();
+    queue.get_static_pad("src").unwrap().link(&sink).unwrap();
+}
+
+/// Forwards every track `from` publishes, now or later, to the subscriber of `to`.
+fn forward_tracks(executor: &Executor, pipeline: &gst::Pipeline, from: &Member, to: &Member) {
+    let pipeline = pipeline.clone();
+    let subscriber = to.subscriber.clone();
+    let mut tracks = from.peer.tracks();
+    executor.spawn(async move {
+        while let Some(tee) = tracks.next().await {
+            debug!("mock sfu forwarding track");
+            forward(&pipeline, &tee, &subscriber);
+        }
+    });
+}
+
+/// Offers the subscriber to the client once every forwarded track has caps,
+/// webrtcbin leaves tracks without caps out of its offer.
+async fn offer_subscriber(
+    subscriber: &gst::Element,
+    out: &mpsc::UnboundedSender<WsMessage>,
+) -> Result<(), Error> {
+    for _ in 0..50 {
+        let ready = subscriber
+            .get_sink_pads()
+            .iter()
+            .all(|pad| !pad.is_linked() || pad.has_current_caps());
+        if ready {
+            break;
+        }
+        async_std::task::sleep(Duration::from_millis(100)).await;
+    }
+
+    let offer = negotiation::create_offer(subscriber, None).await?;
+    negotiation::set_local_description(subscriber, &offer)?;
+    let offer = negotiation::to_signal(&offer)?;
+
+    out.unbounded_send(notification("offer", serde_json::to_value(offer)?))
+        .map_err(|_| Error::NotConnected)
+}
+
+/// Sets up the subscriber webrtcbin of a connection, offering it to the client whenever
+/// forwarded tracks are added.
+fn subscriber(
+    pipeline: &gst::Pipeline,
+    out: &mpsc::UnboundedSender<WsMessage>,
+    mut answered: mpsc::UnboundedReceiver<()>,
+    executor: &Executor,
+) -> Result<gst::Element, Error> {
+    let subscriber = gst::ElementFactory::make("webrtcbin", None)
+        .map_err(|_| Error::ElementNotFound("webrtcbin".to_string()))?;
+    subscriber.set_property_from_str("bundle-policy", "max-bundle");
+    pipeline
+        .add(&subscriber)
+        .map_err(|e| Error::pipeline("adding mock sfu subscriber", e))?;
+    subscriber
+        .sync_state_with_parent()
+        .map_err(|e| Error::pipeline("starting mock sfu subscriber", e))?;
+
+    let (negotiate_tx, mut negotiate) = mpsc::unbounded();
+    subscriber
+        .connect("on-negotiation-needed", false, move |_| {
+            let _ = negotiate_tx.unbounded_send(());
+            None
+        })
+        .map_err(|e| Error::Action {
+            action: "connect on-negotiation-needed",
+            source: e,
+        })?;
+
+    let trickles = out.clone();
+    subscriber
+        .connect("on-ice-candidate", false, move |values| {
+            let mlineindex = values[1].get_some::<u32>().expect("Invalid argument");
+            let candidate = values[2]
+                .get::<String>()
+                .expect("Invalid argument")
+                .unwrap();
+
+            let candidate = TrickleCandidate {
+                candidate,
+                sdp_mid: None,
+                sdp_mline_index: mlineindex,
+            };
+            let params = json!({ "target": 1, "candidate": candidate });
+            let _ = trickles.unbounded_send(notification("trickle", params));
+            None
+        })
+        .map_err(|e| Error::Action {
+            action: "connect on-ice-candidate",
+            source: e,
+        })?;
+
+    let pc = subscriber.clone();
+    let out = out.clone();
+    executor.spawn(async move {
+        while negotiate.next().await.is_some() {
+            // one offer covers every track added in the meantime
+            while let Ok(Some(_)) = negotiate.try_next() {}
+
+            if let Err(err) = offer_subscriber(&pc, &out).await {
+                warn!("mock sfu could not offer subscriber: {}", err);
+                continue;
+            }
+            // a new offer can only be made once the client answered
+            if answered.next().await.is_none() {
+                break;
+            }
+        }
+    });
+
+    Ok(subscriber)
+}
+
+struct Connection {
+    pipeline: gst::Pipeline,
+    member: Arc<Member>,
+    sessions: Sessions,
+    answered: mpsc::UnboundedSender<()>,
+    executor: Executor,
+}
+
+impl Connection {
+    async fn handle(&self, method: &str, params: Value) -> Result<Value, Error> {
+        match method {
+            "join" => {
+                let msg: JoinMsg = serde_json::from_value(params)?;
+                let answer = self.member.peer.answer(&msg.offer).await?;
+
+                let mut sessions = self.sessions.lock().unwrap();
+                let members = sessions.entry(msg.sid).or_default();
+                for other in members.iter() {
+                    forward_tracks(&self.executor, &self.pipeline, other, &self.member);
+                    forward_tracks(&self.executor, &self.pipeline, &self.member, other);
+                }
+                members.push(self.member.clone());
+
+                Ok(serde_json::to_value(answer)?)
+            }
+            "offer" => {
+                let msg: NegotiateMsg = serde_json::from_value(params)?;
+                let answer = self.member.peer.answer(&msg.desc).await?;
+                Ok(serde_json::to_value(answer)?)
+            }
+            "answer" => {
+                let msg: NegotiateMsg = serde_json::from_value(params)?;
+                let answer = negotiation::from_signal(&msg.desc, WebRTCSDPType::Answer)?;
+                negotiation::set_remote_description(&self.member.subscriber, &answer)?;
+                let _ = self.answered.unbounded_send(());
+                Ok(Value::Null)
+            }
+            "trickle" => {
+                let msg: TrickleNotification = serde_json::from_value(params)?;
+                match msg.target {
+                    0 => self.member.peer.add_ice_candidate(&msg.candidate)?,
+                    _ => negotiation::add_ice_candidate(&self.member.subscriber, &msg.candidate)?,
+                }
+                Ok(Value::Null)
+            }
+            "ping" => Ok(json!("pong")),
+            _ => Err(Error::Signal {
+                method: "unknown",
+                source: format!("unknown method {}", method).into(),
+            }),
+        }
+    }
+
+    fn leave(&self) {
+        for members in self.sessions.lock().unwrap().values_mut() {
+            members.retain(|member| !Arc::ptr_eq(member, &self.member));
+        }
+        // ion-sfu closes both peer connections of a connection that went away
+        let _ = self.member.peer.webrtcbin().set_state(gst::State::Null);
+        let _ = self.member.subscriber.set_state(gst::State::Null);
+        let _ = self.pipeline.remove(&self.member.subscriber);
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    pipeline: gst::Pipeline,
+    peers: Arc<Mutex<Vec<Arc<MockPeer>>>>,
+    connections: Connections,
+    sessions: Sessions,
+    executor: Executor,
+) -> Result<(), Error> {
+    let ws = async_tungstenite::accept_async(stream)
+        .await
+        .map_err(|e| Error::signal("accept", e))?;
+    let (mut sink, mut source) = ws.split();
+
+    let (out, mut outgoing) = mpsc::unbounded::<WsMessage>();
+    connections.lock().unwrap().push(out.clone());
+    executor.spawn(async move {
+        while let Some(msg) = outgoing.next().await {
+            if sink.send(msg).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let peer = Arc::new(MockPeer::in_pipeline(&pipeline)?);
+    peers.lock().unwrap().push(peer.clone());
+
+    let mut candidates = peer.candidates();
+    let trickles = out.clone();
+    executor.spawn(async move {
+        while let Some(candidate) = candidates.next().await {
+            let params = json!({ "target": 0, "candidate": candidate });
+            if trickles
+                .unbounded_send(notification("trickle", params))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let (answered, answers) = mpsc::unbounded();
+    let connection = Connection {
+        member: Arc::new(Member {
+            peer,
+            subscriber: subscriber(&pipeline, &out, answers, &executor)?,
+        }),
+        pipeline,
+        sessions,
+        answered,
+        executor,
+    };
+
+    while let Some(msg) = source.next().await {
+        let text = match msg {
+            Ok(WsMessage::Text(text)) => text,
+            Ok(WsMessage::Close(_)) => break,
+            Ok(_) => continue,
+            Err(err) => {
+                warn!("mock sfu receive failed: {}", err);
+                break;
+            }
+        };
+        let request: RpcRequest = serde_json::from_str(&text)?;
+        trace!("mock sfu request: {}", request.method);
+
+        let result = connection.handle(&request.method, request.params).await;
+        let id = match request.id {
+            Some(id) => id,
+            None => {
+                if let Err(err) = result {
+                    warn!("mock sfu notification {} failed: {}", request.method, err);
+                }
+                continue;
+            }
+        };
+
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(err) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": -32000, "message": err.to_string() },
+            }),
+        };
+        if out
+            .unbounded_send(WsMessage::Text(response.to_string()))
+            .is_err()
+        {
+            break;
+        }
+    }
+
+    connection.leave();
+    Ok(())
+}
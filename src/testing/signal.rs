@@ -0,0 +1,158 @@
+use super::super::{
+    Error, Executor, SessionDescription, Signal, SignalNotification, TrickleCandidate,
+};
+use super::MockPeer;
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::future::{self, AbortHandle, FutureExt};
+use futures::StreamExt;
+use log::*;
+use std::sync::{Arc, Mutex};
+
+/// A call made on a `MockSignal`, descriptions are recorded by their sdp.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SignalCall {
+    Open,
+    Close,
+    Ping,
+    Join { sid: String, offer: String },
+    Offer(String),
+    Answer(String),
+    Trickle { target: u32, candidate: String },
+}
+
+/// A `Signal` that records every call and answers publisher offers with a `MockPeer`,
+/// so a `Client` can join without any server. Like the sfu, closing drops the peer, the
+/// next session is answered by a new one.
+///
+/// Clones share their state: keep one to inspect `calls` or inject notifications with
+/// `notify` after handing the other to the client.
+#[derive(Clone)]
+pub struct MockSignal {
+    peer: Arc<Mutex<Arc<MockPeer>>>,
+    calls: Arc<Mutex<Vec<SignalCall>>>,
+    notifications: Arc<Mutex<Option<mpsc::Sender<SignalNotification>>>>,
+    tasks: Arc<Mutex<Vec<AbortHandle>>>,
+    executor: Executor,
+}
+
+impl MockSignal {
+    pub fn new() -> Result<MockSignal, Error> {
+        Ok(MockSignal {
+            peer: Arc::new(Mutex::new(Arc::new(MockPeer::new()?))),
+            calls: Arc::new(Mutex::new(vec![])),
+            notifications: Arc::new(Mutex::new(None)),
+            tasks: Arc::new(Mutex::new(vec![])),
+            executor: Executor::default(),
+        })
+    }
+
+    /// Trickles the peer's candidates from a task on `executor` instead of the default
+    /// glib main context.
+    pub fn with_executor(mut self, executor: Executor) -> MockSignal {
+        self.executor = executor;
+        self
+    }
+
+    /// The peer of the current session.
+    pub fn peer(&self) -> Arc<MockPeer> {
+        self.peer.lock().unwrap().clone()
+    }
+
+    pub fn calls(&self) -> Vec<SignalCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Delivers a notification as if the server sent it.
+    pub fn notify(&self, notification: SignalNotification) -> Result<(), Error> {
+        self.notifications
+            .lock()
+            .unwrap()
+            .as_mut()
+            .ok_or(Error::NotConnected)?
+            .try_send(notification)
+            .map_err(|_| Error::NotConnected)
+    }
+
+    fn record(&self, call: SignalCall) {
+        trace!("mock signal: {:?}", call);
+        self.calls.lock().unwrap().push(call);
+    }
+}
+
+#[async_trait]
+impl Signal for MockSignal {
+    async fn open(&mut self) -> Result<mpsc::Receiver<SignalNotification>, Error> {
+        self.record(SignalCall::Open);
+
+        let (tx, rx) = mpsc::channel(16);
+        *self.notifications.lock().unwrap() = Some(tx.clone());
+
+        let mut candidates = self.peer().candidates();
+        let (task, handle) = future::abortable(async move {
+            let mut tx = tx;
+            while let Some(candidate) = candidates.next().await {
+                let notification = SignalNotification::Trickle {
+                    target: 0,
+                    candidate,
+                };
+                if tx.try_send(notification).is_err() {
+                    warn!("mock signal dropped a peer candidate");
+                }
+            }
+        });
+        self.executor.spawn(task.map(|_| ()));
+        self.tasks.lock().unwrap().push(handle);
+
+        Ok(rx)
+    }
+
+    async fn close(&mut self) -> Result<(), Error> {
+        self.record(SignalCall::Close);
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+        self.notifications.lock().unwrap().take();
+        *self.peer.lock().unwrap() = Arc::new(MockPeer::new()?);
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        self.record(SignalCall::Ping);
+        Ok(())
+    }
+
+    async fn join(
+        &self,
+        sid: String,
+        offer: SessionDescription,
+    ) -> Result<SessionDescription, Error> {
+        self.record(SignalCall::Join {
+            sid,
+            offer: offer.sdp.clone(),
+        });
+        self.peer().answer(&offer).await
+    }
+
+    async fn offer(&self, offer: SessionDescription) -> Result<SessionDescription, Error> {
+        self.record(SignalCall::Offer(offer.sdp.clone()));
+        self.peer().answer(&offer).await
+    }
+
+    async fn answer(&self, answer: SessionDescription) -> Result<(), Error> {
+        self.record(SignalCall::Answer(answer.sdp));
+        Ok(())
+    }
+
+    async fn trickle(&self, target: u32, candidate: TrickleCandidate) -> Result<(), Error> {
+        self.record(SignalCall::Trickle {
+            target,
+            candidate: candidate.candidate.clone(),
+        });
+        match target {
+            0 => self.peer().add_ice_candidate(&candidate),
+            // the mock never offers on the subscriber transport
+            _ => Ok(()),
+        }
+    }
+}
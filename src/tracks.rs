@@ -0,0 +1,595 @@
+//! Maps subscriber pads to the remote streams they carry.
+//!
+//! webrtcbin names its src pads after the m-line they belong to, so the ids announced
+//! with `a=msid` in the sfu's offers can be matched to pads as they appear. Every pad is
+//! fed into a `tee` so a track can be consumed by more than one branch and an unlinked
+//! track never stalls the subscriber.
+
+use super::sdp::Sdp;
+use super::Error;
+use futures::channel::{mpsc, oneshot};
+use futures::stream::{Stream, StreamExt};
+use gst::prelude::*;
+use log::*;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrackKind {
+    Audio,
+    Video,
+}
+
+#[derive(Clone, Debug)]
+pub struct RemoteTrack {
+    pub stream_id: String,
+    pub track_id: String,
+    pub kind: TrackKind,
+    /// Encoding name of the negotiated codec, e.g. `VP8` or `opus`.
+    pub codec: String,
+    pub mline: u32,
+    /// An rtp src pad reserved for the consumer of the track event, released with the
+    /// track.
+    pub pad: gst::Pad,
+    tee: gst::Element,
+}
+
+impl RemoteTrack {
+    /// Requests an additional rtp src pad for this track.
+    pub fn request_pad(&self) -> Result<gst::Pad, Error> {
+        self.tee
+            .get_request_pad("src_%u")
+            .ok_or_else(|| Error::Pipeline {
+                context: format!("track {} refused src pad request", self.track_id),
+                source: None,
+            })
+    }
+}
+
+/// A consumer's src pad on a track's tee and the sink pad it feeds.
+#[derive(Clone, Debug)]
+pub(crate) struct TeeBranch {
+    pub mline: u32,
+    pub track_id: String,
+    pub pad: gst::Pad,
+    pub sink: gst::Pad,
+}
+
+impl TeeBranch {
+    /// Requests a src pad of `track` for `sink`, left unlinked.
+    pub fn request(track: &RemoteTrack, sink: &gst::Pad) -> Result<TeeBranch, Error> {
+        Ok(TeeBranch {
+            mline: track.mline,
+            track_id: track.track_id.clone(),
+            pad: track.request_pad()?,
+            sink: sink.clone(),
+        })
+    }
+
+    pub fn link(&self) -> Result<(), Error> {
+        self.pad
+            .link(&self.sink)
+            .map(|_| ())
+            .map_err(|e| Error::pipeline("linking track branch", e))
+    }
+
+    pub fn is_of(&self, track: &RemoteTrack) -> bool {
+        self.mline == track.mline && self.track_id == track.track_id
+    }
+
+    /// Unlinks the sink and gives our pad back to the tee.
+    pub fn release(&self) {
+        let _ = self.pad.unlink(&self.sink);
+        if let Some(tee) = self.pad.get_parent_element() {
+            tee.release_request_pad(&self.pad);
+        }
+    }
+
+    /// Releases the branch and ends the stream of the sink, e.g. to let a muxer finish.
+    pub fn end(&self) {
+        self.release();
+        self.sink.send_event(gst::event::Eos::new());
+    }
+}
+
+/// What a consumer built for one track, see `follow_tracks`.
+pub(crate) trait TrackBranch {
+    fn tee_branch(&self) -> &TeeBranch;
+
+    /// Whether the consumer of this one track went away and the branch can be removed,
+    /// checked whenever `TrackSender::closing` is signalled.
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+/// Hands out what a consumer built per track, e.g. the decoded tracks of
+/// `Subscriber::start`. The consumer stops, and removes what it added to the pipeline,
+/// once this is dropped.
+pub struct TrackReceiver<T> {
+    items: mpsc::UnboundedReceiver<T>,
+    /// Never sent on, its receiver in `follow_tracks` resolves once this is dropped.
+    _dropped: oneshot::Sender<()>,
+}
+
+impl<T> Stream for TrackReceiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.items.poll_next_unpin(cx)
+    }
+}
+
+/// The sending half of a `TrackReceiver`, consumed by `follow_tracks`.
+pub(crate) struct TrackSender<T> {
+    items: mpsc::UnboundedSender<T>,
+    dropped: oneshot::Receiver<()>,
+    closing: mpsc::UnboundedSender<()>,
+    closed: mpsc::UnboundedReceiver<()>,
+}
+
+impl<T> TrackSender<T> {
+    /// Wakes `follow_tracks` to look for closed branches, see `TrackBranch::is_closed`.
+    pub fn closing(&self) -> mpsc::UnboundedSender<()> {
+        self.closing.clone()
+    }
+}
+
+pub(crate) fn track_channel<T>() -> (TrackSender<T>, TrackReceiver<T>) {
+    let (items_tx, items) = mpsc::unbounded();
+    let (dropped_tx, dropped) = oneshot::channel();
+    let (closing, closed) = mpsc::unbounded();
+    let tx = TrackSender {
+        items: items_tx,
+        dropped,
+        closing,
+        closed,
+    };
+    let rx = TrackReceiver {
+        items,
+        _dropped: dropped_tx,
+    };
+    (tx, rx)
+}
+
+/// Builds a branch with `add` for every track added from now on and hands its item to
+/// `tx`. Branches are given to `remove` with their track, once they are closed, and all
+/// of them once the `TrackReceiver` of `tx` is dropped, which also ends the loop. Tracks
+/// `add` returns `None` for are skipped.
+pub(crate) async fn follow_tracks<T, B, A, R>(
+    mut events: mpsc::UnboundedReceiver<TrackEvent>,
+    tx: TrackSender<T>,
+    mut add: A,
+    mut remove: R,
+) where
+    B: TrackBranch,
+    A: FnMut(&RemoteTrack) -> Result<Option<(B, T)>, Error>,
+    R: FnMut(B),
+{
+    let TrackSender {
+        items,
+        mut dropped,
+        closing: _closing,
+        mut closed,
+    } = tx;
+    let mut branches: Vec<B> = vec![];
+
+    loop {
+        futures::select! {
+            event = events.next() => match event {
+                Some(TrackEvent::Added(track)) => match add(&track) {
+                    Ok(Some((branch, item))) => {
+                        branches.push(branch);
+                        let _ = items.unbounded_send(item);
+                    }
+                    Ok(None) => {}
+                    Err(err) => warn!("could not follow track {}: {}", track.track_id, err),
+                },
+                Some(TrackEvent::Removed(track)) => {
+                    let (removed, kept) = branches
+                        .into_iter()
+                        .partition::<Vec<_>, _>(|b| b.tee_branch().is_of(&track));
+                    branches = kept;
+                    removed.into_iter().for_each(&mut remove);
+                }
+                None => break,
+            },
+            _ = dropped => break,
+            _ = closed.next() => {
+                let (gone, open) = branches
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|b| b.is_closed());
+                branches = open;
+                gone.into_iter().for_each(&mut remove);
+            }
+        }
+    }
+
+    branches.into_iter().for_each(remove);
+}
+
+#[derive(Clone, Debug)]
+pub enum TrackEvent {
+    Added(RemoteTrack),
+    /// The track's m-line was dropped or reassigned. Its reserved pad is released already,
+    /// branches linked to pads it requested should be unlinked.
+    Removed(RemoteTrack),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct TrackInfo {
+    stream_id: String,
+    track_id: String,
+    kind: TrackKind,
+    codec: String,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Tracks announced by the latest subscriber offer, by m-line.
+    described: HashMap<u32, TrackInfo>,
+    /// Tees fed by the subscriber src pads, by m-line.
+    tees: HashMap<u32, gst::Element>,
+    /// Tracks that were announced as added, by m-line.
+    active: HashMap<u32, RemoteTrack>,
+    subscribers: Vec<mpsc::UnboundedSender<TrackEvent>>,
+}
+
+#[derive(Clone)]
+pub(crate) struct TrackTable {
+    pipeline: gst::Pipeline,
+    inner: Arc<Mutex<Inner>>,
+}
+
+fn mline_of(pad: &gst::Pad) -> Option<u32> {
+    pad.get_name().strip_prefix("src_")?.parse().ok()
+}
+
+fn describe(sdp: &str) -> HashMap<u32, TrackInfo> {
+    let sdp = Sdp::parse(sdp);
+    let mut described = HashMap::new();
+
+    for (idx, media) in sdp.media.iter().enumerate() {
+        let kind = match media.kind() {
+            "audio" => TrackKind::Audio,
+            "video" => TrackKind::Video,
+            _ => continue,
+        };
+        if media.is_disabled() || matches!(media.direction(), Some("inactive") | Some("recvonly")) {
+            continue;
+        }
+
+        // msid is either a media attribute or attached to the ssrc lines
+        let msid = media.attribute("msid").or_else(|| {
+            media
+                .attributes("ssrc")
+                .find_map(|ssrc| ssrc.splitn(2, " msid:").nth(1))
+        });
+        let mut ids = match msid {
+            Some(msid) => msid.split_whitespace(),
+            None => continue,
+        };
+        let (stream_id, track_id) = match (ids.next(), ids.next()) {
+            (Some(stream), Some(track)) => (stream.to_string(), track.to_string()),
+            _ => continue,
+        };
+
+        described.insert(
+            idx as u32,
+            TrackInfo {
+                stream_id,
+                track_id,
+                kind,
+                codec: media.first_codec().unwrap_or("").to_string(),
+            },
+        );
+    }
+
+    described
+}
+
+impl TrackTable {
+    pub fn new(pipeline: &gst::Pipeline) -> TrackTable {
+        TrackTable {
+            pipeline: pipeline.clone(),
+            inner: Arc::new(Mutex::new(Inner::default())),
+        }
+    }
+
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<TrackEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.inner.lock().unwrap().subscribers.push(tx);
+        rx
+    }
+
+    /// Records the tracks announced by an offer from the sfu.
+    pub fn update_from_offer(&self, offer: &str) {
+        let events = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.described = describe(offer);
+            inner.reconcile()
+        };
+        self.dispatch(events);
+    }
+
+    pub fn pad_added(&self, pad: &gst::Pad) {
+        let mline = match mline_of(pad) {
+            Some(mline) => mline,
+            None => return,
+        };
+
+        let tee = match self.attach_tee(pad) {
+            Ok(tee) => tee,
+            Err(err) => {
+                error!("could not attach track tee to {}: {}", pad.get_name(), err);
+                return;
+            }
+        };
+
+        let events = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.tees.insert(mline, tee);
+            inner.reconcile()
+        };
+        self.dispatch(events);
+    }
+
+    pub fn pad_removed(&self, pad: &gst::Pad) {
+        let mline = match mline_of(pad) {
+            Some(mline) => mline,
+            None => return,
+        };
+
+        let (tee, events) = {
+            let mut inner = self.inner.lock().unwrap();
+            let tee = inner.tees.remove(&mline);
+            (tee, inner.reconcile())
+        };
+        self.dispatch(events);
+
+        if let Some(tee) = tee {
+            let _ = tee.set_state(gst::State::Null);
+            let _ = self.pipeline.remove(&tee);
+        }
+    }
+
+    /// Forgets every announced track, e.g. when leaving the session.
+    pub fn clear(&self) {
+        let events = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.described.clear();
+            inner.reconcile()
+        };
+        self.dispatch(events);
+    }
+
+    fn attach_tee(&self, pad: &gst::Pad) -> Result<gst::Element, Error> {
+        let tee = gst::ElementFactory::make("tee", None)
+            .map_err(|_| Error::ElementNotFound("tee".to_string()))?;
+        tee.set_property("allow-not-linked", &true)
+            .map_err(|e| Error::pipeline("configuring track tee", e))?;
+
+        self.pipeline
+            .add(&tee)
+            .map_err(|e| Error::pipeline("adding track tee", e))?;
+        tee.sync_state_with_parent()
+            .map_err(|e| Error::pipeline("syncing track tee", e))?;
+
+        let sink = tee.get_static_pad("sink").unwrap();
+        pad.link(&sink)
+            .map_err(|e| Error::pipeline("linking track tee", e))?;
+
+        Ok(tee)
+    }
+
+    fn dispatch(&self, events: Vec<TrackEvent>) {
+        if events.is_empty() {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        for event in events {
+            debug!("track event: {:?}", event);
+            inner
+                .subscribers
+                .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+        }
+    }
+}
+
+impl Inner {
+    /// Brings `active` in line with what is described and which pads exist,
+    /// returning the resulting events.
+    fn reconcile(&mut self) -> Vec<TrackEvent> {
+        let mut events = vec![];
+
+        let stale: Vec<u32> = self
+            .active
+            .iter()
+            .filter(|(mline, track)| {
+                let unchanged = self.described.get(mline).map_or(false, |info| {
+                    info.stream_id == track.stream_id && info.track_id == track.track_id
+                });
+                !unchanged || !self.tees.contains_key(mline)
+            })
+            .map(|(mline, _)| *mline)
+            .collect();
+        for mline in stale {
+            if let Some(track) = self.active.remove(&mline) {
+                track.tee.release_request_pad(&track.pad);
+                events.push(TrackEvent::Removed(track));
+            }
+        }
+
+        for (mline, tee) in self.tees.iter() {
+            if self.active.contains_key(mline) {
+                continue;
+            }
+            let info = match self.described.get(mline) {
+                Some(info) => info.clone(),
+                None => continue,
+            };
+            let pad = match tee.get_request_pad("src_%u") {
+                Some(pad) => pad,
+                None => {
+                    error!("track tee for m-line {} refused src pad request", mline);
+                    continue;
+                }
+            };
+
+            let track = RemoteTrack {
+                stream_id: info.stream_id,
+                track_id: info.track_id,
+                kind: info.kind,
+                codec: info.codec,
+                mline: *mline,
+                pad,
+                tee: tee.clone(),
+            };
+            self.active.insert(*mline, track.clone());
+            events.push(TrackEvent::Added(track));
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> String {
+        "v=0\r\no=- 1 0 IN IP4 0.0.0.0\r\ns=-\r\nt=0 0\r\n".to_string()
+    }
+
+    fn media(kind: &str, port: u32, direction: &str, msid: &str) -> String {
+        format!(
+            "m={} {} UDP/TLS/RTP/SAVPF 96\r\n\
+             a={}\r\n\
+             a=rtpmap:96 {}\r\n\
+             {}",
+            kind,
+            port,
+            direction,
+            if kind == "audio" {
+                "opus/48000/2"
+            } else {
+                "VP8/90000"
+            },
+            msid
+        )
+    }
+
+    fn info(stream_id: &str, track_id: &str, kind: TrackKind, codec: &str) -> TrackInfo {
+        TrackInfo {
+            stream_id: stream_id.to_string(),
+            track_id: track_id.to_string(),
+            kind,
+            codec: codec.to_string(),
+        }
+    }
+
+    fn tee() -> gst::Element {
+        gst::init().unwrap();
+        gst::ElementFactory::make("tee", None).unwrap()
+    }
+
+    fn added(events: &[TrackEvent]) -> Vec<&RemoteTrack> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                TrackEvent::Added(track) => Some(track),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn removed(events: &[TrackEvent]) -> Vec<&RemoteTrack> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                TrackEvent::Removed(track) => Some(track),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn describes_sending_mlines_by_index() {
+        let sdp = session()
+            + &media("audio", 9, "sendonly", "a=msid:stream audio\r\n")
+            + &media("video", 9, "sendrecv", "a=ssrc:1 msid:stream video\r\n")
+            + &media("video", 9, "recvonly", "a=msid:stream ignored\r\n")
+            + &media("video", 0, "sendonly", "a=msid:stream rejected\r\n")
+            + &media("video", 9, "sendonly", "")
+            + &media("video", 9, "sendonly", "a=msid:stream\r\n");
+
+        let described = describe(&sdp);
+        assert_eq!(described.len(), 2, "{:?}", described);
+        assert_eq!(
+            described[&0],
+            info("stream", "audio", TrackKind::Audio, "opus")
+        );
+        assert_eq!(
+            described[&1],
+            info("stream", "video", TrackKind::Video, "VP8")
+        );
+    }
+
+    #[test]
+    fn reconcile_adds_and_removes_tracks() {
+        let tee = tee();
+        let mut inner = Inner::default();
+
+        // a pad without a description, or a description without a pad, is no track
+        inner.tees.insert(0, tee.clone());
+        assert!(inner.reconcile().is_empty());
+        inner.tees.clear();
+        inner
+            .described
+            .insert(0, info("stream", "video", TrackKind::Video, "VP8"));
+        assert!(inner.reconcile().is_empty());
+
+        inner.tees.insert(0, tee.clone());
+        let events = inner.reconcile();
+        let tracks = added(&events);
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].mline, 0);
+        assert_eq!(tracks[0].track_id, "video");
+        assert_eq!(tracks[0].pad.get_parent_element(), Some(tee.clone()));
+        assert!(inner.reconcile().is_empty());
+
+        inner.described.clear();
+        let events = inner.reconcile();
+        assert_eq!(removed(&events).len(), 1);
+        assert!(added(&events).is_empty());
+        // the reserved pad goes back to the tee
+        assert!(tee.get_src_pads().is_empty());
+    }
+
+    #[test]
+    fn reconcile_re_adds_a_reassigned_mline() {
+        let tee = tee();
+        let mut inner = Inner::default();
+        inner.tees.insert(0, tee.clone());
+        inner
+            .described
+            .insert(0, info("stream", "first", TrackKind::Video, "VP8"));
+        assert_eq!(added(&inner.reconcile()).len(), 1);
+
+        inner
+            .described
+            .insert(0, info("stream", "second", TrackKind::Video, "VP8"));
+        let events = inner.reconcile();
+        assert_eq!(removed(&events)[0].track_id, "first");
+        assert_eq!(added(&events)[0].track_id, "second");
+        assert_eq!(tee.get_src_pads().len(), 1);
+
+        inner.tees.remove(&0);
+        let events = inner.reconcile();
+        assert_eq!(removed(&events)[0].track_id, "second");
+        assert!(tee.get_src_pads().is_empty());
+    }
+}
@@ -0,0 +1,422 @@
+//! The publisher and subscriber webrtcbins of a client.
+//!
+//! A webrtcbin can't be reset: set to NULL it keeps its transceivers and signaling state,
+//! and ion-sfu closes both peer connections when a session ends anyway. Leaving therefore
+//! replaces the pair with fresh elements of the same names, moving the publish sources
+//! over, so the next join negotiates from scratch.
+
+use super::config::ClientConfig;
+use super::datachannel::{DataChannel, DataChannelOptions};
+use super::sfu_api::{self, SfuApi};
+use super::state::{StateWatch, Transport};
+use super::tracks::TrackTable;
+use super::{ClientEvent, Error, TrickleCandidate, WebrtcBinEvent};
+use futures::channel::mpsc;
+use futures::future;
+use futures::stream::StreamExt;
+use gst::prelude::*;
+use log::*;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// What every new pair of transports is hooked up to.
+#[derive(Clone)]
+pub(crate) struct Observers {
+    pub tracks: TrackTable,
+    pub states: StateWatch,
+    pub sfu_api: SfuApi,
+    /// Receives the data channels the remote side opens on the subscriber.
+    pub channels: mpsc::UnboundedSender<DataChannel>,
+}
+
+/// Where the publisher's signals go while a session is joined, see `Transports::attach`.
+#[derive(Clone)]
+pub(crate) struct Session {
+    pub webrtcbin: mpsc::UnboundedSender<WebrtcBinEvent>,
+    pub events: mpsc::UnboundedSender<ClientEvent>,
+}
+
+struct Inner {
+    publisher: gst::Element,
+    subscriber: gst::Element,
+    /// Handlers set up with the pair, disconnected before it's replaced.
+    handlers: Vec<(gst::Element, glib::SignalHandlerId)>,
+    session: Option<Session>,
+    /// Handlers of the session on the publisher.
+    session_handlers: Vec<glib::SignalHandlerId>,
+    /// ion-sfu's api channel on the publisher, see `ClientConfig::sfu_api`.
+    api_channel: Option<DataChannel>,
+}
+
+impl Inner {
+    fn disconnect_session(&mut self) {
+        for handler in self.session_handlers.drain(..) {
+            self.publisher.disconnect(handler);
+        }
+    }
+
+    /// Connects the publisher's negotiation and candidate signals to the session.
+    fn connect_session(&mut self) -> Result<(), Error> {
+        self.disconnect_session();
+        let session = match &self.session {
+            Some(session) => session.clone(),
+            None => return Ok(()),
+        };
+
+        let tx = session.webrtcbin.clone();
+        let negotiation_handler = self
+            .publisher
+            .connect("on-negotiation-needed", false, move |_| {
+                info!("pub negotiation needed");
+                if tx
+                    .unbounded_send(WebrtcBinEvent::NegotiationNeeded)
+                    .is_err()
+                {
+                    debug!("publisher event loop gone, dropping negotiation-needed");
+                }
+                None
+            })
+            .map_err(|e| Error::Action {
+                action: "connect on-negotiation-needed",
+                source: e,
+            })?;
+        self.session_handlers.push(negotiation_handler);
+
+        let tx = session.webrtcbin;
+        let events = session.events;
+        let candidate_handler = self
+            .publisher
+            .connect("on-ice-candidate", false, move |values| {
+                let mlineindex = values[1].get_some::<u32>().ok();
+                let candidate = values[2].get::<String>().ok().flatten();
+                let (mlineindex, candidate) = match (mlineindex, candidate) {
+                    (Some(mlineindex), Some(candidate)) => (mlineindex, candidate),
+                    _ => {
+                        let err = Error::Pipeline {
+                            context: "on-ice-candidate with invalid arguments".to_string(),
+                            source: None,
+                        };
+                        ClientEvent::dispatch(&events, Err(err));
+                        return None;
+                    }
+                };
+
+                let candidate = WebrtcBinEvent::IceCandidate(TrickleCandidate {
+                    sdp_mline_index: mlineindex,
+                    sdp_mid: None,
+                    candidate: candidate,
+                });
+                if tx.unbounded_send(candidate).is_err() {
+                    debug!("publisher event loop gone, dropping ice candidate");
+                }
+
+                None
+            })
+            .map_err(|e| Error::Action {
+                action: "connect on-ice-candidate",
+                source: e,
+            })?;
+        self.session_handlers.push(candidate_handler);
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Transports {
+    pipeline: gst::Pipeline,
+    config: ClientConfig,
+    observers: Observers,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl fmt::Debug for Transports {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("Transports")
+            .field("publisher", &inner.publisher.get_name())
+            .field("subscriber", &inner.subscriber.get_name())
+            .finish()
+    }
+}
+
+/// Stops `old` and puts a new webrtcbin of the same name in its place.
+fn replace(pipeline: &gst::Pipeline, old: &gst::Element) -> Result<gst::Element, Error> {
+    old.set_state(gst::State::Null)
+        .map_err(|e| Error::pipeline("stopping transport", e))?;
+
+    // adopted transports may live in a bin of their own
+    let parent = old
+        .get_parent()
+        .and_then(|parent| parent.downcast::<gst::Bin>().ok())
+        .unwrap_or_else(|| pipeline.clone().upcast());
+    parent
+        .remove(old)
+        .map_err(|e| Error::pipeline("removing transport", e))?;
+
+    let name = old.get_name();
+    let new = gst::ElementFactory::make("webrtcbin", Some(name.as_str()))
+        .map_err(|_| Error::ElementNotFound("webrtcbin".to_string()))?;
+    parent
+        .add(&new)
+        .map_err(|e| Error::pipeline("adding transport", e))?;
+    Ok(new)
+}
+
+fn sink_index(pad: &gst::Pad) -> Option<u32> {
+    pad.get_name().strip_prefix("sink_")?.parse().ok()
+}
+
+impl Transports {
+    /// Takes over `publisher` and `subscriber`, which must already be in `pipeline`.
+    pub fn new(
+        pipeline: &gst::Pipeline,
+        publisher: gst::Element,
+        subscriber: gst::Element,
+        config: &ClientConfig,
+        observers: Observers,
+    ) -> Result<Transports, Error> {
+        let transports = Transports {
+            pipeline: pipeline.clone(),
+            config: config.clone(),
+            observers,
+            inner: Arc::new(Mutex::new(Inner {
+                publisher,
+                subscriber,
+                handlers: vec![],
+                session: None,
+                session_handlers: vec![],
+                api_channel: None,
+            })),
+        };
+        transports.configure(&mut transports.inner.lock().unwrap())?;
+        Ok(transports)
+    }
+
+    pub fn publisher(&self) -> gst::Element {
+        self.inner.lock().unwrap().publisher.clone()
+    }
+
+    pub fn subscriber(&self) -> gst::Element {
+        self.inner.lock().unwrap().subscriber.clone()
+    }
+
+    fn configure(&self, inner: &mut Inner) -> Result<(), Error> {
+        let Observers {
+            tracks,
+            states,
+            sfu_api,
+            channels,
+        } = self.observers.clone();
+
+        for pc in &[&inner.publisher, &inner.subscriber] {
+            self.config.apply_ice_servers(pc);
+            pc.set_property_from_str("bundle-policy", "max-bundle");
+        }
+
+        let subscriber = inner.subscriber.clone();
+        let handler = subscriber
+            .connect("on-data-channel", false, move |values| {
+                let channel = match values[1].get::<gst_webrtc::WebRTCDataChannel>() {
+                    Ok(Some(channel)) => channel,
+                    _ => {
+                        warn!("on-data-channel with invalid arguments");
+                        return None;
+                    }
+                };
+
+                match DataChannel::new(channel) {
+                    Ok(channel) if channel.label() == sfu_api::API_CHANNEL => sfu_api.bind(channel),
+                    Ok(channel) => {
+                        debug!("remote data channel {}", channel.label());
+                        let _ = channels.unbounded_send(channel);
+                    }
+                    Err(err) => warn!("could not set up remote data channel: {}", err),
+                }
+                None
+            })
+            .map_err(|e| Error::Action {
+                action: "connect on-data-channel",
+                source: e,
+            })?;
+        inner.handlers.push((subscriber.clone(), handler));
+
+        // webrtcbin adds a src pad per received m-line, hand each to the track table
+        let added = tracks.clone();
+        let handler = subscriber.connect_pad_added(move |_, pad| added.pad_added(pad));
+        inner.handlers.push((subscriber.clone(), handler));
+        let removed = tracks;
+        let handler = subscriber.connect_pad_removed(move |_, pad| removed.pad_removed(pad));
+        inner.handlers.push((subscriber.clone(), handler));
+
+        for (transport, pc) in &[
+            (Transport::Publisher, &inner.publisher),
+            (Transport::Subscriber, &inner.subscriber),
+        ] {
+            for handler in states.watch(*transport, pc) {
+                inner.handlers.push(((*pc).clone(), handler));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Connects the publisher's negotiation and candidate signals to `session`, until
+    /// `detach`.
+    pub fn attach(&self, session: Session) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.session = Some(session);
+        inner.connect_session()
+    }
+
+    /// Connects a publisher created by `rebuild` to the attached session, once it joined.
+    pub fn resume(&self) -> Result<(), Error> {
+        self.inner.lock().unwrap().connect_session()
+    }
+
+    /// Disconnects the publisher from the session, so webrtcbin stops feeding channels
+    /// whose receivers are going away.
+    pub fn detach(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.disconnect_session();
+        inner.session = None;
+    }
+
+    /// Opens ion-sfu's api channel on the publisher, unless it is open already.
+    pub fn open_api_channel(&self) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.api_channel.is_none() {
+            let channel = DataChannel::create(
+                &inner.publisher,
+                sfu_api::API_CHANNEL,
+                &DataChannelOptions::default(),
+            )?;
+            inner.api_channel = Some(channel);
+        }
+        Ok(())
+    }
+
+    /// Replaces both webrtcbins with fresh ones of the same names, the publisher is
+    /// connected to the session again by `resume`. Sources linked to the old publisher are
+    /// linked to the sink pad of the same name on the new one, keeping their m-line; the
+    /// tracks of the old subscriber are removed.
+    pub fn rebuild(&self) -> Result<(), Error> {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        inner.disconnect_session();
+        for (element, handler) in inner.handlers.drain(..) {
+            element.disconnect(handler);
+        }
+        // both ends of the api channel go with the old pair, it's opened again on join
+        if let Some(channel) = inner.api_channel.take() {
+            let _ = channel.close();
+        }
+        self.observers.sfu_api.unbind();
+
+        let mut sources: Vec<(u32, gst::Pad)> = inner
+            .publisher
+            .get_sink_pads()
+            .iter()
+            .filter_map(|pad| {
+                let index = sink_index(pad)?;
+                let peer = pad.get_peer()?;
+                let _ = peer.unlink(pad);
+                Some((index, peer))
+            })
+            .collect();
+        sources.sort_by_key(|(index, _)| *index);
+
+        for pad in inner.subscriber.get_src_pads() {
+            self.observers.tracks.pad_removed(&pad);
+        }
+
+        inner.publisher = replace(&self.pipeline, &inner.publisher)?;
+        inner.subscriber = replace(&self.pipeline, &inner.subscriber)?;
+        self.configure(inner)?;
+        for pc in &[&inner.publisher, &inner.subscriber] {
+            pc.sync_state_with_parent()
+                .map_err(|e| Error::pipeline("syncing transport state", e))?;
+        }
+
+        for (index, src) in sources {
+            let name = format!("sink_{}", index);
+            let moved = inner
+                .publisher
+                .get_request_pad(&name)
+                .ok_or_else(|| Error::Pipeline {
+                    context: format!("publisher refused sink pad {}", name),
+                    source: None,
+                })
+                .and_then(|sink| {
+                    src.link(&sink)
+                        .map_err(|e| Error::pipeline("linking source to new publisher", e))
+                });
+            if let Err(err) = moved {
+                warn!("could not move publish source to {}: {}", name, err);
+            }
+        }
+
+        debug!("transports rebuilt");
+        Ok(())
+    }
+
+    /// Waits until every linked publisher sink pad has negotiated caps, see
+    /// `Client::wait_until_ready`.
+    pub async fn wait_until_ready(&self, timeout: Duration) -> Result<(), Error> {
+        let publisher = self.publisher();
+        let (wake_tx, mut wake) = mpsc::unbounded();
+        let negotiated = Arc::new(Mutex::new(HashSet::new()));
+
+        let mut probes = vec![];
+        for pad in publisher.get_sink_pads() {
+            let wake_tx = wake_tx.clone();
+            let negotiated = negotiated.clone();
+            let probe = pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |pad, info| {
+                if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+                    if event.get_type() == gst::EventType::Caps {
+                        negotiated
+                            .lock()
+                            .unwrap()
+                            .insert(pad.get_name().to_string());
+                        let _ = wake_tx.unbounded_send(());
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            });
+            if let Some(probe) = probe {
+                probes.push((pad, probe));
+            }
+        }
+        drop(wake_tx);
+
+        let ready = async {
+            loop {
+                let pending: Vec<String> = publisher
+                    .get_sink_pads()
+                    .iter()
+                    .filter(|pad| pad.is_linked() && !pad.has_current_caps())
+                    .map(|pad| pad.get_name().to_string())
+                    .filter(|name| !negotiated.lock().unwrap().contains(name))
+                    .collect();
+                if pending.is_empty() {
+                    break;
+                }
+
+                debug!("waiting for caps on {:?}", pending);
+                if wake.next().await.is_none() {
+                    // no probe left to wake us, only the timeout can end this
+                    future::pending::<()>().await;
+                }
+            }
+        };
+        let result = async_std::future::timeout(timeout, ready).await;
+
+        for (pad, probe) in probes {
+            pad.remove_probe(probe);
+        }
+
+        result.map_err(|_| Error::Timeout("publisher caps"))
+    }
+}
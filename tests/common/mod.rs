@@ -0,0 +1,31 @@
+// every test binary uses only some of the helpers
+#![allow(dead_code)]
+
+use gst::prelude::*;
+use std::time::Duration;
+
+/// Builds a pipeline publishing a live vp8 test pattern, its payloader is named `pubsrc`.
+pub fn video_pipeline() -> gst::Pipeline {
+    gst::init().unwrap();
+    gst::parse_launch(
+        "videotestsrc is-live=true ! video/x-raw,width=320,height=240,framerate=15/1 !
+        vp8enc deadline=1 ! rtpvp8pay ! queue name=pubsrc",
+    )
+    .unwrap()
+    .downcast::<gst::Pipeline>()
+    .unwrap()
+}
+
+/// Polls `condition` until it holds or `timeout` passes.
+pub async fn wait_for<F: Fn() -> bool>(timeout: Duration, condition: F) -> bool {
+    let step = Duration::from_millis(100);
+    let mut waited = Duration::from_secs(0);
+    while waited < timeout {
+        if condition() {
+            return true;
+        }
+        async_std::task::sleep(step).await;
+        waited += step;
+    }
+    condition()
+}
@@ -0,0 +1,108 @@
+use futures::StreamExt;
+use gst::prelude::*;
+use ion_gst_rs::jsonrpc::ReconnectConfig;
+use ion_gst_rs::testing::Loopback;
+use ion_gst_rs::{ClientEvent, TrackKind};
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn media_loops_through_forwarding_sfu() {
+    gst::init().unwrap();
+
+    glib::MainContext::default().block_on(async {
+        let loopback = Loopback::start("loopback").await.unwrap();
+        loopback
+            .wait_for_media(Duration::from_secs(15))
+            .await
+            .unwrap();
+        loopback.stop().await.unwrap();
+    });
+}
+
+#[test]
+fn media_flows_again_after_the_signal_reconnects() {
+    gst::init().unwrap();
+
+    glib::MainContext::default().block_on(async {
+        let reconnect = ReconnectConfig {
+            initial_delay: Duration::from_millis(100),
+            ..ReconnectConfig::default()
+        };
+        let mut loopback = Loopback::start_with_reconnect("reconnect", reconnect)
+            .await
+            .unwrap();
+        loopback
+            .wait_for_media(Duration::from_secs(15))
+            .await
+            .unwrap();
+
+        // the sfu closes both peer connections with the socket, the clients rejoin
+        loopback.server.disconnect();
+        for events in loopback.events.iter_mut() {
+            let rejoined = async_std::future::timeout(Duration::from_secs(15), async {
+                while let Some(event) = events.next().await {
+                    if let ClientEvent::Rejoined = event {
+                        return true;
+                    }
+                }
+                false
+            })
+            .await;
+            assert_eq!(
+                rejoined,
+                Ok(true),
+                "a client did not rejoin after the reconnect"
+            );
+        }
+
+        let since = loopback.received();
+        loopback
+            .wait_for_new_media(since, Duration::from_secs(15))
+            .await
+            .unwrap();
+        loopback.stop().await.unwrap();
+    });
+}
+
+#[test]
+fn decoding_stops_once_the_receiver_is_dropped() {
+    gst::init().unwrap();
+
+    glib::MainContext::default().block_on(async {
+        let loopback = Loopback::start("decode").await.unwrap();
+        loopback
+            .wait_for_media(Duration::from_secs(15))
+            .await
+            .unwrap();
+
+        // only tracks added from now on are decoded
+        let mut decoded = loopback.second.subscribe().start();
+        loopback
+            .first
+            .publish()
+            .add_test_source(TrackKind::Video)
+            .unwrap();
+        let mut track = async_std::future::timeout(Duration::from_secs(15), decoded.next())
+            .await
+            .unwrap()
+            .expect("no track was decoded");
+        let sample = async_std::future::timeout(Duration::from_secs(15), track.samples.next())
+            .await
+            .unwrap();
+        assert!(sample.is_some(), "no sample was decoded");
+
+        let tee = track.track.pad.get_parent_element().unwrap();
+        let decoding = tee.get_src_pads().len();
+        drop(decoded);
+        assert!(
+            common::wait_for(Duration::from_secs(5), || tee.get_src_pads().len()
+                == decoding - 1)
+            .await,
+            "the decode branch was not removed"
+        );
+
+        loopback.stop().await.unwrap();
+    });
+}
@@ -0,0 +1,75 @@
+use futures::StreamExt;
+use gst::prelude::*;
+use ion_gst_rs::ion::{IonSignaler, RoomEvent};
+use ion_gst_rs::testing::{IonCall, MockIonServer};
+use ion_gst_rs::{Client, ClientConfig};
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn client_joins_offers_and_trickles_over_ion() {
+    let pipeline = common::video_pipeline();
+
+    glib::MainContext::default().block_on(async {
+        let server = MockIonServer::start().await.unwrap();
+        let signaler = IonSignaler::new(&server.url(), "alice").with_token("secret");
+        let mut room = signaler.room_events();
+
+        let mut client = Client::new(signaler, &pipeline, ClientConfig::new()).unwrap();
+        client
+            .link_publish_source(&pipeline.get_by_name("pubsrc").unwrap())
+            .unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+
+        let _events = client.join("room".to_string()).await.unwrap();
+        assert!(matches!(
+            room.next().await,
+            Some(RoomEvent::PeerJoined(peer)) if peer.uid == "alice" && peer.sid == "room"
+        ));
+        assert!(
+            common::wait_for(Duration::from_secs(10), || {
+                server
+                    .peers()
+                    .iter()
+                    .any(|peer| peer.buffers_received() > 0)
+            })
+            .await,
+            "no media reached the mock ion server"
+        );
+
+        client.restart_ice().await.unwrap();
+
+        let calls = server.calls();
+        assert_eq!(
+            calls[0],
+            IonCall::BizJoin {
+                sid: "room".to_string(),
+                uid: "alice".to_string(),
+                token: "secret".to_string(),
+            }
+        );
+        assert!(calls.contains(&IonCall::RtcJoin {
+            sid: "room".to_string(),
+            uid: "alice".to_string(),
+        }));
+        assert!(calls.contains(&IonCall::Trickle { target: 0 }));
+        assert!(calls.contains(&IonCall::Description {
+            target: 0,
+            t: "offer".to_string(),
+        }));
+
+        client.leave().await.unwrap();
+        assert!(
+            common::wait_for(Duration::from_secs(5), || server.calls().contains(
+                &IonCall::BizLeave {
+                    uid: "alice".to_string()
+                }
+            ))
+            .await,
+            "the room was not left"
+        );
+    });
+
+    pipeline.set_state(gst::State::Null).unwrap();
+}
@@ -0,0 +1,121 @@
+use gst::prelude::*;
+use ion_gst_rs::jsonrpc::JsonRPCSignaler;
+use ion_gst_rs::testing::MockSfuServer;
+use ion_gst_rs::{Client, ClientConfig, SimulcastLayer};
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn client_publishes_over_jsonrpc() {
+    let pipeline = common::video_pipeline();
+
+    glib::MainContext::default().block_on(async {
+        let server = MockSfuServer::start().await.unwrap();
+        let mut client = Client::new(
+            JsonRPCSignaler::new(&server.url()),
+            &pipeline,
+            ClientConfig::new(),
+        )
+        .unwrap();
+        client
+            .link_publish_source(&pipeline.get_by_name("pubsrc").unwrap())
+            .unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+
+        let _events = client.join("test".to_string()).await.unwrap();
+        client.ping().await.unwrap();
+
+        assert!(
+            common::wait_for(Duration::from_secs(10), || {
+                server
+                    .peers()
+                    .iter()
+                    .any(|peer| peer.buffers_received() > 0)
+            })
+            .await,
+            "no media reached the mock sfu"
+        );
+
+        client.leave().await.unwrap();
+    });
+
+    pipeline.set_state(gst::State::Null).unwrap();
+}
+
+fn remote_sdp(webrtcbin: &gst::Element) -> String {
+    webrtcbin
+        .get_property("remote-description")
+        .unwrap()
+        .get::<gst_webrtc::WebRTCSessionDescription>()
+        .unwrap()
+        .unwrap()
+        .get_sdp()
+        .as_text()
+        .unwrap()
+}
+
+#[test]
+fn simulcast_is_folded_for_the_sfu_and_restored() {
+    gst::init().unwrap();
+    let pipeline = gst::parse_launch(
+        "videotestsrc is-live=true ! video/x-raw,width=320,height=240,framerate=15/1 ! tee name=t
+        t. ! queue ! vp8enc deadline=1 ! rtpvp8pay name=f
+        t. ! queue ! videoscale ! video/x-raw,width=160,height=120 !
+            vp8enc deadline=1 ! rtpvp8pay name=h
+        t. ! queue ! videoscale ! video/x-raw,width=80,height=60 !
+            vp8enc deadline=1 ! rtpvp8pay name=q",
+    )
+    .unwrap()
+    .downcast::<gst::Pipeline>()
+    .unwrap();
+
+    glib::MainContext::default().block_on(async {
+        let server = MockSfuServer::start().await.unwrap();
+        let mut client = Client::new(
+            JsonRPCSignaler::new(&server.url()),
+            &pipeline,
+            ClientConfig::new(),
+        )
+        .unwrap();
+        client
+            .publish_simulcast(vec![
+                (SimulcastLayer::High, pipeline.get_by_name("f").unwrap()),
+                (SimulcastLayer::Medium, pipeline.get_by_name("h").unwrap()),
+                (SimulcastLayer::Low, pipeline.get_by_name("q").unwrap()),
+            ])
+            .unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+
+        let _events = client.join("simulcast".to_string()).await.unwrap();
+
+        // the sfu sees one video m-line with all three rids
+        let offer = server.peers()[0].offers().remove(0);
+        assert_eq!(offer.matches("m=video").count(), 1, "{}", offer);
+        for rid in &["f", "h", "q"] {
+            assert!(offer.contains(&format!("a=rid:{} send", rid)), "{}", offer);
+        }
+        assert!(offer.contains("a=simulcast:send f;h;q"), "{}", offer);
+
+        // webrtcbin gets the answer back for each of its m-lines
+        let answer = remote_sdp(&client.publisher());
+        assert_eq!(answer.matches("m=video").count(), 3, "{}", answer);
+        assert!(!answer.contains("a=simulcast"), "{}", answer);
+        assert!(!answer.contains("a=rid"), "{}", answer);
+
+        assert!(
+            common::wait_for(Duration::from_secs(10), || {
+                server
+                    .peers()
+                    .iter()
+                    .any(|peer| peer.buffers_received() > 0)
+            })
+            .await,
+            "no media reached the mock sfu"
+        );
+
+        client.leave().await.unwrap();
+    });
+
+    pipeline.set_state(gst::State::Null).unwrap();
+}
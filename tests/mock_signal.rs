@@ -0,0 +1,100 @@
+use gst::prelude::*;
+use ion_gst_rs::testing::{MockSignal, SignalCall};
+use ion_gst_rs::{Client, ClientConfig};
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn client_joins_through_mock_signal() {
+    let pipeline = common::video_pipeline();
+
+    glib::MainContext::default().block_on(async {
+        let signal = MockSignal::new().unwrap();
+        let mut client = Client::new(signal.clone(), &pipeline, ClientConfig::new()).unwrap();
+        client
+            .link_publish_source(&pipeline.get_by_name("pubsrc").unwrap())
+            .unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+
+        let _events = client.join("test".to_string()).await.unwrap();
+        assert!(
+            common::wait_for(Duration::from_secs(10), || signal.peer().buffers_received()
+                > 0)
+            .await,
+            "no media reached the mock peer"
+        );
+
+        let calls = signal.calls();
+        assert_eq!(calls[0], SignalCall::Open);
+        assert!(matches!(
+            &calls[1],
+            SignalCall::Join { sid, offer } if sid == "test" && offer.contains("VP8")
+        ));
+        assert!(calls
+            .iter()
+            .any(|call| matches!(call, SignalCall::Trickle { target: 0, .. })));
+
+        client.leave().await.unwrap();
+        assert_eq!(signal.calls().last(), Some(&SignalCall::Close));
+    });
+
+    pipeline.set_state(gst::State::Null).unwrap();
+}
+
+#[test]
+fn failed_publish_source_leaves_pipeline_untouched() {
+    let pipeline = common::video_pipeline();
+    let signal = MockSignal::new().unwrap();
+    let client = Client::new(signal, &pipeline, ClientConfig::new()).unwrap();
+    let children = pipeline.get_children().len();
+
+    // a sink has no src pad to publish from
+    let sink = gst::ElementFactory::make("fakesink", Some("not-a-source")).unwrap();
+    assert!(client.publish().add_video_source(&sink).is_err());
+    assert!(pipeline.get_by_name("not-a-source").is_none());
+    assert_eq!(pipeline.get_children().len(), children);
+}
+
+#[test]
+fn client_joins_again_after_leaving() {
+    let pipeline = common::video_pipeline();
+
+    glib::MainContext::default().block_on(async {
+        let signal = MockSignal::new().unwrap();
+        let mut client = Client::new(signal.clone(), &pipeline, ClientConfig::new()).unwrap();
+        client
+            .link_publish_source(&pipeline.get_by_name("pubsrc").unwrap())
+            .unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+
+        let first = client.publisher();
+        let _events = client.join("test".to_string()).await.unwrap();
+        client.leave().await.unwrap();
+        assert_ne!(client.publisher(), first);
+        assert!(first.get_parent().is_none());
+
+        // the second session is answered by a new peer, its offer must start from scratch
+        let _events = client.join("test".to_string()).await.unwrap();
+        let joins: Vec<String> = signal
+            .calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                SignalCall::Join { offer, .. } => Some(offer),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(joins.len(), 2);
+        assert_eq!(joins[1].matches("m=video").count(), 1);
+        assert!(
+            common::wait_for(Duration::from_secs(10), || signal.peer().buffers_received()
+                > 0)
+            .await,
+            "no media reached the peer of the second session"
+        );
+
+        client.leave().await.unwrap();
+    });
+
+    pipeline.set_state(gst::State::Null).unwrap();
+}
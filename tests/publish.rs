@@ -0,0 +1,45 @@
+use futures::StreamExt;
+use ion_gst_rs::testing::Loopback;
+use ion_gst_rs::{TrackEvent, TrackKind};
+use std::time::Duration;
+
+#[test]
+fn test_source_added_after_join_reaches_the_other_client() {
+    gst::init().unwrap();
+
+    glib::MainContext::default().block_on(async {
+        let loopback = Loopback::start("publish").await.unwrap();
+        loopback
+            .wait_for_media(Duration::from_secs(15))
+            .await
+            .unwrap();
+
+        let mut tracks = loopback.second.tracks();
+        let published = loopback
+            .first
+            .publish()
+            .add_test_source(TrackKind::Audio)
+            .unwrap();
+        assert_eq!(published.kind, TrackKind::Audio);
+        assert!(published.pad.is_linked());
+
+        let added = async_std::future::timeout(Duration::from_secs(15), async {
+            while let Some(event) = tracks.next().await {
+                if let TrackEvent::Added(track) = event {
+                    if track.kind == TrackKind::Audio {
+                        return true;
+                    }
+                }
+            }
+            false
+        })
+        .await;
+        assert_eq!(
+            added,
+            Ok(true),
+            "the audio track never reached the other client"
+        );
+
+        loopback.stop().await.unwrap();
+    });
+}
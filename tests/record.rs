@@ -0,0 +1,135 @@
+use gst::prelude::*;
+use ion_gst_rs::testing::Loopback;
+use ion_gst_rs::{Container, RecordConfig, TrackKind, VideoCodec};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+mod common;
+
+fn recorded_files(directory: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(directory)
+        .map(|entries| entries.filter_map(|e| Some(e.ok()?.path())).collect())
+        .unwrap_or_default()
+}
+
+/// Whether `path` demuxes and decodes up to the first frame.
+fn prerolls(path: &Path) -> bool {
+    let pipeline = gst::parse_launch(&format!(
+        "filesrc location={} ! decodebin ! fakesink",
+        path.display()
+    ))
+    .unwrap();
+    pipeline.set_state(gst::State::Paused).unwrap();
+
+    let message = pipeline.get_bus().unwrap().timed_pop_filtered(
+        gst::ClockTime::from_seconds(5),
+        &[gst::MessageType::AsyncDone, gst::MessageType::Error],
+    );
+    pipeline.set_state(gst::State::Null).unwrap();
+    matches!(
+        message.map(|m| m.get_type()),
+        Some(gst::MessageType::AsyncDone)
+    )
+}
+
+#[test]
+fn recording_is_finalized_on_leave() {
+    gst::init().unwrap();
+    let directory = std::env::temp_dir().join(format!("ion-gst-record-{}", std::process::id()));
+
+    glib::MainContext::default().block_on(async {
+        let mut loopback = Loopback::start("record").await.unwrap();
+        loopback
+            .wait_for_media(Duration::from_secs(15))
+            .await
+            .unwrap();
+
+        // only tracks added from now on are recorded
+        loopback
+            .second
+            .record(RecordConfig::new(&directory).settle(Duration::from_millis(200)))
+            .await
+            .unwrap();
+        loopback
+            .first
+            .publish()
+            .add_test_source(TrackKind::Video)
+            .unwrap();
+
+        assert!(
+            common::wait_for(Duration::from_secs(15), || {
+                recorded_files(&directory)
+                    .iter()
+                    .any(|f| f.metadata().map_or(false, |m| m.len() > 0))
+            })
+            .await,
+            "nothing was recorded"
+        );
+        async_std::task::sleep(Duration::from_secs(2)).await;
+
+        loopback.stop().await.unwrap();
+    });
+
+    let files = recorded_files(&directory);
+    assert_eq!(files.len(), 1, "recorded {:?}", files);
+    assert!(
+        prerolls(&files[0]),
+        "{} is not playable",
+        files[0].display()
+    );
+    let _ = std::fs::remove_dir_all(&directory);
+}
+
+#[test]
+fn tracks_the_container_cannot_hold_are_left_out() {
+    gst::init().unwrap();
+    let directory =
+        std::env::temp_dir().join(format!("ion-gst-record-webm-{}", std::process::id()));
+
+    glib::MainContext::default().block_on(async {
+        let mut loopback = Loopback::start("record-webm").await.unwrap();
+        loopback
+            .wait_for_media(Duration::from_secs(15))
+            .await
+            .unwrap();
+
+        loopback
+            .second
+            .record(
+                RecordConfig::new(&directory)
+                    .container(Container::WebM)
+                    .settle(Duration::from_secs(1)),
+            )
+            .await
+            .unwrap();
+        // both settle into one file, webm can't take the h264 track
+        let publisher = loopback.first.publish();
+        publisher.add_test_source(TrackKind::Video).unwrap();
+        publisher
+            .video_codec(VideoCodec::H264)
+            .add_test_source(TrackKind::Video)
+            .unwrap();
+
+        assert!(
+            common::wait_for(Duration::from_secs(15), || {
+                recorded_files(&directory)
+                    .iter()
+                    .any(|f| f.metadata().map_or(false, |m| m.len() > 0))
+            })
+            .await,
+            "nothing was recorded"
+        );
+        async_std::task::sleep(Duration::from_secs(2)).await;
+
+        loopback.stop().await.unwrap();
+    });
+
+    let files = recorded_files(&directory);
+    assert_eq!(files.len(), 1, "recorded {:?}", files);
+    assert!(
+        prerolls(&files[0]),
+        "{} is not playable",
+        files[0].display()
+    );
+    let _ = std::fs::remove_dir_all(&directory);
+}
@@ -0,0 +1,89 @@
+use futures::StreamExt;
+use gst::prelude::*;
+use ion_gst_rs::testing::Loopback;
+use ion_gst_rs::{TrackEvent, TrackKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn relayed_tracks_reach_the_targets_subscribers() {
+    gst::init().unwrap();
+
+    glib::MainContext::default().block_on(async {
+        let loopback = Loopback::start("relay").await.unwrap();
+        loopback
+            .wait_for_media(Duration::from_secs(15))
+            .await
+            .unwrap();
+
+        // the target publishes into a session of its own, watched by the viewer
+        let mut target = loopback.client("snow").unwrap();
+        let mut viewer = loopback.client("pinwheel").unwrap();
+        let mut viewed = viewer.tracks();
+        target.join("relay-target".to_string()).await.unwrap();
+        viewer.join("relay-target".to_string()).await.unwrap();
+
+        // only tracks added from now on are relayed
+        let mut relayed = loopback.first.relay().to(&target).unwrap();
+        loopback
+            .second
+            .publish()
+            .add_test_source(TrackKind::Video)
+            .unwrap();
+        let track = async_std::future::timeout(Duration::from_secs(15), relayed.next())
+            .await
+            .unwrap()
+            .expect("no track was relayed");
+        assert_eq!(
+            track.pad.get_parent_element().as_ref(),
+            Some(&target.publisher())
+        );
+
+        // the target's own track and the relayed one
+        let mut received = vec![];
+        let added = async_std::future::timeout(Duration::from_secs(15), async {
+            while let Some(event) = viewed.next().await {
+                if let TrackEvent::Added(remote) = event {
+                    let counter = Arc::new(AtomicUsize::new(0));
+                    let count = counter.clone();
+                    remote
+                        .pad
+                        .add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+                            count.fetch_add(1, Ordering::Relaxed);
+                            gst::PadProbeReturn::Ok
+                        });
+                    received.push(counter);
+                    if received.len() == 2 {
+                        break;
+                    }
+                }
+            }
+        })
+        .await;
+        assert!(added.is_ok(), "the viewer saw {} tracks", received.len());
+        assert!(
+            common::wait_for(Duration::from_secs(15), || received
+                .iter()
+                .all(|r| r.load(Ordering::Relaxed) > 0))
+            .await,
+            "the relayed media did not reach the viewer"
+        );
+
+        drop(relayed);
+        assert!(
+            common::wait_for(Duration::from_secs(5), || track
+                .pad
+                .get_parent_element()
+                .is_none())
+            .await,
+            "the target's pad was not released"
+        );
+
+        viewer.leave().await.unwrap();
+        target.leave().await.unwrap();
+        loopback.stop().await.unwrap();
+    });
+}